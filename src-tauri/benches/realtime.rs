@@ -0,0 +1,114 @@
+//! Times `Engine::process` on its own and while other threads hammer the same
+//! paths `set_config` and `get_samples` use. The device thread moves the engine
+//! into the audio callback, which runs nothing else, so these timings are the
+//! callback's. With it holding no locks, the two runs should show the same
+//! worst case.
+//!
+//! cargo bench --bench realtime
+
+use app_lib::constants::{default_config, DEFAULT_BUFFER_SIZE, DEFAULT_SAMPLE_RATE};
+use app_lib::engine::{Engine, EngineConfig, EngineControls};
+use app_lib::get_loop_buffer_size::get_loop_buffer_size;
+use app_lib::structs::LoopBuffer;
+use app_lib::types::S;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const CALLBACKS: usize = 2000;
+
+fn run_callbacks(engine: &mut Engine, count: usize) -> Vec<Duration> {
+    let input_channel = vec![0.1; DEFAULT_BUFFER_SIZE];
+    let mut output_channels = vec![vec![0.0; DEFAULT_BUFFER_SIZE]; 2];
+    let mut timings = Vec::with_capacity(count);
+    for _ in 0..count {
+        let input: [&[S]; 1] = [&input_channel];
+        let mut output: Vec<&mut [S]> = output_channels
+            .iter_mut()
+            .map(|c| c.as_mut_slice())
+            .collect();
+        let start = Instant::now();
+        engine.process(&input, &mut output);
+        timings.push(start.elapsed());
+    }
+    timings
+}
+
+fn report(name: &str, mut timings: Vec<Duration>) {
+    timings.sort();
+    let mean = timings.iter().sum::<Duration>() / timings.len() as u32;
+    let p99 = timings[timings.len() * 99 / 100];
+    let max = timings[timings.len() - 1];
+    println!(
+        "{:<24} mean {:>10?}  p99 {:>10?}  max {:>10?}",
+        name, mean, p99, max
+    );
+}
+
+/// Stands in for the Tauri command threads, which only ever lock their own state.
+fn hammer_commands(
+    controls: EngineControls,
+    running: Arc<AtomicBool>,
+) -> std::thread::JoinHandle<usize> {
+    let config = Arc::new(Mutex::new(controls.config));
+    let loop_buffer = Arc::new(Mutex::new(controls.loop_buffer));
+    let visual_samples = Arc::new(Mutex::new(controls.visual_samples));
+
+    let get_samples = {
+        let running = running.clone();
+        let visual_samples = visual_samples.clone();
+        std::thread::spawn(move || {
+            while running.load(Ordering::Relaxed) {
+                {
+                    let mut samples = visual_samples.lock().unwrap();
+                    while samples.pop().is_ok() {}
+                }
+                // the frontend polls at 100 Hz
+                std::thread::sleep(Duration::from_millis(10));
+            }
+        })
+    };
+
+    std::thread::spawn(move || {
+        let mut calls = 0;
+        while running.load(Ordering::Relaxed) {
+            let mut new_config = config.lock().unwrap().current().config.clone();
+            new_config.bpm = if calls % 2 == 0 { 120.0 } else { 91.0 };
+            let new_buffer_size = get_loop_buffer_size(&new_config, DEFAULT_SAMPLE_RATE);
+            config
+                .lock()
+                .unwrap()
+                .publish(EngineConfig::new(new_config));
+            let _ = loop_buffer
+                .lock()
+                .unwrap()
+                .send(LoopBuffer::new(new_buffer_size));
+            calls += 1;
+            // far faster than anyone can drag a slider
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        get_samples.join().unwrap();
+        calls
+    })
+}
+
+fn main() {
+    let (mut engine, mut controls) =
+        Engine::new(default_config(), HashMap::new(), DEFAULT_SAMPLE_RATE);
+    // drain visual samples between callbacks like the UI would, without contention
+    let mut idle = Vec::with_capacity(CALLBACKS);
+    for _ in 0..CALLBACKS / 10 {
+        idle.append(&mut run_callbacks(&mut engine, 10));
+        while controls.visual_samples.pop().is_ok() {}
+    }
+    report("process, idle commands", idle);
+
+    let running = Arc::new(AtomicBool::new(true));
+    let commands = hammer_commands(controls, running.clone());
+    let busy = run_callbacks(&mut engine, CALLBACKS);
+    running.store(false, Ordering::Relaxed);
+    let calls = commands.join().unwrap();
+    report("process, busy commands", busy);
+    println!("set_config calls during run: {}", calls);
+}
@@ -0,0 +1,74 @@
+//! A global allocator wrapper that counts heap operations made by the current
+//! thread inside `count_allocations`, used to check that `Engine::process`
+//! stays allocation free. Binaries opt in with
+//! `#[global_allocator] static ALLOCATOR: CountingAllocator = CountingAllocator;`.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+pub struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static COUNTING: Cell<bool> = const { Cell::new(false) };
+}
+
+fn record() {
+    // try_with: the thread local may already be gone while a thread shuts down
+    if COUNTING.try_with(|c| c.get()).unwrap_or(false) {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        record();
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        record();
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        record();
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        record();
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+/// Runs `f` and returns its result with the number of allocations, frees and
+/// reallocations it made on this thread. Always zero unless the binary installed
+/// `CountingAllocator`.
+pub fn count_allocations<R>(f: impl FnOnce() -> R) -> (R, usize) {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    COUNTING.with(|c| c.set(true));
+    let result = f();
+    COUNTING.with(|c| c.set(false));
+    (result, ALLOCATIONS.load(Ordering::Relaxed) - before)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // for the whole test binary, so other modules' tests can count too
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    #[test]
+    fn counts_only_inside_count_allocations() {
+        let (_, count) = count_allocations(|| vec![0u8; 16]);
+        // the vector is freed once counting has stopped
+        assert_eq!(count, 1);
+        let (_, count) = count_allocations(|| 1 + 1);
+        assert_eq!(count, 0);
+    }
+}
@@ -0,0 +1,201 @@
+use crate::constants::{DEFAULT_BUFFER_SIZE, DEFAULT_SAMPLE_RATE};
+use crate::structs::Buffers;
+use crate::types::S;
+use arrayvec::ArrayVec;
+use rtrb::{Consumer, Producer, RingBuffer};
+use serde::{Deserialize, Serialize};
+
+/// Called once per output buffer with the input captured for the same frames
+/// and the non-interleaved output channels to fill.
+pub type ProcessCallback = Box<dyn FnMut(&[&[S]], &mut [&mut [S]]) + Send>;
+
+/// Most channels a backend passes to its `ProcessCallback`, so the channel
+/// slices can be gathered on the stack inside device callbacks.
+pub const MAX_CHANNELS: usize = 16;
+pub type ChannelSlices<'a> = ArrayVec<&'a [S], MAX_CHANNELS>;
+pub type ChannelSlicesMut<'a> = ArrayVec<&'a mut [S], MAX_CHANNELS>;
+
+/// What to ask devices for. The `DeviceFormat` returned when opening one is
+/// what it actually granted.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct StreamSettings {
+    pub sample_rate: f64,
+    pub buffer_size: usize,
+}
+
+impl Default for StreamSettings {
+    fn default() -> StreamSettings {
+        StreamSettings {
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            buffer_size: DEFAULT_BUFFER_SIZE,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DeviceFormat {
+    pub sample_rate: f64,
+    pub channels: usize,
+    pub buffer_size: usize,
+}
+
+/// A device as listed by `list_audio_devices`. `id` is what `select_audio_devices` takes.
+#[derive(Clone, Debug, Serialize)]
+pub struct DeviceInfo {
+    pub id: String,
+    pub name: String,
+    pub input_channels: usize,
+    pub output_channels: usize,
+    pub sample_rates: Vec<f64>,
+}
+
+/// Rates checked against the ranges devices report when listing them.
+pub const COMMON_SAMPLE_RATES: [f64; 6] = [22050.0, 32000.0, 44100.0, 48000.0, 88200.0, 96000.0];
+
+/// Backends can be stopped, reopened on other devices and started again.
+pub trait AudioBackend {
+    fn name(&self) -> &'static str;
+    fn devices(&self) -> Result<Vec<DeviceInfo>, String>;
+    /// Opens the device with the given `DeviceInfo::id`, or the default input when `None`.
+    fn open_input(
+        &mut self,
+        device: Option<&str>,
+        settings: &StreamSettings,
+    ) -> Result<DeviceFormat, String>;
+    /// Opens the device with the given `DeviceInfo::id`, or the default output when `None`.
+    fn open_output(
+        &mut self,
+        device: Option<&str>,
+        settings: &StreamSettings,
+    ) -> Result<DeviceFormat, String>;
+    fn start(&mut self, callback: ProcessCallback) -> Result<(), String>;
+    /// Stops the streams and drops the callback, even when stopping fails.
+    fn stop(&mut self) -> Result<(), String>;
+    /// Human readable description of the opened streams, sent to the frontend log.
+    fn device_formats(&self) -> Vec<String>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BackendKind {
+    CoreAudio,
+    Cpal,
+    File,
+    /// The file backend with its output fed back into its input.
+    Loopback,
+}
+
+impl BackendKind {
+    pub fn from_name(name: &str) -> Option<BackendKind> {
+        match name.to_lowercase().as_str() {
+            "coreaudio" => Some(BackendKind::CoreAudio),
+            "cpal" | "alsa" | "jack" => Some(BackendKind::Cpal),
+            "file" | "null" => Some(BackendKind::File),
+            "loopback" => Some(BackendKind::Loopback),
+            _ => None,
+        }
+    }
+
+    /// The best backend compiled into this build.
+    pub fn platform_default() -> BackendKind {
+        if cfg!(target_os = "macos") {
+            BackendKind::CoreAudio
+        } else if cfg!(feature = "cpal") {
+            BackendKind::Cpal
+        } else {
+            BackendKind::File
+        }
+    }
+
+    /// Reads the `AUDIO_BACKEND` environment variable, falling back to the platform default.
+    pub fn from_env() -> BackendKind {
+        std::env::var("AUDIO_BACKEND")
+            .ok()
+            .and_then(|name| BackendKind::from_name(&name))
+            .unwrap_or_else(BackendKind::platform_default)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct BackendOptions {
+    /// Input recording played into the file backend; silence when `None`.
+    pub input_file: Option<String>,
+    /// Where the file backend writes its output when stopped.
+    pub output_file: Option<String>,
+    /// Frames between output and input on the loopback backend.
+    pub loopback_delay: usize,
+}
+
+impl BackendOptions {
+    pub fn from_env() -> BackendOptions {
+        BackendOptions {
+            input_file: std::env::var("AUDIO_BACKEND_INPUT").ok(),
+            output_file: std::env::var("AUDIO_BACKEND_OUTPUT").ok(),
+            loopback_delay: std::env::var("AUDIO_BACKEND_LOOPBACK_DELAY")
+                .ok()
+                .and_then(|delay| delay.parse().ok())
+                .unwrap_or(0),
+        }
+    }
+}
+
+pub fn make_backend(
+    kind: BackendKind,
+    options: &BackendOptions,
+) -> Result<Box<dyn AudioBackend>, String> {
+    match kind {
+        #[cfg(target_os = "macos")]
+        BackendKind::CoreAudio => Ok(Box::new(crate::coreaudio_backend::CoreAudioBackend::new())),
+        #[cfg(feature = "cpal")]
+        BackendKind::Cpal => Ok(Box::new(crate::cpal_backend::CpalBackend::new()?)),
+        BackendKind::File => Ok(Box::new(crate::file_backend::FileBackend::new(
+            options.input_file.clone(),
+            options.output_file.clone(),
+        )?)),
+        BackendKind::Loopback => Ok(Box::new(crate::file_backend::FileBackend::loopback(
+            options.loopback_delay,
+            options.output_file.clone(),
+        ))),
+        #[allow(unreachable_patterns)]
+        _ => Err(format!("{:?} backend is not available in this build", kind)),
+    }
+}
+
+/// One second of queued input per channel between the input and output callbacks.
+pub fn make_buffers(channels: usize, sample_rate: f64) -> Buffers {
+    let mut producers = vec![];
+    let mut consumers = vec![];
+    for _ in 0..channels {
+        let (producer, consumer) = RingBuffer::<S>::new(sample_rate as usize);
+        producers.push(producer);
+        consumers.push(consumer);
+    }
+    Buffers {
+        producers,
+        consumers,
+    }
+}
+
+/// Queues input captured by a device callback until the output callback asks for it.
+/// Used by backends whose input and output run as separate streams.
+pub fn push_input(producers: &mut [Producer<S>], channels: &[&[S]]) {
+    for (producer, channel) in producers.iter_mut().zip(channels) {
+        for value in channel.iter() {
+            // the output stream has stalled; drop input rather than block
+            if producer.push(*value).is_err() {
+                break;
+            }
+        }
+    }
+}
+
+/// Pops `num_frames` of queued input into `scratch`, zero filling if the input
+/// stream has fallen behind. `scratch` only allocates if a device hands over
+/// more frames than it was created with.
+pub fn pop_input(consumers: &mut [Consumer<S>], scratch: &mut [Vec<S>], num_frames: usize) {
+    for (consumer, channel) in consumers.iter_mut().zip(scratch.iter_mut()) {
+        channel.resize(num_frames, 0.0);
+        for value in channel.iter_mut() {
+            *value = consumer.pop().unwrap_or(0.0);
+        }
+    }
+}
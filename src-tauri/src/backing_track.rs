@@ -0,0 +1,149 @@
+//! The backing track played along with the loop when `Config::play_file` is set.
+
+use crate::read_audio_file::AudioFileReader;
+use crate::resample::convert;
+use crate::structs::Mp3Buffer;
+use crate::track_stream;
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+/// A loaded backing track, as the file describes it.
+#[derive(Clone, Debug, Serialize)]
+pub struct BackingTrackInfo {
+    pub path: String,
+    /// Length in seconds, 0 when a streamed file does not tell it.
+    pub duration: f64,
+    pub channels: usize,
+    pub sample_rate: f64,
+    /// Whether it plays from disk rather than from memory.
+    pub streamed: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileLoadStage {
+    Decoding,
+    /// Resampling and remixing to the engine's format.
+    Converting,
+    Loaded,
+    Failed,
+    Cancelled,
+}
+
+/// Sent as `file_load_progress` events while a backing track loads.
+#[derive(Clone, Debug, Serialize)]
+pub struct FileLoadProgress {
+    pub path: String,
+    pub stage: FileLoadStage,
+    /// Fraction of the file decoded, `None` when its length is not known.
+    pub progress: Option<f64>,
+    pub error: Option<String>,
+}
+
+impl FileLoadProgress {
+    pub fn new(path: &str, stage: FileLoadStage) -> FileLoadProgress {
+        FileLoadProgress {
+            path: path.to_string(),
+            stage,
+            progress: None,
+            error: None,
+        }
+    }
+}
+
+/// Files longer than this are streamed from disk rather than decoded whole.
+pub const MAX_DECODED_SECONDS: f64 = 30.0;
+/// Seconds a streamed file decodes ahead before it starts playing.
+const PREBUFFER_SECONDS: f64 = 2.0;
+const PREBUFFER_WAIT: Duration = Duration::from_millis(10);
+
+/// Opens `path` ready for the audio thread: short files are decoded and brought
+/// to `sample_rate` and stereo, longer ones and those that do not tell their
+/// length are streamed. Reports each percent decoded and each stage to
+/// `progress`, and gives up once `cancel` is set.
+pub fn load_backing_track(
+    path: &str,
+    sample_rate: f64,
+    cancel: &AtomicBool,
+    progress: &mut dyn FnMut(FileLoadProgress),
+) -> Result<(Mp3Buffer, BackingTrackInfo), String> {
+    let reader = AudioFileReader::open(path)?;
+    let info = BackingTrackInfo {
+        path: path.to_string(),
+        duration: reader.frames.map_or(0.0, |n| n as f64 / reader.sample_rate),
+        channels: reader.channels,
+        sample_rate: reader.sample_rate,
+        streamed: !reader.frames.map_or(false, |n| {
+            n as f64 / reader.sample_rate <= MAX_DECODED_SECONDS
+        }),
+    };
+    if info.streamed {
+        let buffer = stream_backing_track(reader, sample_rate, cancel, &mut |fraction| {
+            progress(FileLoadProgress {
+                progress: Some(fraction),
+                ..FileLoadProgress::new(path, FileLoadStage::Decoding)
+            })
+        })?;
+        return Ok((buffer, info));
+    }
+
+    let mut last_percent = None;
+    let decoded = reader.read_to_end(&mut |fraction| {
+        let percent = fraction.map(|f| (f * 100.0) as usize);
+        if last_percent != Some(percent) {
+            last_percent = Some(percent);
+            progress(FileLoadProgress {
+                progress: fraction,
+                ..FileLoadProgress::new(path, FileLoadStage::Decoding)
+            });
+        }
+        !cancel.load(Ordering::Relaxed)
+    })?;
+    if decoded.frames() == 0 {
+        return Err(format!("{} has no audio", path));
+    }
+    let info = BackingTrackInfo {
+        duration: decoded.frames() as f64 / decoded.sample_rate,
+        channels: decoded.channels,
+        ..info
+    };
+    progress(FileLoadProgress::new(path, FileLoadStage::Converting));
+    let buffer = convert(&decoded, sample_rate, 2)?;
+    if cancel.load(Ordering::Relaxed) {
+        return Err("cancelled".to_string());
+    }
+    Ok((Mp3Buffer::new(buffer.samples, buffer.sample_rate), info))
+}
+
+/// Starts streaming and waits for `PREBUFFER_SECONDS` to be decoded, or the
+/// whole file if it is shorter, reporting the fraction of that buffered.
+fn stream_backing_track(
+    reader: AudioFileReader,
+    sample_rate: f64,
+    cancel: &AtomicBool,
+    progress: &mut dyn FnMut(f64),
+) -> Result<Mp3Buffer, String> {
+    let stream = track_stream::stream(reader, sample_rate)?;
+    let mut last_percent = None;
+    loop {
+        let buffered = (stream.buffered(sample_rate) / PREBUFFER_SECONDS).min(1.0);
+        let percent = (buffered * 100.0) as usize;
+        if last_percent != Some(percent) {
+            last_percent = Some(percent);
+            progress(buffered);
+        }
+        if buffered >= 1.0 || stream.is_finished() {
+            break;
+        }
+        if cancel.load(Ordering::Relaxed) {
+            return Err("cancelled".to_string());
+        }
+        thread::sleep(PREBUFFER_WAIT);
+    }
+    if stream.buffered(sample_rate) == 0.0 {
+        return Err("no audio could be decoded".to_string());
+    }
+    Ok(Mp3Buffer::streaming(stream, sample_rate))
+}
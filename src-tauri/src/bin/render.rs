@@ -0,0 +1,212 @@
+//! Runs a session offline through the same `Engine` the app uses, from an input
+//! recording to a stereo WAV plus the visual `(beat, amplitude)` stream and the
+//! `(beat, strength)` hits detected on the input.
+//!
+//! render --input take.wav --config config.json --duration 30 --output mix.wav --visual visual.csv
+//!
+//! Renders at the input's sample rate unless `--sample-rate` is given, in blocks of
+//! `--buffer-size` frames. With `--check-allocations` the render fails if
+//! `Engine::process` touches the heap. `--subdivisions` replaces the config's
+//! layers with one of a rhythm in the frontend's notation, e.g. `"k1, s1, [h1,h1]:1"`.
+//! The config can be a settings file or a bare config of any version.
+
+use app_lib::alloc_counter::{count_allocations, CountingAllocator};
+use app_lib::constants::DEFAULT_BUFFER_SIZE;
+use app_lib::engine::Engine;
+use app_lib::kit::load_kit;
+use app_lib::parse_rhythm::parse_rhythm;
+use app_lib::read_audio_file::decode_audio_file;
+use app_lib::resample::convert;
+use app_lib::settings::read_config;
+use app_lib::structs::{Mp3Buffer, RhythmLayer};
+use app_lib::types::S;
+use app_lib::util::deinterleave;
+use app_lib::write_audio_file::write_wav;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+const USAGE: &str = "usage: render --input <wav> --config <json> --duration <seconds> \
+--output <wav> [--visual <csv>] [--hits <csv>] [--file <backing track>] [--samples <dir>] \
+[--subdivisions <rhythm>] [--sample-rate <hz>] [--buffer-size <frames>] [--check-allocations]";
+
+struct Args {
+    input: String,
+    config: String,
+    duration: f64,
+    output: String,
+    visual: Option<String>,
+    hits: Option<String>,
+    file: Option<String>,
+    samples: String,
+    subdivisions: Option<String>,
+    sample_rate: Option<f64>,
+    buffer_size: usize,
+    check_allocations: bool,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut options: HashMap<String, String> = HashMap::new();
+    let mut check_allocations = false;
+    let mut args = std::env::args().skip(1);
+    while let Some(flag) = args.next() {
+        let name = flag
+            .strip_prefix("--")
+            .ok_or_else(|| format!("unexpected argument {}", flag))?;
+        if name == "check-allocations" {
+            check_allocations = true;
+            continue;
+        }
+        let value = args
+            .next()
+            .ok_or_else(|| format!("missing value for {}", flag))?;
+        options.insert(name.to_string(), value);
+    }
+    let mut required = |name: &str| {
+        options
+            .remove(name)
+            .ok_or_else(|| format!("missing --{}", name))
+    };
+    let input = required("input")?;
+    let config = required("config")?;
+    let duration = required("duration")?
+        .parse::<f64>()
+        .map_err(|e| format!("invalid --duration: {}", e))?;
+    let output = required("output")?;
+    let sample_rate = match options.remove("sample-rate") {
+        Some(rate) => Some(
+            rate.parse::<f64>()
+                .map_err(|e| format!("invalid --sample-rate: {}", e))?,
+        ),
+        None => None,
+    };
+    let buffer_size = match options.remove("buffer-size") {
+        Some(size) => size
+            .parse::<usize>()
+            .map_err(|e| format!("invalid --buffer-size: {}", e))?,
+        None => DEFAULT_BUFFER_SIZE,
+    };
+    Ok(Args {
+        input,
+        config,
+        duration,
+        output,
+        visual: options.remove("visual"),
+        hits: options.remove("hits"),
+        file: options.remove("file"),
+        samples: options
+            .remove("samples")
+            .unwrap_or_else(|| concat!(env!("CARGO_MANIFEST_DIR"), "/samples").to_string()),
+        subdivisions: options.remove("subdivisions"),
+        sample_rate,
+        buffer_size,
+        check_allocations,
+    })
+}
+
+fn create_csv(filename: &Option<String>) -> Result<Option<BufWriter<File>>, String> {
+    match filename {
+        Some(filename) => Ok(Some(BufWriter::new(
+            File::create(filename).map_err(|e| format!("Failed to create file: {}", e))?,
+        ))),
+        None => Ok(None),
+    }
+}
+
+fn main() -> Result<(), String> {
+    let args = parse_args().map_err(|e| format!("{}\n{}", e, USAGE))?;
+
+    let config_json =
+        std::fs::read_to_string(&args.config).map_err(|e| format!("Failed to open file: {}", e))?;
+    let mut config = read_config(&config_json)?;
+    if let Some(text) = &args.subdivisions {
+        let rhythm = parse_rhythm(text).map_err(|e| e.describe(text))?;
+        config.layers = vec![RhythmLayer::new(rhythm)];
+    }
+
+    let input = decode_audio_file(&args.input)?;
+    let sample_rate = args.sample_rate.unwrap_or(input.sample_rate);
+    let input = convert(&input, sample_rate, 2)?;
+    let input: Vec<Vec<S>> = deinterleave(&input.samples, input.channels);
+
+    let sample_buffers = load_kit(&config.kit, Path::new(&args.samples), sample_rate);
+
+    let mut visual = create_csv(&args.visual)?;
+    let mut hits = create_csv(&args.hits)?;
+
+    let (mut engine, mut controls) = Engine::new(config, sample_buffers, sample_rate);
+    if let Some(file) = &args.file {
+        let buffer = convert(&decode_audio_file(file)?, sample_rate, 2)?;
+        let _ = controls
+            .mp3
+            .send(Mp3Buffer::new(buffer.samples, buffer.sample_rate));
+    }
+
+    let total_frames = (args.duration * sample_rate) as usize;
+    let mut rendered: Vec<Vec<S>> = (0..2).map(|_| Vec::with_capacity(total_frames)).collect();
+    let mut input_scratch = vec![vec![0.0; args.buffer_size]; input.len()];
+    let mut output_scratch = vec![vec![0.0; args.buffer_size]; 2];
+    let mut allocations = 0;
+    let mut pos = 0;
+    while pos < total_frames {
+        let num_frames = args.buffer_size.min(total_frames - pos);
+        for (scratch, channel) in input_scratch.iter_mut().zip(input.iter()) {
+            for (i, value) in scratch[..num_frames].iter_mut().enumerate() {
+                *value = *channel.get(pos + i).unwrap_or(&0.0);
+            }
+        }
+        {
+            let input_slices: Vec<&[S]> = input_scratch.iter().map(|c| &c[..num_frames]).collect();
+            let mut output_slices: Vec<&mut [S]> = output_scratch
+                .iter_mut()
+                .map(|c| &mut c[..num_frames])
+                .collect();
+            let ((), count) =
+                count_allocations(|| engine.process(&input_slices, &mut output_slices));
+            allocations += count;
+        }
+        for (channel, scratch) in rendered.iter_mut().zip(output_scratch.iter()) {
+            channel.extend_from_slice(&scratch[..num_frames]);
+        }
+
+        while let Ok((beat, amplitude)) = controls.visual_samples.pop() {
+            if let Some(visual) = visual.as_mut() {
+                writeln!(visual, "{},{}", beat, amplitude).map_err(|e| e.to_string())?;
+            }
+        }
+        while let Ok(hit) = controls.hits.pop() {
+            if let Some(hits) = hits.as_mut() {
+                writeln!(hits, "{},{}", hit.beat, hit.strength).map_err(|e| e.to_string())?;
+            }
+        }
+        pos += num_frames;
+    }
+
+    write_wav(&args.output, &rendered, sample_rate as u32)?;
+    if let Some(mut visual) = visual {
+        visual.flush().map_err(|e| e.to_string())?;
+    }
+    if let Some(mut hits) = hits {
+        hits.flush().map_err(|e| e.to_string())?;
+    }
+    println!(
+        "rendered {} frames ({} beats) to {}",
+        total_frames,
+        engine.beat(),
+        args.output
+    );
+    if args.check_allocations {
+        if allocations > 0 {
+            return Err(format!(
+                "Engine::process made {} heap allocations",
+                allocations
+            ));
+        }
+        println!("Engine::process made no heap allocations");
+    }
+    Ok(())
+}
@@ -0,0 +1,277 @@
+//! The click. Each note starts a short synthesized sound whose level, and for
+//! the pitched timbres whose pitch, depends on where the note falls in the bar:
+//! the first beat of a bar is a downbeat, any other whole beat a beat, and the
+//! rest are subdivisions. Notes with an accent click like downbeats.
+
+use crate::kit::DEFAULT_SOUND;
+use rand::{rngs::StdRng, Rng};
+use serde::{Deserialize, Serialize};
+use std::f32::consts::TAU;
+
+/// Envelopes end once they have decayed this far, about -60 dB.
+const DECAY_FLOOR: f32 = 6.9;
+/// Frequencies of the woodblock's partials relative to its pitch, and their levels.
+const WOODBLOCK_PARTIALS: [(f32, f32); 3] = [(1.0, 0.6), (2.76, 0.3), (5.4, 0.1)];
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ClickTimbre {
+    Woodblock,
+    Beep,
+    Noise,
+    /// The kit sound named by `ClickSettings::sample`.
+    Sample,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Accent {
+    Downbeat,
+    Beat,
+    Subdivision,
+}
+
+impl Accent {
+    /// The level of a note `beat` beats from the start of the transport.
+    pub fn at(beat: f64, beats_per_bar: u32) -> Accent {
+        let whole = beat.round();
+        if (beat - whole).abs() > 1e-6 {
+            Accent::Subdivision
+        } else if (whole as i64).rem_euclid(beats_per_bar.max(1) as i64) == 0 {
+            Accent::Downbeat
+        } else {
+            Accent::Beat
+        }
+    }
+}
+
+/// How one accent level sounds.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug)]
+pub struct ClickLevel {
+    pub gain: f32,
+    /// In Hz, for the woodblock and beep.
+    pub pitch: f32,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct ClickSettings {
+    pub timbre: ClickTimbre,
+    /// Seconds to full level.
+    pub attack: f32,
+    /// Seconds from full level to silence.
+    pub decay: f32,
+    pub sample: String,
+    pub beats_per_bar: u32,
+    pub downbeat: ClickLevel,
+    pub beat: ClickLevel,
+    pub subdivision: ClickLevel,
+}
+
+impl ClickSettings {
+    pub fn level(&self, accent: Accent) -> &ClickLevel {
+        match accent {
+            Accent::Downbeat => &self.downbeat,
+            Accent::Beat => &self.beat,
+            Accent::Subdivision => &self.subdivision,
+        }
+    }
+}
+
+impl Default for ClickSettings {
+    fn default() -> ClickSettings {
+        ClickSettings {
+            timbre: ClickTimbre::Woodblock,
+            attack: 0.001,
+            decay: 0.04,
+            sample: DEFAULT_SOUND.to_string(),
+            beats_per_bar: 4,
+            downbeat: ClickLevel {
+                gain: 1.0,
+                pitch: 1600.0,
+            },
+            beat: ClickLevel {
+                gain: 0.7,
+                pitch: 1200.0,
+            },
+            subdivision: ClickLevel {
+                gain: 0.4,
+                pitch: 900.0,
+            },
+        }
+    }
+}
+
+/// A synthesized click sounding on the audio thread.
+#[derive(Clone, Copy)]
+pub struct ClickVoice {
+    timbre: ClickTimbre,
+    /// Frames since the click started.
+    elapsed: usize,
+    attack: usize,
+    /// Frames in all; 0 once silent.
+    length: usize,
+    /// Radians per frame.
+    step: f32,
+    decay_per_frame: f32,
+    /// Left and right.
+    gains: [f32; 2],
+}
+
+impl ClickVoice {
+    pub fn silent() -> ClickVoice {
+        ClickVoice {
+            timbre: ClickTimbre::Beep,
+            elapsed: 0,
+            attack: 0,
+            length: 0,
+            step: 0.0,
+            decay_per_frame: 0.0,
+            gains: [0.0; 2],
+        }
+    }
+
+    /// Starts over with a click of `settings` at `accent`. The sample timbre
+    /// is not synthesized and stays silent.
+    pub fn trigger(
+        &mut self,
+        settings: &ClickSettings,
+        accent: Accent,
+        gains: [f32; 2],
+        sample_rate: f64,
+    ) {
+        let level = settings.level(accent);
+        let attack = (settings.attack.max(0.0) as f64 * sample_rate) as usize;
+        let decay = ((settings.decay as f64 * sample_rate) as usize).max(1);
+        *self = ClickVoice {
+            timbre: settings.timbre,
+            elapsed: 0,
+            attack,
+            length: match settings.timbre {
+                ClickTimbre::Sample => 0,
+                _ => attack + decay,
+            },
+            step: TAU * level.pitch / sample_rate as f32,
+            decay_per_frame: DECAY_FLOOR / decay as f32,
+            gains: [gains[0] * level.gain, gains[1] * level.gain],
+        };
+    }
+
+    /// The next frame, left and right.
+    pub fn next_frame(&mut self, rng: &mut StdRng) -> [f32; 2] {
+        if self.elapsed >= self.length {
+            return [0.0; 2];
+        }
+        let t = self.elapsed as f32;
+        let envelope = if self.elapsed < self.attack {
+            t / self.attack as f32
+        } else {
+            (-(t - self.attack as f32) * self.decay_per_frame).exp()
+        };
+        let wave = match self.timbre {
+            ClickTimbre::Woodblock => WOODBLOCK_PARTIALS
+                .iter()
+                .map(|(ratio, level)| (self.step * ratio * t).sin() * level)
+                .sum(),
+            ClickTimbre::Beep => (self.step * t).sin(),
+            ClickTimbre::Noise => rng.gen::<f32>() * 2.0 - 1.0,
+            ClickTimbre::Sample => 0.0,
+        };
+        self.elapsed += 1;
+        let value = wave * envelope;
+        [value * self.gains[0], value * self.gains[1]]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+
+    const RATE: f64 = 44100.0;
+
+    /// The left channel of a whole click.
+    fn render(settings: &ClickSettings, accent: Accent) -> Vec<f32> {
+        let mut voice = ClickVoice::silent();
+        let mut rng = StdRng::seed_from_u64(1);
+        voice.trigger(settings, accent, [1.0, 1.0], RATE);
+        (0..(RATE * 0.2) as usize)
+            .map(|_| voice.next_frame(&mut rng)[0])
+            .collect()
+    }
+
+    fn peak(samples: &[f32]) -> f32 {
+        samples.iter().fold(0.0, |peak, s| peak.max(s.abs()))
+    }
+
+    #[test]
+    fn places_accents_in_the_bar() {
+        assert_eq!(Accent::at(0.0, 4), Accent::Downbeat);
+        assert_eq!(Accent::at(1.0, 4), Accent::Beat);
+        assert_eq!(Accent::at(3.0, 4), Accent::Beat);
+        assert_eq!(Accent::at(4.0, 4), Accent::Downbeat);
+        assert_eq!(Accent::at(8.0, 4), Accent::Downbeat);
+        assert_eq!(Accent::at(-4.0, 4), Accent::Downbeat);
+        assert_eq!(Accent::at(3.0, 3), Accent::Downbeat);
+        assert_eq!(Accent::at(0.5, 4), Accent::Subdivision);
+        assert_eq!(Accent::at(4.25, 4), Accent::Subdivision);
+        // sums of fractions land on whole beats despite rounding
+        assert_eq!(Accent::at(0.1 + 0.2 + 0.7, 4), Accent::Beat);
+    }
+
+    #[test]
+    fn every_beat_is_a_downbeat_in_bars_of_one_or_none() {
+        for beats_per_bar in [0, 1] {
+            assert_eq!(Accent::at(0.0, beats_per_bar), Accent::Downbeat);
+            assert_eq!(Accent::at(1.0, beats_per_bar), Accent::Downbeat);
+            assert_eq!(Accent::at(7.0, beats_per_bar), Accent::Downbeat);
+            assert_eq!(Accent::at(7.5, beats_per_bar), Accent::Subdivision);
+        }
+    }
+
+    #[test]
+    fn synthesizes_a_decaying_click_in_every_timbre() {
+        for timbre in [
+            ClickTimbre::Woodblock,
+            ClickTimbre::Beep,
+            ClickTimbre::Noise,
+        ] {
+            let settings = ClickSettings {
+                timbre,
+                ..ClickSettings::default()
+            };
+            let click = render(&settings, Accent::Downbeat);
+            assert!(click.iter().all(|s| s.is_finite()), "{:?}", timbre);
+            // an attack of 1 ms and a decay of 40 ms, silent after
+            let quarter = (RATE * 0.01) as usize;
+            let first = peak(&click[..quarter]);
+            let later = peak(&click[quarter * 3..quarter * 4]);
+            assert!(first > 0.1, "{:?}", timbre);
+            assert!(later < first / 2.0, "{:?}", timbre);
+            let end = ((settings.attack + settings.decay) as f64 * RATE) as usize + 1;
+            assert_eq!(peak(&click[end..]), 0.0, "{:?}", timbre);
+        }
+    }
+
+    #[test]
+    fn leaves_the_sample_timbre_to_the_kit() {
+        let settings = ClickSettings {
+            timbre: ClickTimbre::Sample,
+            ..ClickSettings::default()
+        };
+        assert_eq!(peak(&render(&settings, Accent::Downbeat)), 0.0);
+    }
+
+    #[test]
+    fn accents_click_at_their_own_levels() {
+        let settings = ClickSettings {
+            timbre: ClickTimbre::Beep,
+            ..ClickSettings::default()
+        };
+        let downbeat = peak(&render(&settings, Accent::Downbeat));
+        let beat = peak(&render(&settings, Accent::Beat));
+        let subdivision = peak(&render(&settings, Accent::Subdivision));
+        assert!(downbeat > beat && beat > subdivision && subdivision > 0.0);
+        assert!((beat / downbeat - 0.7).abs() < 0.05);
+        assert!((subdivision / downbeat - 0.4).abs() < 0.05);
+    }
+}
@@ -1,35 +1,209 @@
-use crate::get_loop_buffer_size::get_loop_buffer_size;
-use crate::read_audio_file::get_samples_from_filename;
-use crate::structs::{
-    BeatResetState, Config, ConfigState, LogState, LoopBufferState, Mp3BufferState, Payload,
-    SampleOutputBuffer,
+use app_lib::audio_backend::{DeviceInfo, StreamSettings};
+use app_lib::backing_track::{
+    load_backing_track, BackingTrackInfo, FileLoadProgress, FileLoadStage,
 };
+use app_lib::device_manager::OpenedDevices;
+use app_lib::engine::EngineConfig;
+use app_lib::get_loop_buffer_size::get_loop_buffer_size;
+use app_lib::latency::{device_pair, measure, LatencyMeasurement};
+use app_lib::loop_file;
+use app_lib::looper::{LooperMode, LooperStatus};
+use app_lib::onset::{Hit, InputBeat};
+use app_lib::parse_rhythm;
+use app_lib::read_audio_file::{AudioFormat, SUPPORTED_FORMATS};
+use app_lib::recording::{Recording, RecordingOptions, RecordingSummary};
+use app_lib::settings::Settings;
+use app_lib::structs::{
+    BackingTrackState, BeatResetState, Config, ConfigState, DeviceManagerState, FileLoadState,
+    HitState, KitState, LatencyProbeState, LogState, LoopBuffer, LoopBufferState,
+    LoopSnapshotState, LooperState, Mp3Buffer, Mp3BufferState, ParserRhythm, Payload,
+    RecordingSinkState, RecordingState, SampleOutputBuffer, SampleRateState, SettingsState,
+    TimingState, TrackSeekState,
+};
+use app_lib::timing::TimingStats;
+use rtrb::Consumer;
+use serde_json::Value;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 use tauri::{Manager, State};
+use triple_buffer::Output;
+
+/// Hits kept for `get_hits` when the frontend stops asking; older ones are dropped.
+const MAX_PENDING_HITS: usize = 4096;
 
+/// Loads `filename` as the backing track on a worker thread, sending
+/// `file_load_progress` events as it goes and cancelling any load still
+/// running. The decoded file replaces the old one in a single swap, the beat
+/// restarts so it plays from its start on beat 0, and it is remembered for the
+/// next launch.
 #[tauri::command]
-pub fn set_mp3_buffer(app_handle: tauri::AppHandle, filename: String) {
+pub async fn set_mp3_buffer(
+    app_handle: tauri::AppHandle,
+    filename: String,
+) -> Result<BackingTrackInfo, String> {
+    let cancel = Arc::new(AtomicBool::new(false));
+    let file_loads: tauri::State<FileLoadState> = app_handle.state();
+    if let Some(previous) = file_loads
+        .0
+        .lock()
+        .map_err(|e| e.to_string())?
+        .replace(cancel.clone())
+    {
+        previous.store(true, Ordering::Relaxed);
+    }
+    let sample_rate_state: tauri::State<SampleRateState> = app_handle.state();
+    // bring the file to the engine's rate and stereo before it reaches the audio thread
+    let sample_rate = sample_rate_state.0.load(Ordering::Relaxed);
+
+    let worker_handle = app_handle.clone();
+    let worker_cancel = cancel.clone();
+    let path = filename.clone();
+    let loaded = tauri::async_runtime::spawn_blocking(move || {
+        load_backing_track(&path, sample_rate, &worker_cancel, &mut |progress| {
+            let _ = worker_handle.emit_all("file_load_progress", progress);
+        })
+    })
+    .await
+    .map_err(|e| e.to_string())?;
+    let result =
+        loaded.and_then(|(buffer, info)| swap_in_backing_track(&app_handle, &cancel, buffer, info));
+
+    let event = match &result {
+        Ok(_) => FileLoadProgress {
+            progress: Some(1.0),
+            ..FileLoadProgress::new(&filename, FileLoadStage::Loaded)
+        },
+        Err(_) if cancel.load(Ordering::Relaxed) => {
+            FileLoadProgress::new(&filename, FileLoadStage::Cancelled)
+        }
+        Err(err) => FileLoadProgress {
+            error: Some(err.clone()),
+            ..FileLoadProgress::new(&filename, FileLoadStage::Failed)
+        },
+    };
+    let _ = app_handle.emit_all("file_load_progress", event);
+    if result.is_err() && cancel.load(Ordering::Relaxed) {
+        return Err(format!("loading {} was cancelled", filename));
+    }
+    result
+}
+
+/// Hands a decoded backing track to the audio thread unless its load has been
+/// cancelled, checked under the lock a newer load takes to cancel it.
+fn swap_in_backing_track(
+    app_handle: &tauri::AppHandle,
+    cancel: &Arc<AtomicBool>,
+    buffer: Mp3Buffer,
+    info: BackingTrackInfo,
+) -> Result<BackingTrackInfo, String> {
+    let file_loads: tauri::State<FileLoadState> = app_handle.state();
+    let mut current_load = file_loads.0.lock().map_err(|e| e.to_string())?;
+    if cancel.load(Ordering::Relaxed) {
+        return Err("cancelled".to_string());
+    }
+    *current_load = None;
+    println!("loaded backing track: {:?}", info);
+    let seek = buffer.seek_request();
     let mp3_buffer_state: tauri::State<Mp3BufferState> = app_handle.state();
+    if mp3_buffer_state
+        .0
+        .lock()
+        .map_err(|e| e.to_string())?
+        .send(buffer)
+        .is_err()
+    {
+        return Err("audio thread is not taking new files".to_string());
+    }
+    let track_seek: tauri::State<TrackSeekState> = app_handle.state();
+    *track_seek.0.lock().map_err(|e| e.to_string())? = Some(seek);
     let beat_state_reset: tauri::State<BeatResetState> = app_handle.state();
-    let mut mp3_buffer = mp3_buffer_state.0.lock().unwrap();
-    let samples = get_samples_from_filename(&filename);
-    if let Err(_err) = samples {
-        println!("Error while reading file: {}", _err);
-    } else {
-        let samples = samples.unwrap();
-        println!("samples: {}", samples.len());
-        mp3_buffer.buffer = samples;
-        mp3_buffer.pos = 0;
-        beat_state_reset
-            .0
-            .store(true, std::sync::atomic::Ordering::Relaxed);
+    // also starts the timing scores over, see `TimingScorer`
+    beat_state_reset.0.store(true, Ordering::Relaxed);
+    let backing_track: tauri::State<BackingTrackState> = app_handle.state();
+    *backing_track.0.lock().map_err(|e| e.to_string())? = Some(info.clone());
+    let settings: tauri::State<SettingsState> = app_handle.state();
+    if let Err(err) = settings
+        .0
+        .lock()
+        .map_err(|e| e.to_string())?
+        .set_backing_track(Some(info.path.clone()))
+    {
+        println!("could not save settings: {}", err);
+    }
+    Ok(info)
+}
+
+/// Stops the backing track being loaded, if any. The one playing stays.
+#[tauri::command]
+pub fn cancel_file_load(state: State<FileLoadState>) -> Result<(), String> {
+    if let Some(cancel) = state.0.lock().map_err(|e| e.to_string())?.take() {
+        cancel.store(true, Ordering::Relaxed);
+    }
+    Ok(())
+}
+
+/// The file types `set_mp3_buffer` and `load_loop` can read, for the file dialog.
+#[tauri::command]
+pub fn supported_formats() -> Vec<AudioFormat> {
+    SUPPORTED_FORMATS.to_vec()
+}
+
+/// The backing track playing, `None` when no file is loaded.
+#[tauri::command]
+pub fn get_backing_track(
+    state: State<BackingTrackState>,
+) -> Result<Option<BackingTrackInfo>, String> {
+    Ok(state.0.lock().map_err(|e| e.to_string())?.clone())
+}
+
+/// Plays the backing track on from `seconds` into the file, wrapping past its end.
+#[tauri::command]
+pub fn seek_backing_track(state: State<TrackSeekState>, seconds: f64) -> Result<(), String> {
+    match state.0.lock().map_err(|e| e.to_string())?.as_ref() {
+        Some(seek) => {
+            seek.seek(seconds);
+            Ok(())
+        }
+        None => Err("no backing track is loaded".to_string()),
+    }
+}
+
+/// Stops the backing track and any file still loading, and forgets it.
+#[tauri::command]
+pub fn clear_backing_track(app_handle: tauri::AppHandle) -> Result<(), String> {
+    let file_loads: tauri::State<FileLoadState> = app_handle.state();
+    if let Some(cancel) = file_loads.0.lock().map_err(|e| e.to_string())?.take() {
+        cancel.store(true, Ordering::Relaxed);
+    }
+    let sample_rate_state: tauri::State<SampleRateState> = app_handle.state();
+    let sample_rate = sample_rate_state.0.load(Ordering::Relaxed);
+    let mp3_buffer_state: tauri::State<Mp3BufferState> = app_handle.state();
+    if mp3_buffer_state
+        .0
+        .lock()
+        .map_err(|e| e.to_string())?
+        .send(Mp3Buffer::new(vec![], sample_rate))
+        .is_err()
+    {
+        return Err("audio thread is not taking new files".to_string());
     }
+    let backing_track: tauri::State<BackingTrackState> = app_handle.state();
+    *backing_track.0.lock().map_err(|e| e.to_string())? = None;
+    let track_seek: tauri::State<TrackSeekState> = app_handle.state();
+    *track_seek.0.lock().map_err(|e| e.to_string())? = None;
+    let settings: tauri::State<SettingsState> = app_handle.state();
+    let mut settings = settings.0.lock().map_err(|e| e.to_string())?;
+    settings.set_backing_track(None)
 }
 
 #[tauri::command]
 pub fn get_samples(state: State<SampleOutputBuffer>) -> Result<Vec<(f32, f32)>, String> {
     if let Ok(mut samples) = state.buffer.lock() {
-        let res = samples.to_vec();
-        samples.clear();
+        let mut res = Vec::with_capacity(samples.slots());
+        while let Ok(sample) = samples.pop() {
+            res.push(sample);
+        }
         return Ok(res);
     } else {
         return Err("get_samples failed.".into());
@@ -37,13 +211,30 @@ pub fn get_samples(state: State<SampleOutputBuffer>) -> Result<Vec<(f32, f32)>,
 }
 
 #[tauri::command]
+/// The timing scores start over once the audio thread has reset the beat.
 pub fn reset_beat(state: State<BeatResetState>) -> Result<(), String> {
     state.0.store(true, std::sync::atomic::Ordering::Relaxed);
     Ok(())
 }
 
+/// Makes `new_config` current and saves it, with `frontend` (state the
+/// frontend wants back with it, like the rhythm notation) if given.
 #[tauri::command]
-pub fn set_config(app_handle: tauri::AppHandle, new_config: Config) {
+pub fn set_config(app_handle: tauri::AppHandle, new_config: Config, frontend: Option<Value>) {
+    let settings: tauri::State<SettingsState> = app_handle.state();
+    if let Err(err) = settings
+        .0
+        .lock()
+        .unwrap()
+        .set_config(new_config.clone(), frontend)
+    {
+        println!("could not save settings: {}", err);
+    }
+    apply_config(&app_handle, new_config);
+}
+
+/// Sends `new_config` to the audio thread and the scorer.
+fn apply_config(app_handle: &tauri::AppHandle, new_config: Config) {
     let logs: tauri::State<LogState> = app_handle.state();
     let logs = logs.0.lock().unwrap();
 
@@ -59,20 +250,374 @@ pub fn set_config(app_handle: tauri::AppHandle, new_config: Config) {
     let config_state: tauri::State<ConfigState> = app_handle.state();
     let mut config = config_state.0.lock().unwrap();
 
-    let should_update_loop_buffer = new_config.bpm != config.bpm
-        || new_config.beats_to_loop != config.beats_to_loop
-        || new_config.buffer_compensation != config.buffer_compensation;
-    *config = new_config;
+    let old_config = &config.current().config;
+    let should_update_loop_buffer = new_config.bpm != old_config.bpm
+        || new_config.beats_to_loop != old_config.beats_to_loop
+        || new_config.buffer_compensation != old_config.buffer_compensation;
+    let sample_rate: tauri::State<SampleRateState> = app_handle.state();
+    let sample_rate = sample_rate.0.load(std::sync::atomic::Ordering::Relaxed);
+    let new_buffer_size = get_loop_buffer_size(&new_config, sample_rate);
+    let timing: tauri::State<TimingState> = app_handle.state();
+    timing
+        .0
+        .lock()
+        .unwrap()
+        .set_rhythm(new_config.scored_rhythm());
+    let kit: tauri::State<KitState> = app_handle.state();
+    kit.0.lock().unwrap().update(&new_config.kit, sample_rate);
+    config.publish(EngineConfig::new(new_config));
 
     if should_update_loop_buffer {
         println!("updating loop buffer");
-        let c = config.clone();
-        let new_buffer_size = get_loop_buffer_size(&c);
         let loop_buffer_state: tauri::State<LoopBufferState> = app_handle.state();
         let mut loop_buffer = loop_buffer_state.0.lock().unwrap();
-        // loop_buffer.buffer.clear();
-        loop_buffer.buffer.resize(new_buffer_size, 0.0);
-        loop_buffer.pos = 0;
+        // allocated here and swapped in by the audio thread, which keeps the old contents
+        let new_buffer = LoopBuffer::new(new_buffer_size);
+        if loop_buffer.send(new_buffer).is_err() {
+            println!("audio thread is not taking new loop buffers");
+        }
         println!("new_buffer_size: {}", new_buffer_size);
     }
 }
+
+#[tauri::command]
+pub fn list_audio_devices(state: State<DeviceManagerState>) -> Result<Vec<DeviceInfo>, String> {
+    let device_manager = state.0.lock().map_err(|e| e.to_string())?;
+    device_manager.list_devices()
+}
+
+/// Rebuilds the streams on the given device ids (`None` for the default device).
+/// The engine, and with it the loop and beat position, carries over.
+#[tauri::command]
+pub fn select_audio_devices(
+    state: State<DeviceManagerState>,
+    input: Option<String>,
+    output: Option<String>,
+) -> Result<OpenedDevices, String> {
+    let device_manager = state.0.lock().map_err(|e| e.to_string())?;
+    device_manager.select_devices(input, output)
+}
+
+/// Reopens the current devices at another sample rate and buffer size. The
+/// devices may settle on something else, which the returned formats report.
+#[tauri::command]
+pub fn set_stream_settings(
+    state: State<DeviceManagerState>,
+    sample_rate: f64,
+    buffer_size: usize,
+) -> Result<OpenedDevices, String> {
+    let device_manager = state.0.lock().map_err(|e| e.to_string())?;
+    device_manager.configure_streams(StreamSettings {
+        sample_rate,
+        buffer_size,
+    })
+}
+
+/// Plays a chirp through the current output and listens for it on the input,
+/// on a worker thread. The round trip becomes `buffer_compensation` and is
+/// saved for the device pair. Other audio is muted for about a second.
+#[tauri::command]
+pub async fn measure_latency(app_handle: tauri::AppHandle) -> Result<LatencyMeasurement, String> {
+    let device_manager: tauri::State<DeviceManagerState> = app_handle.state();
+    let (input, output) = device_manager
+        .0
+        .lock()
+        .map_err(|e| e.to_string())?
+        .selected_devices()?;
+    let worker_handle = app_handle.clone();
+    let measured = tauri::async_runtime::spawn_blocking(move || {
+        let probes: tauri::State<LatencyProbeState> = worker_handle.state();
+        let sample_rate: tauri::State<SampleRateState> = worker_handle.state();
+        let sample_rate = sample_rate.0.load(Ordering::Relaxed);
+        let mut probes = probes.0.lock().map_err(|e| e.to_string())?;
+        measure(&mut probes, sample_rate)
+    })
+    .await
+    .map_err(|e| e.to_string())?;
+    let measurement = measured?;
+    println!("measured latency: {:?}", measurement);
+
+    let settings: tauri::State<SettingsState> = app_handle.state();
+    let config = {
+        let mut settings = settings.0.lock().map_err(|e| e.to_string())?;
+        settings.set_latency(
+            device_pair(input.as_deref(), output.as_deref()),
+            measurement.clone(),
+        )?;
+        settings.settings().config.clone()
+    };
+    apply_config(&app_handle, config);
+    Ok(measurement)
+}
+
+/// The last measurement for a device pair, `None` for the default device.
+#[tauri::command]
+pub fn get_measured_latency(
+    state: State<SettingsState>,
+    input: Option<String>,
+    output: Option<String>,
+) -> Result<Option<LatencyMeasurement>, String> {
+    let settings = state.0.lock().map_err(|e| e.to_string())?;
+    Ok(settings
+        .settings()
+        .latencies
+        .get(&device_pair(input.as_deref(), output.as_deref()))
+        .cloned())
+}
+
+/// Hits detected on the input since the last call, oldest first. Each hit is
+/// also sent as a `hit` event as soon as it is detected.
+#[tauri::command]
+pub fn get_hits(state: State<HitState>) -> Result<Vec<Hit>, String> {
+    let mut hits = state.0.lock().map_err(|e| e.to_string())?;
+    Ok(hits.drain(..).collect())
+}
+
+/// Hit counts and early/late offsets against the first layer's rhythm, for the
+/// session since the last beat reset or rhythm change and for each bar of it.
+#[tauri::command]
+pub fn get_timing_stats(state: State<TimingState>) -> Result<TimingStats, String> {
+    Ok(state.0.lock().map_err(|e| e.to_string())?.stats())
+}
+
+/// Moves hits off the audio thread's ring buffer, emitting a `hit` event for
+/// each, keeping them for `get_hits` and scoring them. Runs for the life of the app.
+pub fn forward_hits(
+    app_handle: tauri::AppHandle,
+    mut hits: Consumer<Hit>,
+    mut input_beat: Output<InputBeat>,
+) {
+    let hit_state: tauri::State<HitState> = app_handle.state();
+    let config_state: tauri::State<ConfigState> = app_handle.state();
+    let timing_state: tauri::State<TimingState> = app_handle.state();
+    loop {
+        // read first: every hit before it is already in the ring
+        let input_beat = *input_beat.read();
+        let bpm = match config_state.0.lock() {
+            Ok(config) => config.current().config.bpm,
+            Err(_) => return,
+        };
+        let mut timing = match timing_state.0.lock() {
+            Ok(timing) => timing,
+            Err(_) => return,
+        };
+        while let Ok(hit) = hits.pop() {
+            let _ = app_handle.emit_all("hit", hit);
+            if let Ok(mut pending) = hit_state.0.lock() {
+                if pending.len() >= MAX_PENDING_HITS {
+                    pending.pop_front();
+                }
+                pending.push_back(hit);
+            }
+            timing.score(&hit, bpm);
+        }
+        timing.advance(input_beat);
+        drop(timing);
+        std::thread::sleep(Duration::from_millis(5));
+    }
+}
+
+/// Writes the current loop to `path` as a WAV file one loop long, starting on
+/// beat 0 as heard, i.e. with `buffer_compensation` taken out. Waits for the
+/// audio thread's copy and writes the file on a worker thread.
+#[tauri::command]
+pub async fn export_loop(app_handle: tauri::AppHandle, path: String) -> Result<(), String> {
+    let config_state: tauri::State<ConfigState> = app_handle.state();
+    let config = config_state
+        .0
+        .lock()
+        .map_err(|e| e.to_string())?
+        .current()
+        .config
+        .clone();
+    let sample_rate: tauri::State<SampleRateState> = app_handle.state();
+    let sample_rate = sample_rate.0.load(Ordering::Relaxed);
+    let worker_handle = app_handle.clone();
+    tauri::async_runtime::spawn_blocking(move || {
+        let snapshots: tauri::State<LoopSnapshotState> = worker_handle.state();
+        let mut snapshots = snapshots.0.lock().map_err(|e| e.to_string())?;
+        let frames = loop_file::export_loop(&mut snapshots, &config, sample_rate, &path)?;
+        println!("saved {} loop frames to {}", frames, path);
+        Ok(())
+    })
+    .await
+    .map_err(|e| e.to_string())?
+}
+
+/// Plays `filename` as the loop from the next bar line on, cut or padded to
+/// the loop length. The looper switches to play, so the input does not
+/// replace it.
+#[tauri::command]
+pub fn load_loop(app_handle: tauri::AppHandle, filename: String) -> Result<(), String> {
+    let config_state: tauri::State<ConfigState> = app_handle.state();
+    let config = config_state
+        .0
+        .lock()
+        .map_err(|e| e.to_string())?
+        .current()
+        .config
+        .clone();
+    let sample_rate: tauri::State<SampleRateState> = app_handle.state();
+    let sample_rate = sample_rate.0.load(Ordering::Relaxed);
+    let loop_buffer = loop_file::load_loop(&filename, &config, sample_rate)?;
+    let loop_buffer_state: tauri::State<LoopBufferState> = app_handle.state();
+    let mut loop_buffers = loop_buffer_state.0.lock().map_err(|e| e.to_string())?;
+    if loop_buffers.send(loop_buffer).is_err() {
+        return Err("audio thread is not taking new loop buffers".to_string());
+    }
+    let looper: tauri::State<LooperState> = app_handle.state();
+    looper
+        .0
+        .lock()
+        .map_err(|e| e.to_string())?
+        .reference_loaded();
+    Ok(())
+}
+
+/// Parses rhythm notation the way configs and presets holding it as text are parsed.
+#[tauri::command]
+pub fn parse_rhythm(text: String) -> Result<ParserRhythm, String> {
+    parse_rhythm::parse_rhythm(&text).map_err(|e| e.describe(&text))
+}
+
+/// The saved config and frontend state, for the frontend to start from.
+#[tauri::command]
+pub fn get_settings(state: State<SettingsState>) -> Result<Settings, String> {
+    Ok(state
+        .0
+        .lock()
+        .map_err(|e| e.to_string())?
+        .settings()
+        .clone())
+}
+
+#[tauri::command]
+pub fn list_presets(state: State<SettingsState>) -> Result<Vec<String>, String> {
+    state.0.lock().map_err(|e| e.to_string())?.list_presets()
+}
+
+/// Saves the current config and frontend state as a preset named `name`.
+#[tauri::command]
+pub fn save_preset(state: State<SettingsState>, name: String) -> Result<(), String> {
+    state
+        .0
+        .lock()
+        .map_err(|e| e.to_string())?
+        .save_preset(&name)
+}
+
+/// Makes the preset current and returns it, for the frontend to show.
+#[tauri::command]
+pub fn load_preset(app_handle: tauri::AppHandle, name: String) -> Result<Settings, String> {
+    let settings: tauri::State<SettingsState> = app_handle.state();
+    let preset = settings
+        .0
+        .lock()
+        .map_err(|e| e.to_string())?
+        .load_preset(&name)?;
+    apply_config(&app_handle, preset.config.clone());
+    Ok(preset)
+}
+
+#[tauri::command]
+pub fn delete_preset(state: State<SettingsState>, name: String) -> Result<(), String> {
+    state
+        .0
+        .lock()
+        .map_err(|e| e.to_string())?
+        .delete_preset(&name)
+}
+
+/// Switches the looper between record, play and overdub.
+#[tauri::command]
+pub fn set_looper_mode(
+    state: State<LooperState>,
+    mode: LooperMode,
+) -> Result<LooperStatus, String> {
+    state.0.lock().map_err(|e| e.to_string())?.set_mode(mode)
+}
+
+/// How much of the loop each overdub pass keeps, from 0.0 to 1.0.
+#[tauri::command]
+pub fn set_overdub_feedback(
+    state: State<LooperState>,
+    feedback: f32,
+) -> Result<LooperStatus, String> {
+    state
+        .0
+        .lock()
+        .map_err(|e| e.to_string())?
+        .set_feedback(feedback)
+}
+
+#[tauri::command]
+pub fn clear_loop(state: State<LooperState>) -> Result<LooperStatus, String> {
+    state.0.lock().map_err(|e| e.to_string())?.clear()
+}
+
+/// Takes the last overdub layer out of the loop.
+#[tauri::command]
+pub fn undo_overdub(state: State<LooperState>) -> Result<LooperStatus, String> {
+    state.0.lock().map_err(|e| e.to_string())?.undo()
+}
+
+#[tauri::command]
+pub fn get_looper_status(state: State<LooperState>) -> Result<LooperStatus, String> {
+    Ok(state.0.lock().map_err(|e| e.to_string())?.status())
+}
+
+/// Streams the raw input to `path` (a WAV file) until `stop_recording`, with
+/// the mix and click in files next to it when `options` asks for them and the
+/// beat positions in a CSV sidecar.
+#[tauri::command]
+pub fn start_recording(
+    app_handle: tauri::AppHandle,
+    path: String,
+    options: Option<RecordingOptions>,
+) -> Result<(), String> {
+    let recording_state: tauri::State<RecordingState> = app_handle.state();
+    let mut recording = recording_state.0.lock().map_err(|e| e.to_string())?;
+    if recording.is_some() {
+        return Err("already recording".to_string());
+    }
+    let device_manager: tauri::State<DeviceManagerState> = app_handle.state();
+    let opened = device_manager
+        .0
+        .lock()
+        .map_err(|e| e.to_string())?
+        .opened_devices()?;
+    let sinks: tauri::State<RecordingSinkState> = app_handle.state();
+    let mut sinks = sinks.0.lock().map_err(|e| e.to_string())?;
+    *recording = Some(Recording::start(
+        &mut sinks,
+        &path,
+        opened.input.channels,
+        opened.output.sample_rate,
+        options.unwrap_or_default(),
+    )?);
+    println!("recording to {}", path);
+    Ok(())
+}
+
+/// Stops the recording and returns the files written, once the writer has
+/// finished them on a worker thread.
+#[tauri::command]
+pub async fn stop_recording(app_handle: tauri::AppHandle) -> Result<RecordingSummary, String> {
+    let recording_state: tauri::State<RecordingState> = app_handle.state();
+    let recording = recording_state
+        .0
+        .lock()
+        .map_err(|e| e.to_string())?
+        .take()
+        .ok_or_else(|| "not recording".to_string())?;
+    let worker_handle = app_handle.clone();
+    tauri::async_runtime::spawn_blocking(move || {
+        let sinks: tauri::State<RecordingSinkState> = worker_handle.state();
+        let mut sinks = sinks.0.lock().map_err(|e| e.to_string())?;
+        let summary = recording.stop(&mut sinks)?;
+        println!("recorded {:?}", summary);
+        Ok(summary)
+    })
+    .await
+    .map_err(|e| e.to_string())?
+}
@@ -1,31 +1,31 @@
-use crate::structs::{Config, Note, ParserRhythm};
-use coreaudio::audio_unit::SampleFormat;
-pub const SAMPLE_RATE: f64 = 44100.0;
-pub const SAMPLE_FORMAT: SampleFormat = SampleFormat::F32;
+use crate::click::ClickSettings;
+use crate::kit::default_kit;
+use crate::structs::{Config, InputChannel, Note, ParserRhythm, RhythmLayer};
+/// Requested when opening devices; the device may settle on something else.
+pub const DEFAULT_SAMPLE_RATE: f64 = 44100.0;
+pub const DEFAULT_BUFFER_SIZE: usize = 2048;
 
 pub fn default_config() -> Config {
     return Config {
         bpm: 91.0,
         beats_to_loop: 4.0,
         audio_in_gain: 1.0,
+        input_channels: vec![InputChannel::default()],
         looping_on: false,
         click_on: true,
         click_toggle: false,
         click_volume: 0.3,
+        click: ClickSettings::default(),
         drum_on: true,
         play_file: true,
         visual_monitor_on: true,
         audio_monitor_on: false,
         buffer_compensation: 4330,
-        audio_subdivisions: ParserRhythm {
+        layers: vec![RhythmLayer::new(ParserRhythm {
             start: 0.0,
             end: 1.0,
-            notes: vec![Note { time: 0.0 }, Note { time: 0.5 }],
-        },
-        test_object: ParserRhythm {
-            start: 0.0,
-            end: 0.0,
-            notes: vec![],
-        },
+            notes: vec![Note::at(0.0), Note::at(0.5)],
+        })],
+        kit: default_kit(),
     };
 }
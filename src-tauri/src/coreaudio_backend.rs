@@ -0,0 +1,380 @@
+extern crate coreaudio;
+
+use crate::audio_backend::{
+    make_buffers, pop_input, push_input, AudioBackend, ChannelSlices, ChannelSlicesMut,
+    DeviceFormat, DeviceInfo, ProcessCallback, StreamSettings, COMMON_SAMPLE_RATES, MAX_CHANNELS,
+};
+use crate::structs::Buffers;
+use crate::types::S;
+use coreaudio::audio_unit::audio_format::LinearPcmFlags;
+use coreaudio::audio_unit::macos_helpers::{
+    audio_unit_from_device_id, get_audio_device_ids, get_default_device_id, get_device_name,
+    get_supported_physical_stream_formats,
+};
+use coreaudio::audio_unit::render_callback::{self, data};
+use coreaudio::audio_unit::{AudioUnit, Element, SampleFormat, Scope, StreamFormat};
+use coreaudio::sys::*;
+
+pub const SAMPLE_FORMAT: SampleFormat = SampleFormat::F32;
+
+pub type Args = render_callback::Args<data::NonInterleaved<S>>;
+pub type InputArgs = render_callback::Args<data::Interleaved<S>>;
+
+pub struct CoreAudioBackend {
+    input_audio_unit: Option<AudioUnit>,
+    output_audio_unit: Option<AudioUnit>,
+    input_channels: usize,
+    settings: StreamSettings,
+    input_log: Vec<String>,
+    output_log: Vec<String>,
+}
+
+impl CoreAudioBackend {
+    pub fn new() -> CoreAudioBackend {
+        let devices = get_audio_device_ids();
+        devices.unwrap().iter().for_each(|d| {
+            println!("device: {:?}", get_device_name(*d));
+            println!("{:?}", get_supported_physical_stream_formats(*d));
+        });
+        CoreAudioBackend {
+            input_audio_unit: None,
+            output_audio_unit: None,
+            input_channels: 0,
+            settings: StreamSettings::default(),
+            input_log: vec![],
+            output_log: vec![],
+        }
+    }
+
+    fn device_id(device: Option<&str>, input: bool) -> Result<AudioDeviceID, String> {
+        match device {
+            Some(id) => id
+                .parse::<AudioDeviceID>()
+                .map_err(|_| format!("no audio device with id {}", id)),
+            None if input => {
+                get_default_device_id(true).ok_or_else(|| "no default input device".into())
+            }
+            None => get_default_device_id(false).ok_or_else(|| "no default output device".into()),
+        }
+    }
+
+    /// The format the callbacks see. The audio unit converts from the hardware
+    /// rate, so any `sample_rate` should be granted; `stream_rate` reads back
+    /// what was.
+    fn stream_format(
+        &self,
+        channels: u32,
+        interleaved: bool,
+        sample_rate: f64,
+    ) -> Result<StreamFormat, String> {
+        let format_flag = match SAMPLE_FORMAT {
+            SampleFormat::F32 => LinearPcmFlags::IS_FLOAT,
+            SampleFormat::I32 | SampleFormat::I16 | SampleFormat::I8 => {
+                LinearPcmFlags::IS_SIGNED_INTEGER
+            }
+            format => return Err(format!("unsupported sample format {:?}", format)),
+        };
+
+        let mut flags = format_flag | LinearPcmFlags::IS_PACKED;
+        if !interleaved {
+            flags |= LinearPcmFlags::IS_NON_INTERLEAVED;
+        }
+        Ok(StreamFormat {
+            sample_rate,
+            sample_format: SAMPLE_FORMAT,
+            flags,
+            channels,
+        })
+    }
+
+    fn describe(stream_format: &StreamFormat) -> Vec<String> {
+        let lines = vec![
+            format!("{:#?}", stream_format),
+            format!("{:#?}", stream_format.to_asbd()),
+        ];
+        lines.iter().for_each(|line| println!("{}", line));
+        lines
+    }
+}
+
+/// Reads a device property, returning its raw bytes (8 byte aligned) and size.
+fn device_property(
+    device_id: AudioDeviceID,
+    selector: AudioObjectPropertySelector,
+    scope: AudioObjectPropertyScope,
+) -> Result<(Vec<u64>, usize), String> {
+    let address = AudioObjectPropertyAddress {
+        mSelector: selector,
+        mScope: scope,
+        mElement: kAudioObjectPropertyElementMaster,
+    };
+    let mut size: u32 = 0;
+    let status = unsafe {
+        AudioObjectGetPropertyDataSize(device_id, &address, 0, std::ptr::null(), &mut size)
+    };
+    if status != 0 {
+        return Err(format!("AudioObjectGetPropertyDataSize failed: {}", status));
+    }
+    let mut data = vec![0u64; (size as usize + 7) / 8];
+    let status = unsafe {
+        AudioObjectGetPropertyData(
+            device_id,
+            &address,
+            0,
+            std::ptr::null(),
+            &mut size,
+            data.as_mut_ptr() as *mut std::ffi::c_void,
+        )
+    };
+    if status != 0 {
+        return Err(format!("AudioObjectGetPropertyData failed: {}", status));
+    }
+    Ok((data, size as usize))
+}
+
+/// The rate the audio unit's callbacks run at on `scope` of `element`, as it
+/// took the stream format asked for.
+fn stream_rate(audio_unit: &AudioUnit, scope: Scope, element: Element) -> Result<f64, String> {
+    let format: AudioStreamBasicDescription = audio_unit
+        .get_property(kAudioUnitProperty_StreamFormat, scope, element)
+        .map_err(|e| e.to_string())?;
+    Ok(format.mSampleRate)
+}
+
+/// Asks the device for `frames` per buffer and returns what it settled on. The
+/// buffer size belongs to the whole device, so it is set in the global scope.
+fn set_buffer_size(audio_unit: &mut AudioUnit, frames: usize) -> Result<usize, String> {
+    let frames = frames as u32;
+    audio_unit
+        .set_property(
+            kAudioDevicePropertyBufferFrameSize,
+            Scope::Global,
+            Element::Output,
+            Some(&frames),
+        )
+        .map_err(|e| e.to_string())?;
+    let frames: u32 = audio_unit
+        .get_property(
+            kAudioDevicePropertyBufferFrameSize,
+            Scope::Global,
+            Element::Output,
+        )
+        .map_err(|e| e.to_string())?;
+    Ok(frames as usize)
+}
+
+fn device_channels(device_id: AudioDeviceID, scope: AudioObjectPropertyScope) -> usize {
+    let (data, _) = match device_property(device_id, kAudioDevicePropertyStreamConfiguration, scope)
+    {
+        Ok(property) => property,
+        Err(_) => return 0,
+    };
+    let buffer_list = data.as_ptr() as *const AudioBufferList;
+    unsafe {
+        let buffers = std::slice::from_raw_parts(
+            (*buffer_list).mBuffers.as_ptr(),
+            (*buffer_list).mNumberBuffers as usize,
+        );
+        buffers.iter().map(|b| b.mNumberChannels as usize).sum()
+    }
+}
+
+fn device_sample_rates(device_id: AudioDeviceID) -> Vec<f64> {
+    let (data, size) = match device_property(
+        device_id,
+        kAudioDevicePropertyAvailableNominalSampleRates,
+        kAudioObjectPropertyScopeGlobal,
+    ) {
+        Ok(property) => property,
+        Err(_) => return vec![],
+    };
+    let ranges = unsafe {
+        std::slice::from_raw_parts(
+            data.as_ptr() as *const AudioValueRange,
+            size / std::mem::size_of::<AudioValueRange>(),
+        )
+    };
+    COMMON_SAMPLE_RATES
+        .iter()
+        .copied()
+        .filter(|rate| {
+            ranges
+                .iter()
+                .any(|r| r.mMinimum <= *rate && *rate <= r.mMaximum)
+        })
+        .collect()
+}
+
+impl AudioBackend for CoreAudioBackend {
+    fn name(&self) -> &'static str {
+        "coreaudio"
+    }
+
+    fn devices(&self) -> Result<Vec<DeviceInfo>, String> {
+        let ids = get_audio_device_ids().map_err(|e| e.to_string())?;
+        Ok(ids
+            .into_iter()
+            .map(|id| DeviceInfo {
+                id: id.to_string(),
+                name: get_device_name(id).unwrap_or_else(|_| format!("device {}", id)),
+                input_channels: device_channels(id, kAudioObjectPropertyScopeInput),
+                output_channels: device_channels(id, kAudioObjectPropertyScopeOutput),
+                sample_rates: device_sample_rates(id),
+            })
+            .collect())
+    }
+
+    fn open_input(
+        &mut self,
+        device: Option<&str>,
+        settings: &StreamSettings,
+    ) -> Result<DeviceFormat, String> {
+        let device_id = CoreAudioBackend::device_id(device, true)?;
+        let mut input_audio_unit =
+            audio_unit_from_device_id(device_id, true).map_err(|e| e.to_string())?;
+
+        // the channel count the hardware delivers on the input element
+        let hardware_format: AudioStreamBasicDescription = input_audio_unit
+            .get_property(
+                kAudioUnitProperty_StreamFormat,
+                Scope::Input,
+                Element::Input,
+            )
+            .map_err(|e| e.to_string())?;
+        let channels = (hardware_format.mChannelsPerFrame as usize).clamp(1, MAX_CHANNELS);
+
+        // audio_unit.set_input_callback renders into a single buffer, so all
+        // channels arrive interleaved and are split in the callback
+        let in_stream_format = self.stream_format(channels as u32, true, settings.sample_rate)?;
+        self.input_log = CoreAudioBackend::describe(&in_stream_format);
+
+        let asbd = in_stream_format.to_asbd();
+        input_audio_unit
+            .set_property(
+                kAudioUnitProperty_StreamFormat,
+                Scope::Output,
+                Element::Input,
+                Some(&asbd),
+            )
+            .map_err(|e| e.to_string())?;
+
+        let sample_rate = stream_rate(&input_audio_unit, Scope::Output, Element::Input)?;
+        let buffer_size = set_buffer_size(&mut input_audio_unit, settings.buffer_size)?;
+
+        self.input_channels = channels;
+        self.input_audio_unit = Some(input_audio_unit);
+        Ok(DeviceFormat {
+            sample_rate,
+            channels,
+            buffer_size,
+        })
+    }
+
+    fn open_output(
+        &mut self,
+        device: Option<&str>,
+        settings: &StreamSettings,
+    ) -> Result<DeviceFormat, String> {
+        let device_id = CoreAudioBackend::device_id(device, false)?;
+        let mut output_audio_unit =
+            audio_unit_from_device_id(device_id, false).map_err(|e| e.to_string())?;
+
+        let out_stream_format = self.stream_format(2, false, settings.sample_rate)?;
+        self.output_log = CoreAudioBackend::describe(&out_stream_format);
+
+        let asbd = out_stream_format.to_asbd();
+        output_audio_unit
+            .set_property(
+                kAudioUnitProperty_StreamFormat,
+                Scope::Input,
+                Element::Output,
+                Some(&asbd),
+            )
+            .map_err(|e| e.to_string())?;
+
+        let sample_rate = stream_rate(&output_audio_unit, Scope::Input, Element::Output)?;
+        let buffer_size = set_buffer_size(&mut output_audio_unit, settings.buffer_size)?;
+
+        self.output_audio_unit = Some(output_audio_unit);
+        self.settings = StreamSettings {
+            sample_rate,
+            buffer_size,
+        };
+        Ok(DeviceFormat {
+            sample_rate,
+            channels: 2,
+            buffer_size,
+        })
+    }
+
+    fn start(&mut self, mut callback: ProcessCallback) -> Result<(), String> {
+        let Buffers {
+            mut producers,
+            mut consumers,
+        } = make_buffers(self.input_channels, self.settings.sample_rate);
+        let buffer_size = self.settings.buffer_size;
+
+        if let Some(input_audio_unit) = self.input_audio_unit.as_mut() {
+            let input_channels = self.input_channels;
+            let mut deinterleaved: Vec<Vec<S>> = vec![vec![0.0; buffer_size]; input_channels];
+            input_audio_unit
+                .set_input_callback(move |args: InputArgs| {
+                    let InputArgs {
+                        num_frames, data, ..
+                    } = args;
+                    for (ch, channel) in deinterleaved.iter_mut().enumerate() {
+                        channel.resize(num_frames, 0.0);
+                        for (i, value) in channel.iter_mut().enumerate() {
+                            *value = data.buffer[i * input_channels + ch];
+                        }
+                    }
+                    let channels: ChannelSlices =
+                        deinterleaved.iter().map(|c| c.as_slice()).collect();
+                    push_input(&mut producers, &channels);
+                    Ok(())
+                })
+                .map_err(|e| e.to_string())?;
+            input_audio_unit.start().map_err(|e| e.to_string())?;
+        }
+
+        let output_audio_unit = self
+            .output_audio_unit
+            .as_mut()
+            .ok_or("output has not been opened")?;
+        let mut input_scratch: Vec<Vec<S>> = vec![vec![0.0; buffer_size]; consumers.len()];
+        output_audio_unit
+            .set_render_callback(move |args: Args| {
+                let Args {
+                    num_frames,
+                    mut data,
+                    ..
+                } = args;
+                pop_input(&mut consumers, &mut input_scratch, num_frames);
+                let input: ChannelSlices = input_scratch.iter().map(|c| c.as_slice()).collect();
+                let mut output: ChannelSlicesMut = data.channels_mut().take(MAX_CHANNELS).collect();
+                callback(&input, &mut output);
+                Ok(())
+            })
+            .map_err(|e| e.to_string())?;
+        output_audio_unit.start().map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<(), String> {
+        let mut stopped = Ok(());
+        // the callbacks are freed either way, which hands the engine back
+        if let Some(input_audio_unit) = self.input_audio_unit.as_mut() {
+            stopped = stopped.and(input_audio_unit.stop().map_err(|e| e.to_string()));
+            input_audio_unit.free_input_callback();
+        }
+        if let Some(output_audio_unit) = self.output_audio_unit.as_mut() {
+            stopped = stopped.and(output_audio_unit.stop().map_err(|e| e.to_string()));
+            output_audio_unit.free_render_callback();
+        }
+        stopped
+    }
+
+    fn device_formats(&self) -> Vec<String> {
+        [self.input_log.as_slice(), self.output_log.as_slice()].concat()
+    }
+}
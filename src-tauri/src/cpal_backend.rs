@@ -0,0 +1,315 @@
+use crate::audio_backend::{
+    make_buffers, pop_input, push_input, AudioBackend, ChannelSlices, ChannelSlicesMut,
+    DeviceFormat, DeviceInfo, ProcessCallback, StreamSettings, COMMON_SAMPLE_RATES, MAX_CHANNELS,
+};
+use crate::constants::DEFAULT_BUFFER_SIZE;
+use crate::structs::Buffers;
+use crate::types::S;
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::{
+    BufferSize, Device, Host, SampleRate, Stream, StreamConfig, SupportedBufferSize,
+    SupportedStreamConfigRange,
+};
+
+pub struct CpalBackend {
+    host: Host,
+    input: Option<(Device, StreamConfig)>,
+    output: Option<(Device, StreamConfig)>,
+    streams: Vec<Stream>,
+}
+
+impl CpalBackend {
+    pub fn new() -> Result<CpalBackend, String> {
+        let host = CpalBackend::host()?;
+        println!("cpal host: {:?}", host.id());
+        if let Ok(devices) = host.devices() {
+            devices.for_each(|d| println!("device: {:?}", d.name()));
+        }
+        Ok(CpalBackend {
+            host,
+            input: None,
+            output: None,
+            streams: vec![],
+        })
+    }
+
+    /// Uses JACK when built with the `jack` feature and `AUDIO_HOST=jack`, otherwise
+    /// the platform's default host (ALSA on Linux).
+    fn host() -> Result<Host, String> {
+        #[cfg(all(feature = "jack", target_os = "linux"))]
+        if std::env::var("AUDIO_HOST")
+            .map(|h| h == "jack")
+            .unwrap_or(false)
+        {
+            return cpal::host_from_id(cpal::HostId::Jack).map_err(|e| e.to_string());
+        }
+        Ok(cpal::default_host())
+    }
+
+    /// Uses the requested rate when the device supports it with `channels`, its
+    /// default rate otherwise, and clamps the buffer size to what it allows.
+    fn stream_config(
+        ranges: &[SupportedStreamConfigRange],
+        channels: u16,
+        settings: &StreamSettings,
+        default_rate: SampleRate,
+    ) -> StreamConfig {
+        let requested = SampleRate(settings.sample_rate as u32);
+        let supported = ranges.iter().find(|r| {
+            r.channels() == channels
+                && r.min_sample_rate() <= requested
+                && requested <= r.max_sample_rate()
+        });
+        let buffer_size = match supported.map(|r| r.buffer_size()) {
+            Some(SupportedBufferSize::Range { min, max }) => {
+                (settings.buffer_size as u32).clamp(*min, *max)
+            }
+            _ => settings.buffer_size as u32,
+        };
+        StreamConfig {
+            channels,
+            sample_rate: if supported.is_some() {
+                requested
+            } else {
+                default_rate
+            },
+            buffer_size: BufferSize::Fixed(buffer_size),
+        }
+    }
+
+    fn device_format(config: &StreamConfig) -> DeviceFormat {
+        DeviceFormat {
+            sample_rate: config.sample_rate.0 as f64,
+            channels: config.channels as usize,
+            buffer_size: buffer_frames(config),
+        }
+    }
+
+    fn describe(device: &Device, config: &StreamConfig) -> String {
+        format!("{:?}: {:#?}", device.name(), config)
+    }
+
+    /// cpal has no stable device ids, so devices are identified by name.
+    fn find_device(&self, name: Option<&str>, input: bool) -> Result<Device, String> {
+        let name = match name {
+            Some(name) => name,
+            None if input => {
+                return self
+                    .host
+                    .default_input_device()
+                    .ok_or_else(|| "no default input device".to_string())
+            }
+            None => {
+                return self
+                    .host
+                    .default_output_device()
+                    .ok_or_else(|| "no default output device".to_string())
+            }
+        };
+        let mut devices = if input {
+            self.host.input_devices()
+        } else {
+            self.host.output_devices()
+        }
+        .map_err(|e| e.to_string())?;
+        devices
+            .find(|d| d.name().map(|n| n == name).unwrap_or(false))
+            .ok_or_else(|| format!("no audio device named {}", name))
+    }
+}
+
+fn buffer_frames(config: &StreamConfig) -> usize {
+    match config.buffer_size {
+        BufferSize::Fixed(frames) => frames as usize,
+        BufferSize::Default => DEFAULT_BUFFER_SIZE,
+    }
+}
+
+fn max_channels(ranges: &[SupportedStreamConfigRange]) -> usize {
+    ranges
+        .iter()
+        .map(|r| r.channels() as usize)
+        .max()
+        .unwrap_or(0)
+}
+
+impl AudioBackend for CpalBackend {
+    fn name(&self) -> &'static str {
+        "cpal"
+    }
+
+    fn devices(&self) -> Result<Vec<DeviceInfo>, String> {
+        let devices = self.host.devices().map_err(|e| e.to_string())?;
+        Ok(devices
+            .filter_map(|device| {
+                let name = device.name().ok()?;
+                let input_ranges: Vec<SupportedStreamConfigRange> = device
+                    .supported_input_configs()
+                    .map(|configs| configs.collect())
+                    .unwrap_or_default();
+                let output_ranges: Vec<SupportedStreamConfigRange> = device
+                    .supported_output_configs()
+                    .map(|configs| configs.collect())
+                    .unwrap_or_default();
+                let sample_rates = COMMON_SAMPLE_RATES
+                    .iter()
+                    .copied()
+                    .filter(|rate| {
+                        input_ranges.iter().chain(output_ranges.iter()).any(|r| {
+                            r.min_sample_rate().0 as f64 <= *rate
+                                && *rate <= r.max_sample_rate().0 as f64
+                        })
+                    })
+                    .collect();
+                Some(DeviceInfo {
+                    id: name.clone(),
+                    name,
+                    input_channels: max_channels(&input_ranges),
+                    output_channels: max_channels(&output_ranges),
+                    sample_rates,
+                })
+            })
+            .collect())
+    }
+
+    fn open_input(
+        &mut self,
+        device: Option<&str>,
+        settings: &StreamSettings,
+    ) -> Result<DeviceFormat, String> {
+        let device = self.find_device(device, true)?;
+        let default = device.default_input_config().map_err(|e| e.to_string())?;
+        // open every channel the device has; Config::input_channels picks which are used
+        let channels = default.channels().clamp(1, MAX_CHANNELS as u16);
+        let ranges: Vec<SupportedStreamConfigRange> = device
+            .supported_input_configs()
+            .map(|configs| configs.collect())
+            .unwrap_or_default();
+        let config = CpalBackend::stream_config(&ranges, channels, settings, default.sample_rate());
+        println!("{}", CpalBackend::describe(&device, &config));
+        let format = CpalBackend::device_format(&config);
+        self.input = Some((device, config));
+        Ok(format)
+    }
+
+    fn open_output(
+        &mut self,
+        device: Option<&str>,
+        settings: &StreamSettings,
+    ) -> Result<DeviceFormat, String> {
+        let device = self.find_device(device, false)?;
+        let default = device.default_output_config().map_err(|e| e.to_string())?;
+        let ranges: Vec<SupportedStreamConfigRange> = device
+            .supported_output_configs()
+            .map(|configs| configs.collect())
+            .unwrap_or_default();
+        let config = CpalBackend::stream_config(&ranges, 2, settings, default.sample_rate());
+        println!("{}", CpalBackend::describe(&device, &config));
+        let format = CpalBackend::device_format(&config);
+        self.output = Some((device, config));
+        Ok(format)
+    }
+
+    fn start(&mut self, mut callback: ProcessCallback) -> Result<(), String> {
+        let input_channels = self
+            .input
+            .as_ref()
+            .map(|(_, config)| config.channels as usize)
+            .unwrap_or(0);
+        let sample_rate = self
+            .output
+            .as_ref()
+            .map(|(_, config)| config.sample_rate.0 as f64)
+            .ok_or("output has not been opened")?;
+        let Buffers {
+            mut producers,
+            mut consumers,
+        } = make_buffers(input_channels, sample_rate);
+
+        if let Some((device, config)) = self.input.as_ref() {
+            let buffer_size = buffer_frames(config);
+            let mut deinterleaved: Vec<Vec<S>> = vec![vec![0.0; buffer_size]; input_channels];
+            let stream = device
+                .build_input_stream(
+                    config,
+                    move |data: &[S], _: &cpal::InputCallbackInfo| {
+                        let num_frames = data.len() / input_channels;
+                        for (ch, channel) in deinterleaved.iter_mut().enumerate() {
+                            channel.resize(num_frames, 0.0);
+                            for (i, value) in channel.iter_mut().enumerate() {
+                                *value = data[i * input_channels + ch];
+                            }
+                        }
+                        let channels: ChannelSlices = deinterleaved
+                            .iter()
+                            .take(MAX_CHANNELS)
+                            .map(|c| c.as_slice())
+                            .collect();
+                        push_input(&mut producers, &channels);
+                    },
+                    |err| println!("input stream error: {}", err),
+                )
+                .map_err(|e| e.to_string())?;
+            stream.play().map_err(|e| e.to_string())?;
+            self.streams.push(stream);
+        }
+
+        let (device, config) = self.output.as_ref().ok_or("output has not been opened")?;
+        let output_channels = config.channels as usize;
+        let buffer_size = buffer_frames(config);
+        let mut input_scratch: Vec<Vec<S>> = vec![vec![0.0; buffer_size]; consumers.len()];
+        let mut output_scratch: Vec<Vec<S>> = vec![vec![0.0; buffer_size]; output_channels];
+        let stream = device
+            .build_output_stream(
+                config,
+                move |data: &mut [S], _: &cpal::OutputCallbackInfo| {
+                    let num_frames = data.len() / output_channels;
+                    pop_input(&mut consumers, &mut input_scratch, num_frames);
+                    for channel in output_scratch.iter_mut() {
+                        channel.resize(num_frames, 0.0);
+                    }
+                    {
+                        let input: ChannelSlices = input_scratch
+                            .iter()
+                            .take(MAX_CHANNELS)
+                            .map(|c| c.as_slice())
+                            .collect();
+                        let mut output: ChannelSlicesMut = output_scratch
+                            .iter_mut()
+                            .take(MAX_CHANNELS)
+                            .map(|c| c.as_mut_slice())
+                            .collect();
+                        callback(&input, &mut output);
+                    }
+                    for (ch, channel) in output_scratch.iter().enumerate() {
+                        for (i, value) in channel.iter().enumerate() {
+                            data[i * output_channels + ch] = *value;
+                        }
+                    }
+                },
+                |err| println!("output stream error: {}", err),
+            )
+            .map_err(|e| e.to_string())?;
+        stream.play().map_err(|e| e.to_string())?;
+        self.streams.push(stream);
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<(), String> {
+        let paused = self
+            .streams
+            .iter()
+            .try_for_each(|stream| stream.pause().map_err(|e| e.to_string()));
+        // dropped either way, which hands the engine back
+        self.streams.clear();
+        paused
+    }
+
+    fn device_formats(&self) -> Vec<String> {
+        self.input
+            .iter()
+            .chain(self.output.iter())
+            .map(|(device, config)| CpalBackend::describe(device, config))
+            .collect()
+    }
+}
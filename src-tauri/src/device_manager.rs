@@ -0,0 +1,425 @@
+//! Owns the audio backend on a thread of its own, so streams can be torn down
+//! and rebuilt on other devices while the app runs. Backends hold handles that
+//! are not `Send` (e.g. cpal streams), so commands talk to the thread through
+//! a channel instead of sharing the backend.
+//!
+//! The engine is moved into each audio callback and comes back to this thread
+//! when the backend drops the callback, so the audio thread never takes a
+//! lock. Rate changes are applied to it in between, while no stream runs.
+
+use crate::audio_backend::{
+    make_backend, AudioBackend, BackendKind, BackendOptions, DeviceFormat, DeviceInfo,
+    ProcessCallback, StreamSettings,
+};
+use crate::engine::Engine;
+use crate::types::S;
+use serde::Serialize;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How long a stopped backend may take to drop the callback holding the engine.
+const ENGINE_RETURN_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Clone, Debug, Serialize)]
+pub struct OpenedDevices {
+    pub backend: &'static str,
+    pub input: DeviceFormat,
+    pub output: DeviceFormat,
+}
+
+/// Input and output device ids, `None` meaning the default device.
+pub type DeviceIds = (Option<String>, Option<String>);
+
+/// The devices and settings the streams were last opened with.
+#[derive(Clone, Default)]
+struct Selection {
+    input: Option<String>,
+    output: Option<String>,
+    settings: StreamSettings,
+}
+
+enum DeviceRequest {
+    List(Sender<Result<Vec<DeviceInfo>, String>>),
+    Select {
+        input: Option<String>,
+        output: Option<String>,
+        reply: Sender<Result<OpenedDevices, String>>,
+    },
+    Configure {
+        settings: StreamSettings,
+        reply: Sender<Result<OpenedDevices, String>>,
+    },
+    Selected(Sender<Result<DeviceIds, String>>),
+    Opened(Sender<Result<OpenedDevices, String>>),
+    Stop(Sender<Result<(), String>>),
+}
+
+/// The engine, either here on the device thread or out in a running callback.
+struct EngineSlot {
+    engine: Option<Engine>,
+    returned: Sender<Engine>,
+    returns: Receiver<Engine>,
+}
+
+/// Owns the engine inside an audio callback and sends it back to the device
+/// thread when the callback is dropped.
+struct EngineHandoff {
+    engine: Option<Engine>,
+    returned: Sender<Engine>,
+}
+
+impl Drop for EngineHandoff {
+    fn drop(&mut self) {
+        if let Some(engine) = self.engine.take() {
+            let _ = self.returned.send(engine);
+        }
+    }
+}
+
+impl EngineSlot {
+    fn new(engine: Engine) -> EngineSlot {
+        let (returned, returns) = channel();
+        EngineSlot {
+            engine: Some(engine),
+            returned,
+            returns,
+        }
+    }
+
+    /// Brings the engine to `sample_rate` and moves it into a callback.
+    fn callback(&mut self, sample_rate: f64) -> Result<ProcessCallback, String> {
+        let mut engine = self
+            .engine
+            .take()
+            .ok_or_else(|| "the audio engine is still running".to_string())?;
+        engine.set_sample_rate(sample_rate);
+        let mut handoff = EngineHandoff {
+            engine: Some(engine),
+            returned: self.returned.clone(),
+        };
+        Ok(Box::new(move |input: &[&[S]], output: &mut [&mut [S]]| {
+            if let Some(engine) = handoff.engine.as_mut() {
+                engine.process(input, output);
+            }
+        }))
+    }
+
+    /// Waits for the engine to come back once the backend has dropped its callback.
+    fn reclaim(&mut self) -> Result<(), String> {
+        if self.engine.is_none() {
+            let engine = self
+                .returns
+                .recv_timeout(ENGINE_RETURN_TIMEOUT)
+                .map_err(|_| "the audio backend did not release the engine".to_string())?;
+            self.engine = Some(engine);
+        }
+        Ok(())
+    }
+}
+
+/// Handle to the device thread. Cloning it gives another handle to the same thread.
+#[derive(Clone)]
+pub struct DeviceManager {
+    requests: Sender<DeviceRequest>,
+}
+
+impl DeviceManager {
+    /// Opens the default devices of `kind` (falling back to the file backend) with
+    /// `settings` and starts `engine` on them. `log` is replaced with the backend's
+    /// description of the streams whenever they are opened.
+    pub fn start(
+        kind: BackendKind,
+        options: BackendOptions,
+        settings: StreamSettings,
+        engine: Engine,
+        log: Arc<Mutex<Vec<String>>>,
+    ) -> Result<(DeviceManager, OpenedDevices), String> {
+        let (requests, receiver) = channel();
+        let (ready_sender, ready) = channel();
+        std::thread::spawn(move || {
+            let mut engine = EngineSlot::new(engine);
+            let selection = Selection {
+                settings,
+                ..Selection::default()
+            };
+            match open_backend(kind, &options, &mut engine, &selection, &log) {
+                Ok((backend, opened)) => {
+                    let _ = ready_sender.send(Ok(opened.clone()));
+                    run(backend, engine, selection, opened, receiver, log);
+                }
+                Err(err) => {
+                    let _ = ready_sender.send(Err(err));
+                }
+            }
+        });
+        let opened = ready
+            .recv()
+            .map_err(|_| "audio device thread exited".to_string())??;
+        Ok((DeviceManager { requests }, opened))
+    }
+
+    pub fn list_devices(&self) -> Result<Vec<DeviceInfo>, String> {
+        let (reply, response) = channel();
+        self.request(DeviceRequest::List(reply), response)
+    }
+
+    /// Switches to the given devices, `None` meaning the default. On failure the
+    /// previous devices are reopened.
+    pub fn select_devices(
+        &self,
+        input: Option<String>,
+        output: Option<String>,
+    ) -> Result<OpenedDevices, String> {
+        let (reply, response) = channel();
+        self.request(
+            DeviceRequest::Select {
+                input,
+                output,
+                reply,
+            },
+            response,
+        )
+    }
+
+    /// Reopens the current devices asking for another sample rate and buffer size.
+    /// The returned formats say what the devices settled on.
+    pub fn configure_streams(&self, settings: StreamSettings) -> Result<OpenedDevices, String> {
+        let (reply, response) = channel();
+        self.request(DeviceRequest::Configure { settings, reply }, response)
+    }
+
+    /// The input and output device ids the streams run on, `None` meaning the default.
+    pub fn selected_devices(&self) -> Result<DeviceIds, String> {
+        let (reply, response) = channel();
+        self.request(DeviceRequest::Selected(reply), response)
+    }
+
+    /// The formats the running streams were opened with.
+    pub fn opened_devices(&self) -> Result<OpenedDevices, String> {
+        let (reply, response) = channel();
+        self.request(DeviceRequest::Opened(reply), response)
+    }
+
+    pub fn stop(&self) -> Result<(), String> {
+        let (reply, response) = channel();
+        self.request(DeviceRequest::Stop(reply), response)
+    }
+
+    fn request<T>(
+        &self,
+        request: DeviceRequest,
+        response: Receiver<Result<T, String>>,
+    ) -> Result<T, String> {
+        self.requests
+            .send(request)
+            .map_err(|_| "audio device thread exited".to_string())?;
+        response
+            .recv()
+            .map_err(|_| "audio device thread exited".to_string())?
+    }
+}
+
+fn run(
+    mut backend: Box<dyn AudioBackend>,
+    mut engine: EngineSlot,
+    mut current: Selection,
+    mut opened: OpenedDevices,
+    requests: Receiver<DeviceRequest>,
+    log: Arc<Mutex<Vec<String>>>,
+) {
+    for request in requests {
+        match request {
+            DeviceRequest::List(reply) => {
+                let _ = reply.send(backend.devices());
+            }
+            DeviceRequest::Select {
+                input,
+                output,
+                reply,
+            } => {
+                let next = Selection {
+                    input,
+                    output,
+                    ..current.clone()
+                };
+                let _ = reply.send(switch(
+                    backend.as_mut(),
+                    &mut engine,
+                    &mut current,
+                    &mut opened,
+                    next,
+                    &log,
+                ));
+            }
+            DeviceRequest::Configure { settings, reply } => {
+                let next = Selection {
+                    settings,
+                    ..current.clone()
+                };
+                let _ = reply.send(switch(
+                    backend.as_mut(),
+                    &mut engine,
+                    &mut current,
+                    &mut opened,
+                    next,
+                    &log,
+                ));
+            }
+            DeviceRequest::Selected(reply) => {
+                let _ = reply.send(Ok((current.input.clone(), current.output.clone())));
+            }
+            DeviceRequest::Opened(reply) => {
+                let _ = reply.send(Ok(opened.clone()));
+            }
+            DeviceRequest::Stop(reply) => {
+                let _ = reply.send(backend.stop());
+                return;
+            }
+        }
+    }
+    let _ = backend.stop();
+}
+
+/// Restarts the streams with `next`, going back to `current` if that fails.
+/// `opened` follows whatever ends up running.
+fn switch(
+    backend: &mut dyn AudioBackend,
+    engine: &mut EngineSlot,
+    current: &mut Selection,
+    opened: &mut OpenedDevices,
+    next: Selection,
+    log: &Arc<Mutex<Vec<String>>>,
+) -> Result<OpenedDevices, String> {
+    backend.stop()?;
+    engine.reclaim()?;
+    match start_streams(backend, engine, &next, log) {
+        Ok(next_opened) => {
+            *current = next;
+            *opened = next_opened.clone();
+            Ok(next_opened)
+        }
+        Err(err) => {
+            println!("could not switch audio devices: {}", err);
+            backend.stop()?;
+            engine.reclaim()?;
+            *opened = start_streams(backend, engine, current, log)?;
+            Err(err)
+        }
+    }
+}
+
+/// Opens output first, as its rate is what the engine runs at, then input at the same rate.
+fn start_streams(
+    backend: &mut dyn AudioBackend,
+    engine: &mut EngineSlot,
+    selection: &Selection,
+    log: &Arc<Mutex<Vec<String>>>,
+) -> Result<OpenedDevices, String> {
+    let output = backend.open_output(selection.output.as_deref(), &selection.settings)?;
+    let settings = StreamSettings {
+        sample_rate: output.sample_rate,
+        buffer_size: output.buffer_size,
+    };
+    let input = backend.open_input(selection.input.as_deref(), &settings)?;
+    if input.sample_rate != output.sample_rate {
+        return Err(format!(
+            "input runs at {} Hz but output at {} Hz",
+            input.sample_rate, output.sample_rate
+        ));
+    }
+    backend.start(engine.callback(output.sample_rate)?)?;
+    if let Ok(mut log) = log.lock() {
+        *log = backend.device_formats();
+    }
+    println!("audio backend: {}", backend.name());
+    Ok(OpenedDevices {
+        backend: backend.name(),
+        input,
+        output,
+    })
+}
+
+/// Creates the requested backend and starts it on the selected devices, falling
+/// back to the file backend (silent input) when it is not available.
+fn open_backend(
+    kind: BackendKind,
+    options: &BackendOptions,
+    engine: &mut EngineSlot,
+    selection: &Selection,
+    log: &Arc<Mutex<Vec<String>>>,
+) -> Result<(Box<dyn AudioBackend>, OpenedDevices), String> {
+    let opened = make_backend(kind, options).and_then(|mut backend| {
+        let opened = start_streams(backend.as_mut(), engine, selection, log)?;
+        Ok((backend, opened))
+    });
+    match opened {
+        Err(err) if kind != BackendKind::File => {
+            println!(
+                "{:?} backend unavailable ({}), using file backend",
+                kind, err
+            );
+            // dropping the failed backend has handed the engine back
+            engine.reclaim()?;
+            open_backend(BackendKind::File, options, engine, selection, log)
+        }
+        opened => opened,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::default_config;
+    use crate::kit::SampleKit;
+    use crate::latency::measure;
+    use std::sync::atomic::Ordering;
+
+    #[test]
+    fn measures_the_loopback_delay() {
+        let settings = StreamSettings {
+            sample_rate: 16000.0,
+            buffer_size: 256,
+        };
+        let options = BackendOptions {
+            loopback_delay: 1500,
+            ..BackendOptions::default()
+        };
+        let (engine, mut controls) = Engine::new(default_config(), SampleKit::new(), 16000.0);
+        let log = Arc::new(Mutex::new(vec![]));
+        let (devices, opened) =
+            DeviceManager::start(BackendKind::Loopback, options, settings, engine, log).unwrap();
+        assert_eq!(opened.backend, "loopback");
+        let measurement = measure(&mut controls.latency_probes, settings.sample_rate);
+        devices.stop().unwrap();
+        let measurement = measurement.unwrap();
+        assert_eq!(measurement.frames, 1500);
+        assert!(measurement.confidence > 0.9);
+    }
+
+    #[test]
+    fn keeps_the_engine_across_a_rate_change() {
+        let settings = StreamSettings {
+            sample_rate: 16000.0,
+            buffer_size: 256,
+        };
+        let options = BackendOptions {
+            loopback_delay: 1000,
+            ..BackendOptions::default()
+        };
+        let (engine, mut controls) = Engine::new(default_config(), SampleKit::new(), 16000.0);
+        let log = Arc::new(Mutex::new(vec![]));
+        let (devices, _) =
+            DeviceManager::start(BackendKind::Loopback, options, settings, engine, log).unwrap();
+        let opened = devices.configure_streams(StreamSettings {
+            sample_rate: 8000.0,
+            buffer_size: 128,
+        });
+        // the engine came back from the first stream and runs the second at the new rate
+        let measurement = measure(&mut controls.latency_probes, 8000.0);
+        devices.stop().unwrap();
+        assert_eq!(opened.unwrap().output.sample_rate, 8000.0);
+        assert_eq!(controls.sample_rate.load(Ordering::Relaxed), 8000.0);
+        assert_eq!(measurement.unwrap().frames, 1000);
+    }
+}
@@ -0,0 +1,948 @@
+use crate::click::{Accent, ClickTimbre, ClickVoice};
+use crate::get_loop_buffer_size::get_loop_buffer_size;
+use crate::kit::{sound_name, SampleKit, DEFAULT_SOUND};
+use crate::latency::LatencyProbe;
+use crate::loop_file::{first_frame, LoopSnapshot};
+use crate::looper::{looper, Looper, LooperControls};
+use crate::onset::{Hit, InputBeat, OnsetDetector, ONSET_HOP};
+use crate::read_audio_file::AudioBuffer;
+use crate::realtime::{swap_channel, value_channel, SwapReceiver, SwapSender, ValueSender};
+use crate::recording::RecordingSink;
+use crate::resample::resample;
+use crate::structs::{Config, InputChannel, LoopBuffer, Mp3Buffer, RhythmLayer};
+use crate::types::S;
+use crate::util::{beat_bisect, mod_add};
+use crate::voice_pool::VoicePool;
+use atomic_float::AtomicF64;
+use rand::{rngs::StdRng, SeedableRng};
+use rtrb::{Consumer, Producer, RingBuffer};
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    sync::Arc,
+};
+use triple_buffer::{Input, Output, TripleBuffer};
+
+/// Room for two seconds of stereo `(beat, amplitude)` pairs at up to 96 kHz
+/// between `get_samples` polls.
+const VISUAL_BUFFER_SIZE: usize = 96000 * 2 * 2;
+/// Hits waiting to be forwarded to the frontend.
+const HITS_BUFFER_SIZE: usize = 1024;
+/// Layers past this many are not played, so the audio thread's per layer
+/// state is allocated once.
+pub const MAX_LAYERS: usize = 16;
+
+/// An `InputChannel` with its gain and pan folded into one gain per side.
+#[derive(Clone)]
+pub struct InputRoute {
+    pub channel: usize,
+    pub gains: [f32; 2],
+    pub to_loop: bool,
+    pub to_monitor: bool,
+    pub to_visualizer: bool,
+}
+
+/// Left and right gains for `gain` panned by `pan`. At 0.0 both sides get all of it.
+fn pan_gains(gain: f32, pan: f32) -> [f32; 2] {
+    let pan = pan.clamp(-1.0, 1.0);
+    [gain * (1.0 - pan).min(1.0), gain * (1.0 + pan).min(1.0)]
+}
+
+impl InputRoute {
+    fn new(input: &InputChannel, audio_in_gain: f32) -> InputRoute {
+        InputRoute {
+            channel: input.channel,
+            gains: pan_gains(input.gain * audio_in_gain, input.pan),
+            to_loop: input.to_loop,
+            to_monitor: input.to_monitor,
+            to_visualizer: input.to_visualizer,
+        }
+    }
+}
+
+/// `Config` plus the tables the audio thread derives from it, built when the
+/// config is published so the render path never allocates.
+#[derive(Clone)]
+pub struct EngineConfig {
+    pub config: Config,
+    /// At most `MAX_LAYERS`.
+    pub layers: Vec<EngineLayer>,
+    pub input_routes: Vec<InputRoute>,
+}
+
+impl EngineConfig {
+    pub fn new(config: Config) -> EngineConfig {
+        if config.layers.len() > MAX_LAYERS {
+            println!(
+                "only the first {} of {} layers are played",
+                MAX_LAYERS,
+                config.layers.len()
+            );
+        }
+        let layers = config
+            .layers
+            .iter()
+            .take(MAX_LAYERS)
+            .map(EngineLayer::new)
+            .collect();
+        let input_routes = config
+            .input_channels
+            .iter()
+            .map(|input| InputRoute::new(input, config.audio_in_gain))
+            .collect();
+        EngineConfig {
+            config,
+            layers,
+            input_routes,
+        }
+    }
+}
+
+/// A `RhythmLayer` as the audio thread plays it.
+#[derive(Clone)]
+pub struct EngineLayer {
+    /// Note times followed by the rhythm's end, as `beat_bisect` expects.
+    pub times: Vec<f64>,
+    pub notes: Vec<NoteTrigger>,
+    pub mute: bool,
+    /// For a rhythm without notes, which plays on every beat.
+    pub every_beat: NoteTrigger,
+}
+
+impl EngineLayer {
+    fn new(layer: &RhythmLayer) -> EngineLayer {
+        let rhythm = &layer.rhythm;
+        let mut times: Vec<f64> = rhythm.notes.iter().map(|n| n.time).collect();
+        times.push(rhythm.end);
+        let layer_sound = layer.sound.as_deref().map(sound_name);
+        let notes = rhythm
+            .notes
+            .iter()
+            .map(|n| {
+                let sounds = match (n.sounds.is_empty(), layer_sound) {
+                    (true, Some(sound)) => vec![sound.to_string()],
+                    _ => n.sounds.iter().map(|s| sound_name(s).to_string()).collect(),
+                };
+                NoteTrigger {
+                    sounds,
+                    rest: n.rest,
+                    gains: pan_gains(n.gain() * layer.gain, layer.pan),
+                    accented: n.accent.is_some(),
+                }
+            })
+            .collect();
+        EngineLayer {
+            times,
+            notes,
+            mute: layer.mute,
+            every_beat: NoteTrigger {
+                sounds: layer_sound.map(|s| vec![s.to_string()]).unwrap_or_default(),
+                rest: false,
+                gains: pan_gains(layer.gain, layer.pan),
+                accented: false,
+            },
+        }
+    }
+
+    fn note(&self, index: isize) -> &NoteTrigger {
+        match self.notes.len() {
+            0 => &self.every_beat,
+            n => &self.notes[index.rem_euclid(n as isize) as usize],
+        }
+    }
+
+    /// Where the note with `index` falls, in beats from the start of the transport.
+    fn beat(&self, index: isize) -> f64 {
+        match self.notes.len() {
+            0 => index as f64,
+            n => {
+                let n = n as isize;
+                let end = self.times[self.times.len() - 1];
+                index.div_euclid(n) as f64 * end + self.times[index.rem_euclid(n) as usize]
+            }
+        }
+    }
+}
+
+/// A note as the audio thread plays it, with its kit names looked up and its
+/// layer's gain and pan applied.
+#[derive(Clone)]
+pub struct NoteTrigger {
+    pub sounds: Vec<String>,
+    pub rest: bool,
+    /// Left and right.
+    pub gains: [f32; 2],
+    /// Clicks like a downbeat wherever it falls.
+    pub accented: bool,
+}
+
+/// Where the audio thread is in one layer.
+#[derive(Clone, Copy)]
+struct LayerPlayback {
+    last_note: isize,
+    click: ClickVoice,
+}
+
+impl LayerPlayback {
+    fn at(note: isize) -> LayerPlayback {
+        LayerPlayback {
+            last_note: note,
+            click: ClickVoice::silent(),
+        }
+    }
+}
+
+/// The command side of the engine. Nothing in here is shared with the audio
+/// thread through a lock.
+pub struct EngineControls {
+    pub config: ValueSender<EngineConfig>,
+    pub loop_buffer: SwapSender<LoopBuffer>,
+    /// Snapshots are sent back through `take_retired` once filled.
+    pub loop_snapshots: SwapSender<LoopSnapshot>,
+    pub mp3: SwapSender<Mp3Buffer>,
+    pub should_reset_beat: Arc<AtomicBool>,
+    pub visual_samples: Consumer<(f32, f32)>,
+    pub hits: Consumer<Hit>,
+    /// Beat of the latest input the onset detector has seen, compensated like
+    /// `Hit::beat`. Every hit before it has been pushed to `hits`.
+    pub input_beat: Output<InputBeat>,
+    /// Follows `Engine::set_sample_rate`, for sizing buffers built off the audio thread.
+    pub sample_rate: Arc<AtomicF64>,
+    /// Probes are sent back through `take_retired` once recorded.
+    pub latency_probes: SwapSender<LatencyProbe>,
+    pub recordings: SwapSender<RecordingSink>,
+    pub looper: LooperControls,
+    /// Replaces the drum samples, see `KitLoader`.
+    pub kit: SwapSender<SampleKit>,
+}
+
+/// The mixer behind every backend: monitoring, loop buffer, backing file, drum
+/// samples, click, the visual sample stream, hit detection and recording.
+pub struct Engine {
+    config: Output<EngineConfig>,
+    loop_buffer: LoopBuffer,
+    loop_buffer_updates: SwapReceiver<LoopBuffer>,
+    loop_snapshots: SwapReceiver<LoopSnapshot>,
+    looper: Looper,
+    mp3: Mp3Buffer,
+    mp3_updates: SwapReceiver<Mp3Buffer>,
+    latency_probe: Option<LatencyProbe>,
+    latency_probe_updates: SwapReceiver<LatencyProbe>,
+    recording: Option<RecordingSink>,
+    recording_updates: SwapReceiver<RecordingSink>,
+    should_reset_beat: Arc<AtomicBool>,
+    visual_samples: Producer<(f32, f32)>,
+    onsets: OnsetDetector,
+    hits: Producer<Hit>,
+    input_beat: Input<InputBeat>,
+    /// Beat resets so far.
+    generation: usize,
+    sample_buffers: SampleKit,
+    kit_updates: SwapReceiver<SampleKit>,
+    voices: VoicePool,
+    /// Clicks of the sample timbre.
+    click_samples: VoicePool,
+    sample_rate: f64,
+    shared_sample_rate: Arc<AtomicF64>,
+    beat: f64,
+    /// One per layer of the config being played.
+    layers: Vec<LayerPlayback>,
+    rng: StdRng,
+}
+
+impl Engine {
+    pub fn new(
+        config: Config,
+        sample_buffers: SampleKit,
+        sample_rate: f64,
+    ) -> (Engine, EngineControls) {
+        let loop_buffer_size = get_loop_buffer_size(&config, sample_rate);
+        let shared_sample_rate = Arc::new(AtomicF64::new(sample_rate));
+        let engine_config = EngineConfig::new(config);
+        let mut voices = VoicePool::new();
+        let mut click_samples = VoicePool::new();
+        let mut layers = Vec::with_capacity(MAX_LAYERS);
+        for layer in &engine_config.layers {
+            let mut playback = LayerPlayback::at(0);
+            // the first beat is never crossed, so its notes sound from the start
+            if !layer.mute && !layer.note(0).rest {
+                trigger_note(&mut voices, &sample_buffers, layer.note(0));
+                trigger_click(
+                    &mut playback.click,
+                    &mut click_samples,
+                    &sample_buffers,
+                    &engine_config.config,
+                    layer,
+                    0,
+                    sample_rate,
+                );
+            }
+            layers.push(playback);
+        }
+        let (config_sender, config_output) = value_channel(engine_config);
+        let (loop_buffer_sender, loop_buffer_updates) = swap_channel();
+        let (loop_snapshot_sender, loop_snapshots) = swap_channel();
+        let (looper, looper_controls) = looper();
+        let (mp3_sender, mp3_updates) = swap_channel();
+        let (latency_probe_sender, latency_probe_updates) = swap_channel();
+        let (recording_sender, recording_updates) = swap_channel();
+        let (kit_sender, kit_updates) = swap_channel();
+        let (visual_producer, visual_consumer) = RingBuffer::new(VISUAL_BUFFER_SIZE);
+        let (hit_producer, hit_consumer) = RingBuffer::new(HITS_BUFFER_SIZE);
+        let (input_beat_input, input_beat_output) =
+            TripleBuffer::new(&InputBeat::default()).split();
+        let should_reset_beat = Arc::new(AtomicBool::new(false));
+
+        let engine = Engine {
+            config: config_output,
+            loop_buffer: LoopBuffer::new(loop_buffer_size),
+            loop_buffer_updates,
+            loop_snapshots,
+            looper,
+            mp3: Mp3Buffer::new(vec![], sample_rate),
+            mp3_updates,
+            latency_probe: None,
+            latency_probe_updates,
+            recording: None,
+            recording_updates,
+            should_reset_beat: should_reset_beat.clone(),
+            visual_samples: visual_producer,
+            onsets: OnsetDetector::new(sample_rate),
+            hits: hit_producer,
+            input_beat: input_beat_input,
+            generation: 0,
+            sample_buffers,
+            kit_updates,
+            voices,
+            click_samples,
+            sample_rate,
+            shared_sample_rate: shared_sample_rate.clone(),
+            beat: 0.0,
+            layers,
+            // the noise click only needs to sound random, a fixed seed keeps renders reproducible
+            rng: StdRng::seed_from_u64(0),
+        };
+        let controls = EngineControls {
+            config: config_sender,
+            loop_buffer: loop_buffer_sender,
+            loop_snapshots: loop_snapshot_sender,
+            mp3: mp3_sender,
+            should_reset_beat,
+            visual_samples: visual_consumer,
+            hits: hit_consumer,
+            input_beat: input_beat_output,
+            sample_rate: shared_sample_rate,
+            latency_probes: latency_probe_sender,
+            recordings: recording_sender,
+            looper: looper_controls,
+            kit: kit_sender,
+        };
+        (engine, controls)
+    }
+
+    pub fn beat(&self) -> f64 {
+        self.beat
+    }
+
+    pub fn sample_rate(&self) -> f64 {
+        self.sample_rate
+    }
+
+    /// Switches to a new device rate, keeping the beat position. Resamples the
+    /// loop and the drum samples, so call it only while no stream is running.
+    pub fn set_sample_rate(&mut self, sample_rate: f64) {
+        if sample_rate == self.sample_rate {
+            return;
+        }
+        // they play the samples being replaced, which are freed here with them
+        self.voices.clear();
+        self.click_samples.clear();
+        for (name, samples) in self.sample_buffers.iter_mut() {
+            let buffer = AudioBuffer {
+                samples: samples.to_vec(),
+                sample_rate: self.sample_rate,
+                channels: 2,
+            };
+            match resample(&buffer, sample_rate) {
+                Ok(resampled) => *samples = Arc::new(resampled.samples),
+                Err(e) => println!("could not resample {}: {}", name, e),
+            }
+        }
+        let size = get_loop_buffer_size(&self.config.read().config, sample_rate);
+        self.loop_buffer = resample_loop(&self.loop_buffer, self.sample_rate, sample_rate, size);
+        self.looper.buffer_replaced(&self.loop_buffer);
+        self.sample_rate = sample_rate;
+        self.onsets = OnsetDetector::new(sample_rate);
+        self.shared_sample_rate
+            .store(sample_rate, Ordering::Relaxed);
+    }
+
+    /// Takes any buffers the commands have sent since the last callback.
+    fn receive_updates(&mut self) {
+        let config = &self.config.read().config;
+        let loop_buffer_size = get_loop_buffer_size(config, self.sample_rate);
+        if let Some(mut loop_buffer) = self.loop_buffer_updates.receive() {
+            if loop_buffer.buffer.len() == loop_buffer_size {
+                if loop_buffer.reference {
+                    // line the reference up so its first frame sounds on a bar line
+                    let frames = loop_buffer_size / 2;
+                    let first = first_frame(config, self.sample_rate, 0, self.beat, frames);
+                    loop_buffer.pos = (frames - first) % frames.max(1) * 2;
+                } else {
+                    // keep what has been played so far, as resizing in place used to
+                    let n = loop_buffer.buffer.len().min(self.loop_buffer.buffer.len());
+                    loop_buffer.buffer[..n].copy_from_slice(&self.loop_buffer.buffer[..n]);
+                    loop_buffer.pos = self.loop_buffer.pos % loop_buffer_size.max(1);
+                }
+                if loop_buffer.reference {
+                    self.looper.reference_loaded(&loop_buffer);
+                } else {
+                    self.looper.buffer_replaced(&loop_buffer);
+                }
+                let old = std::mem::replace(&mut self.loop_buffer, loop_buffer);
+                self.loop_buffer_updates.retire(old);
+            } else {
+                // sized for another rate or an older config; a fitting one follows
+                self.loop_buffer_updates.retire(loop_buffer);
+            }
+        }
+        self.looper.receive(&mut self.loop_buffer);
+        if let Some(mut snapshot) = self.loop_snapshots.receive() {
+            // a loop resized since the snapshot was sized is not taken
+            snapshot.taken = snapshot.buffer.len() == self.loop_buffer.buffer.len();
+            if snapshot.taken {
+                snapshot.buffer.copy_from_slice(&self.loop_buffer.buffer);
+                snapshot.pos = self.loop_buffer.pos;
+                snapshot.beat = self.beat;
+            }
+            self.loop_snapshots.retire(snapshot);
+        }
+        if let Some(mp3) = self.mp3_updates.receive() {
+            let old = std::mem::replace(&mut self.mp3, mp3);
+            self.mp3_updates.retire(old);
+        }
+        self.mp3.receive_seek();
+        if let Some(samples) = self.kit_updates.receive() {
+            // voices keep playing the old samples; the sender frees them, see `RetiredKits`
+            let old = std::mem::replace(&mut self.sample_buffers, samples);
+            self.kit_updates.retire(old);
+        }
+        if self.latency_probe.is_none() {
+            self.latency_probe = self.latency_probe_updates.receive();
+        }
+        if self.recording.is_none() {
+            self.recording = self.recording_updates.receive();
+        }
+        if let Some(recording) = self.recording.take() {
+            if recording.stop_requested() {
+                recording.finish();
+                self.recording_updates.retire(recording);
+            } else {
+                self.recording = Some(recording);
+            }
+        }
+    }
+
+    /// Renders one buffer. `input` and `output` are non-interleaved channels of
+    /// equal length. Input channels are mixed to a left/right pair per
+    /// destination by `Config::input_channels`; routes to channels the device
+    /// does not have are silent. Even output channels get the left side, odd
+    /// ones the right. Never allocates, locks or frees.
+    pub fn process(&mut self, input: &[&[S]], output: &mut [&mut [S]]) {
+        self.receive_updates();
+        let num_frames = output.first().map(|c| c.len()).unwrap_or(0);
+        let EngineConfig {
+            config,
+            layers,
+            input_routes,
+        } = self.config.read();
+        let loop_buffer = &mut self.loop_buffer;
+        let beats_per_sample: f64 = config.bpm / self.sample_rate / 60f64;
+        let mp3 = &mut self.mp3;
+        let file_frames_per_sample = mp3.sample_rate / self.sample_rate;
+        let hit_delay = (ONSET_HOP + config.buffer_compensation) as f64 * beats_per_sample;
+
+        if self.should_reset_beat.load(Ordering::Relaxed) {
+            self.beat = 0.0;
+            self.generation += 1;
+            mp3.rewind();
+            self.should_reset_beat.store(false, Ordering::Relaxed);
+        }
+        if self.layers.len() != layers.len() {
+            // layers added since start on their next note; within the
+            // capacity reserved in `new`, so this does not allocate
+            let kept = self.layers.len().min(layers.len());
+            self.layers.truncate(kept);
+            for layer in &layers[kept..] {
+                let note = beat_bisect(&layer.times, self.beat);
+                self.layers.push(LayerPlayback::at(note));
+            }
+        }
+
+        for i in 0..num_frames {
+            let mut loop_in: [S; 2] = [0.0; 2];
+            let mut monitor_in: [S; 2] = [0.0; 2];
+            let mut visual_in: [S; 2] = [0.0; 2];
+            let mut click_out: S = 0.0;
+            for route in input_routes.iter() {
+                let value = match input.get(route.channel) {
+                    Some(channel) => channel[i],
+                    None => continue,
+                };
+                for (side, gain) in route.gains.iter().enumerate() {
+                    let sample = value * gain;
+                    if route.to_loop {
+                        loop_in[side] += sample;
+                    }
+                    if route.to_monitor {
+                        monitor_in[side] += sample;
+                    }
+                    if route.to_visualizer {
+                        visual_in[side] += sample;
+                    }
+                }
+            }
+            // hits are detected on what the visualizer shows
+            if let Some(strength) = self.onsets.process(visual_in[0] + visual_in[1]) {
+                // a full ring means nobody is forwarding hits; drop the hit
+                let _ = self.hits.push(Hit {
+                    beat: self.beat - hit_delay,
+                    strength,
+                    generation: self.generation,
+                });
+            }
+            // before any channel reads the voices, so new ones start on the left
+            for (layer, playback) in layers.iter().zip(self.layers.iter_mut()) {
+                let note_index = beat_bisect(&layer.times, self.beat);
+                if note_index != playback.last_note {
+                    let note = layer.note(note_index);
+                    if !layer.mute && !note.rest {
+                        trigger_note(&mut self.voices, &self.sample_buffers, note);
+                        trigger_click(
+                            &mut playback.click,
+                            &mut self.click_samples,
+                            &self.sample_buffers,
+                            config,
+                            layer,
+                            note_index,
+                            self.sample_rate,
+                        );
+                    }
+                    playback.last_note = note_index;
+                }
+            }
+            let mut click = [0.0; 2];
+            for playback in self.layers.iter_mut() {
+                let frame = playback.click.next_frame(&mut self.rng);
+                click[0] += frame[0];
+                click[1] += frame[1];
+            }
+            let in_loop = self.beat % (config.beats_to_loop * 2.0) < config.beats_to_loop;
+            let click_audible = config.click_on && (!config.click_toggle || in_loop);
+            // the voices, kit clicks and loop are interleaved stereo: one frame
+            // is taken per output frame, whatever the number of channels
+            let drum = [self.voices.next_sample(), self.voices.next_sample()];
+            let kit_click = [
+                self.click_samples.next_sample(),
+                self.click_samples.next_sample(),
+            ];
+            let visual_beat =
+                (self.beat - (config.buffer_compensation as f64) * beats_per_sample) as f32;
+            let mut frame_out: [S; 2] = [0.0; 2];
+            for (side, out) in frame_out.iter_mut().enumerate() {
+                let sample: S = loop_in[side];
+                let mut audio_out = 0.0;
+                let mut visual_out = 0.0;
+                if config.audio_monitor_on {
+                    audio_out += monitor_in[side];
+                }
+                if config.visual_monitor_on {
+                    visual_out += visual_in[side];
+                }
+
+                let p = loop_buffer.pos;
+
+                let compensated_loop_buffer_pos = mod_add(
+                    loop_buffer.pos,
+                    config.buffer_compensation * 2,
+                    loop_buffer.buffer.len(),
+                );
+
+                if config.looping_on {
+                    audio_out += loop_buffer.buffer[compensated_loop_buffer_pos];
+                    visual_out += loop_buffer.buffer[loop_buffer.pos];
+                    self.looper.write(loop_buffer, p, sample);
+                }
+
+                *out = audio_out * 12.0;
+
+                if config.play_file {
+                    *out += mp3.sample(side);
+                }
+
+                loop_buffer.pos += 1;
+                if loop_buffer.pos >= loop_buffer.buffer.len() {
+                    loop_buffer.pos = 0;
+                }
+
+                if config.drum_on {
+                    *out += drum[side];
+                }
+
+                // a full ring means nobody is polling get_samples; drop the sample
+                let _ = self.visual_samples.push((visual_beat, visual_out.abs()));
+
+                let click_sample = click[side] + kit_click[side];
+                if click_audible {
+                    *out += click_sample;
+                    if side == 0 {
+                        click_out = click_sample;
+                    }
+                }
+            }
+            for (ch, channel) in output.iter_mut().enumerate() {
+                channel[i] = frame_out[ch % 2];
+            }
+            if let Some(recording) = self.recording.as_mut() {
+                let input_beat = self.beat - config.buffer_compensation as f64 * beats_per_sample;
+                recording.push(input, i, frame_out, click_out, input_beat);
+            }
+            mp3.advance(file_frames_per_sample);
+            self.beat += beats_per_sample;
+        }
+        self.input_beat.write(InputBeat {
+            beat: self.beat - hit_delay,
+            generation: self.generation,
+        });
+
+        // a latency measurement takes over the output until it has recorded enough
+        if let Some(probe) = self.latency_probe.as_mut() {
+            probe.process(input, output);
+            if probe.is_done() {
+                if let Some(probe) = self.latency_probe.take() {
+                    self.latency_probe_updates.retire(probe);
+                }
+            }
+        }
+    }
+}
+
+/// `loop_buffer` recorded at `from` brought to `to` and `size` samples, playing
+/// on from the same point. Silent if it cannot be resampled.
+fn resample_loop(loop_buffer: &LoopBuffer, from: f64, to: f64, size: usize) -> LoopBuffer {
+    let mut resampled = LoopBuffer::new(size);
+    let buffer = AudioBuffer {
+        samples: loop_buffer.buffer.clone(),
+        sample_rate: from,
+        channels: 2,
+    };
+    match resample(&buffer, to) {
+        Ok(buffer) => {
+            let n = buffer.samples.len().min(size);
+            resampled.buffer[..n].copy_from_slice(&buffer.samples[..n]);
+        }
+        Err(e) => println!("could not resample the loop: {}", e),
+    }
+    let frame = ((loop_buffer.pos / 2) as f64 * to / from).round() as usize;
+    resampled.pos = (frame * 2) % size.max(1);
+    resampled
+}
+
+/// Starts the kit's samples for the note's sounds, or the default sound if it
+/// has none of them. Rests start nothing.
+fn trigger_note(voices: &mut VoicePool, samples: &SampleKit, note: &NoteTrigger) {
+    if note.rest {
+        return;
+    }
+    let mut triggered = false;
+    for name in &note.sounds {
+        if let Some(sample) = samples.get(name) {
+            voices.trigger(sample, note.gains);
+            triggered = true;
+        }
+    }
+    if !triggered {
+        if let Some(sample) = samples.get(DEFAULT_SOUND) {
+            voices.trigger(sample, note.gains);
+        }
+    }
+}
+
+/// Starts the click of the note with `index` in `layer`: synthesized on
+/// `click`, or for the sample timbre from the kit on `click_samples`.
+fn trigger_click(
+    click: &mut ClickVoice,
+    click_samples: &mut VoicePool,
+    samples: &SampleKit,
+    config: &Config,
+    layer: &EngineLayer,
+    index: isize,
+    sample_rate: f64,
+) {
+    let note = layer.note(index);
+    let accent = if note.accented {
+        Accent::Downbeat
+    } else {
+        Accent::at(layer.beat(index), config.click.beats_per_bar)
+    };
+    let volume = config.click_volume as f32;
+    let gains = [note.gains[0] * volume, note.gains[1] * volume];
+    click.trigger(&config.click, accent, gains, sample_rate);
+    if config.click.timbre == ClickTimbre::Sample {
+        if let Some(sample) = samples.get(sound_name(&config.click.sample)) {
+            let level = config.click.level(accent).gain;
+            click_samples.trigger(sample, [gains[0] * level, gains[1] * level]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alloc_counter::count_allocations;
+    use crate::constants::default_config;
+    use crate::kit::RetiredKits;
+    use crate::looper::LooperMode;
+    use crate::structs::{Note, ParserRhythm};
+
+    const RATE: f64 = 44100.0;
+    const BLOCK: usize = 512;
+
+    /// Everything off, one beat a second, no compensation.
+    fn quiet_config() -> Config {
+        Config {
+            bpm: 60.0,
+            looping_on: false,
+            click_on: false,
+            drum_on: false,
+            play_file: false,
+            audio_monitor_on: false,
+            buffer_compensation: 0,
+            ..default_config()
+        }
+    }
+
+    /// Renders the mono `input` in blocks, returning left and right.
+    fn render(engine: &mut Engine, input: &[S]) -> [Vec<S>; 2] {
+        let mut out = [vec![0.0; input.len()], vec![0.0; input.len()]];
+        for start in (0..input.len()).step_by(BLOCK) {
+            let end = (start + BLOCK).min(input.len());
+            let [left, right] = &mut out;
+            engine.process(
+                &[&input[start..end]],
+                &mut [&mut left[start..end], &mut right[start..end]],
+            );
+        }
+        out
+    }
+
+    /// Like `render`, to `channels` output channels.
+    fn render_channels(engine: &mut Engine, input: &[S], channels: usize) -> Vec<Vec<S>> {
+        let mut out = vec![vec![0.0; input.len()]; channels];
+        for start in (0..input.len()).step_by(BLOCK) {
+            let end = (start + BLOCK).min(input.len());
+            let mut output: Vec<&mut [S]> = out.iter_mut().map(|c| &mut c[start..end]).collect();
+            engine.process(&[&input[start..end]], &mut output);
+        }
+        out
+    }
+
+    fn peak(samples: &[S]) -> f32 {
+        samples.iter().fold(0.0, |peak, s| peak.max(s.abs()))
+    }
+
+    #[test]
+    fn passes_the_input_through_when_monitoring() {
+        let mut config = quiet_config();
+        config.audio_monitor_on = true;
+        config.input_channels[0].pan = 1.0;
+        let (mut engine, _controls) = Engine::new(config, SampleKit::new(), RATE);
+        let [left, right] = render(&mut engine, &[0.01; 1000]);
+        assert!(left.iter().all(|s| *s == 0.0));
+        assert!(right.iter().all(|s| (s - 0.12).abs() < 1e-6));
+    }
+
+    #[test]
+    fn plays_the_drum_sample_of_each_note() {
+        let mut config = quiet_config();
+        config.drum_on = true;
+        let ride = vec![0.1, 0.2, 0.3, 0.4];
+        let kit = SampleKit::from([(DEFAULT_SOUND.to_string(), Arc::new(ride))]);
+        let (mut engine, _controls) = Engine::new(config, kit, RATE);
+        let [left, right] = render(&mut engine, &[0.0; 30000]);
+        assert_eq!(&left[..3], &[0.1, 0.3, 0.0]);
+        assert_eq!(&right[..3], &[0.2, 0.4, 0.0]);
+        // the second note is half a beat in
+        let half = RATE as usize / 2;
+        assert!(left[3..half].iter().all(|s| *s == 0.0));
+        assert_eq!(&left[half..half + 3], &[0.1, 0.3, 0.0]);
+    }
+
+    #[test]
+    fn plays_at_the_same_speed_to_any_number_of_channels() {
+        let mut config = quiet_config();
+        config.drum_on = true;
+        config.looping_on = true;
+        config.beats_to_loop = 1.0;
+        let ride = vec![0.1, 0.2, 0.3, 0.4];
+        let frames = RATE as usize;
+        let half = frames / 2;
+        let mut input = vec![0.0; frames];
+        input[100] = 0.5;
+        for channels in [1, 4] {
+            let kit = SampleKit::from([(DEFAULT_SOUND.to_string(), Arc::new(ride.clone()))]);
+            let (mut engine, mut controls) = Engine::new(config.clone(), kit, RATE);
+            let recorded = render_channels(&mut engine, &input, channels);
+            controls.looper.set_mode(LooperMode::Play).unwrap();
+            let played = render_channels(&mut engine, &vec![0.0; frames], channels);
+            for ch in 0..channels {
+                let side = ch % 2;
+                let expected = [ride[side], ride[side + 2], 0.0];
+                assert_eq!(&recorded[ch][..3], &expected, "{} {}", channels, ch);
+                assert_eq!(&recorded[ch][half..half + 3], &expected);
+                // the loop comes round a second later, as recorded
+                assert_eq!(played[ch][100], 6.0, "{} {}", channels, ch);
+                assert_eq!(played[ch][..3], expected);
+                let elsewhere = |i: usize| i != 100 && i % half >= 2;
+                assert!(played[ch]
+                    .iter()
+                    .enumerate()
+                    .all(|(i, s)| !elsewhere(i) || *s == 0.0));
+            }
+        }
+    }
+
+    #[test]
+    fn clicks_on_each_note_louder_on_the_downbeat() {
+        let mut config = quiet_config();
+        config.click_on = true;
+        let (mut engine, _controls) = Engine::new(config, SampleKit::new(), RATE);
+        let [left, right] = render(&mut engine, &[0.0; 50000]);
+        assert_eq!(left, right);
+        let half = RATE as usize / 2;
+        let downbeat = peak(&left[..half / 2]);
+        let subdivision = peak(&left[half..half + half / 2]);
+        assert!(downbeat > 0.0);
+        assert!(subdivision > 0.0 && subdivision < downbeat);
+        assert_eq!(peak(&left[half / 2..half]), 0.0);
+    }
+
+    #[test]
+    fn plays_back_a_recorded_loop() {
+        let mut config = quiet_config();
+        config.looping_on = true;
+        config.beats_to_loop = 1.0;
+        let (mut engine, mut controls) = Engine::new(config, SampleKit::new(), RATE);
+        let frames = RATE as usize;
+        let mut input = vec![0.0; frames];
+        input[100] = 0.5;
+        render(&mut engine, &input);
+        controls.looper.set_mode(LooperMode::Play).unwrap();
+        let [left, right] = render(&mut engine, &vec![0.0; frames]);
+        assert_eq!(left[100], 6.0);
+        assert_eq!(right[100], 6.0);
+        assert_eq!(peak(&left[..100]), 0.0);
+        assert_eq!(peak(&left[101..]), 0.0);
+    }
+
+    #[test]
+    fn stamps_hits_with_the_compensated_beat() {
+        let hit_beat = |buffer_compensation: usize| {
+            let config = Config {
+                buffer_compensation,
+                ..quiet_config()
+            };
+            let (mut engine, mut controls) = Engine::new(config, SampleKit::new(), RATE);
+            let mut input = vec![0.0; RATE as usize];
+            input[RATE as usize / 2] = 0.5;
+            render(&mut engine, &input);
+            let hit = controls.hits.pop().unwrap();
+            assert!(controls.hits.pop().is_err());
+            hit.beat
+        };
+        // the hop the impulse is in is taken back out
+        let uncompensated = hit_beat(0);
+        assert!((0.5 - ONSET_HOP as f64 / RATE..=0.5).contains(&uncompensated));
+        // a tenth of a beat earlier
+        let compensated = hit_beat(RATE as usize / 10);
+        assert!((uncompensated - compensated - 0.1).abs() < 1e-9);
+    }
+
+    #[test]
+    fn keeps_the_loop_across_a_rate_change() {
+        let mut config = quiet_config();
+        config.looping_on = true;
+        config.beats_to_loop = 1.0;
+        let (mut engine, mut controls) = Engine::new(config, SampleKit::new(), RATE);
+        let mut input = vec![0.0; RATE as usize];
+        input[1000] = 0.5;
+        render(&mut engine, &input);
+        controls.looper.set_mode(LooperMode::Play).unwrap();
+        engine.set_sample_rate(RATE / 2.0);
+        let [left, _] = render(&mut engine, &vec![0.0; RATE as usize / 2]);
+        let loudest = (0..left.len())
+            .max_by(|a, b| left[*a].abs().partial_cmp(&left[*b].abs()).unwrap())
+            .unwrap();
+        // the impulse was 1000 frames in, within a frame of the resampler's alignment
+        assert!((499..=501).contains(&loudest), "{}", loudest);
+        assert!(left[loudest] > 1.0);
+    }
+
+    #[test]
+    fn process_does_not_allocate_across_swaps() {
+        let config = Config {
+            looping_on: true,
+            click_on: true,
+            drum_on: true,
+            play_file: true,
+            audio_monitor_on: true,
+            ..quiet_config()
+        };
+        let kit = SampleKit::from([(DEFAULT_SOUND.to_string(), Arc::new(vec![0.1; 8000]))]);
+        let (mut engine, mut controls) = Engine::new(config.clone(), kit, RATE);
+        let mut retired = RetiredKits::default();
+        let input = vec![0.01; BLOCK];
+        let (mut left, mut right) = (vec![0.0; BLOCK], vec![0.0; BLOCK]);
+        let mut allocations = 0;
+        for block in 0..400 {
+            while let Some(kit) = controls.kit.take_retired() {
+                retired.keep(kit);
+            }
+            retired.sweep();
+            match block {
+                // while the second note's ride is still sounding
+                45 => {
+                    let kit = SampleKit::from([
+                        (DEFAULT_SOUND.to_string(), Arc::new(vec![0.2; 8000])),
+                        ("kick".to_string(), Arc::new(vec![0.3; 8000])),
+                    ]);
+                    assert!(controls.kit.push(kit).is_ok());
+                }
+                100 => {
+                    let size = get_loop_buffer_size(&config, RATE);
+                    assert!(controls.loop_buffer.send(LoopBuffer::new(size)).is_ok());
+                }
+                150 => {
+                    let mut config = config.clone();
+                    config.click.timbre = ClickTimbre::Sample;
+                    config.layers.push(RhythmLayer::new(ParserRhythm {
+                        start: 0.0,
+                        end: 0.75,
+                        notes: vec![Note::at(0.0), Note::at(0.25), Note::at(0.5)],
+                    }));
+                    config.layers[1].rhythm.notes[1].sounds = vec!["k".to_string()];
+                    controls.config.publish(EngineConfig::new(config));
+                }
+                200 => {
+                    controls.looper.set_mode(LooperMode::Overdub).unwrap();
+                }
+                250 => controls.config.publish(EngineConfig::new(quiet_config())),
+                _ => {}
+            }
+            let (_, count) = count_allocations(|| {
+                engine.process(&[&input], &mut [&mut left, &mut right]);
+            });
+            allocations += count;
+        }
+        assert_eq!(allocations, 0);
+    }
+}
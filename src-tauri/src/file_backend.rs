@@ -0,0 +1,244 @@
+use crate::audio_backend::{
+    AudioBackend, ChannelSlices, ChannelSlicesMut, DeviceFormat, DeviceInfo, ProcessCallback,
+    StreamSettings, COMMON_SAMPLE_RATES, MAX_CHANNELS,
+};
+
+use crate::read_audio_file::{decode_audio_file, AudioBuffer};
+use crate::resample::convert;
+use crate::types::S;
+use crate::util::deinterleave;
+use crate::write_audio_file::write_wav;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+const INPUT_CHANNELS: usize = 2;
+const DEVICE_ID: &str = "file";
+
+fn check_device(device: Option<&str>) -> Result<(), String> {
+    match device {
+        Some(id) if id != DEVICE_ID => Err(format!("no audio device with id {}", id)),
+        _ => Ok(()),
+    }
+}
+
+/// Backend without audio hardware. Plays an input recording (mixed to stereo) or
+/// silence into the process callback at real-time pace and optionally writes
+/// what it renders to a WAV file when stopped. In loopback mode the input is the
+/// first output channel delayed by a fixed number of frames instead, standing in
+/// for a cable from output to input.
+pub struct FileBackend {
+    input: Option<AudioBuffer>,
+    loopback_delay: Option<usize>,
+    output_file: Option<String>,
+    output_channels: usize,
+    settings: StreamSettings,
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<Vec<Vec<S>>>>,
+    log: Vec<String>,
+}
+
+impl FileBackend {
+    pub fn new(
+        input_file: Option<String>,
+        output_file: Option<String>,
+    ) -> Result<FileBackend, String> {
+        let mut log = vec![];
+        let input = match &input_file {
+            Some(filename) => {
+                let buffer = decode_audio_file(filename)?;
+                log.push(format!(
+                    "file input: {} ({} channels, {} Hz)",
+                    filename, buffer.channels, buffer.sample_rate
+                ));
+                Some(buffer)
+            }
+            None => {
+                log.push("file input: silence".to_string());
+                None
+            }
+        };
+        if let Some(filename) = &output_file {
+            log.push(format!("file output: {}", filename));
+        }
+        Ok(FileBackend {
+            input,
+            loopback_delay: None,
+            output_file,
+            output_channels: 0,
+            settings: StreamSettings::default(),
+            running: Arc::new(AtomicBool::new(false)),
+            thread: None,
+            log,
+        })
+    }
+
+    /// Delays below the buffer size are raised to it, as a buffer's input has
+    /// to exist before its output is rendered.
+    pub fn loopback(delay: usize, output_file: Option<String>) -> FileBackend {
+        let mut log = vec![format!(
+            "loopback input: output delayed by {} frames",
+            delay
+        )];
+        if let Some(filename) = &output_file {
+            log.push(format!("file output: {}", filename));
+        }
+        FileBackend {
+            input: None,
+            loopback_delay: Some(delay),
+            output_file,
+            output_channels: 0,
+            settings: StreamSettings::default(),
+            running: Arc::new(AtomicBool::new(false)),
+            thread: None,
+            log,
+        }
+    }
+}
+
+impl AudioBackend for FileBackend {
+    fn name(&self) -> &'static str {
+        if self.loopback_delay.is_some() {
+            "loopback"
+        } else {
+            "file"
+        }
+    }
+
+    fn devices(&self) -> Result<Vec<DeviceInfo>, String> {
+        Ok(vec![DeviceInfo {
+            id: DEVICE_ID.to_string(),
+            name: format!("{} backend", self.name()),
+            input_channels: INPUT_CHANNELS,
+            output_channels: 2,
+            sample_rates: COMMON_SAMPLE_RATES.to_vec(),
+        }])
+    }
+
+    fn open_input(
+        &mut self,
+        device: Option<&str>,
+        settings: &StreamSettings,
+    ) -> Result<DeviceFormat, String> {
+        check_device(device)?;
+        Ok(DeviceFormat {
+            sample_rate: settings.sample_rate,
+            channels: INPUT_CHANNELS,
+            buffer_size: settings.buffer_size,
+        })
+    }
+
+    fn open_output(
+        &mut self,
+        device: Option<&str>,
+        settings: &StreamSettings,
+    ) -> Result<DeviceFormat, String> {
+        check_device(device)?;
+        self.output_channels = 2;
+        self.settings = *settings;
+        Ok(DeviceFormat {
+            sample_rate: settings.sample_rate,
+            channels: 2,
+            buffer_size: settings.buffer_size,
+        })
+    }
+
+    fn start(&mut self, mut callback: ProcessCallback) -> Result<(), String> {
+        if self.output_channels == 0 {
+            return Err("output has not been opened".into());
+        }
+        let running = self.running.clone();
+        running.store(true, Ordering::Relaxed);
+        // converted here since the stream rate is only known once the output is open
+        let input_samples = match &self.input {
+            Some(buffer) => {
+                let buffer = convert(buffer, self.settings.sample_rate, INPUT_CHANNELS)?;
+                deinterleave(&buffer.samples, INPUT_CHANNELS)
+            }
+            None => vec![vec![]; INPUT_CHANNELS],
+        };
+        let output_channels = self.output_channels;
+        let keep_output = self.output_file.is_some();
+        let buffer_size = self.settings.buffer_size;
+        let buffer_duration =
+            Duration::from_secs_f64(buffer_size as f64 / self.settings.sample_rate);
+        let loopback = self.loopback_delay.is_some();
+        let mut delay_line: VecDeque<S> = match self.loopback_delay {
+            Some(delay) => VecDeque::from(vec![0.0; delay.max(buffer_size)]),
+            None => VecDeque::new(),
+        };
+
+        self.thread = Some(std::thread::spawn(move || {
+            let mut recorded: Vec<Vec<S>> = vec![vec![]; output_channels];
+            let mut input_scratch = vec![vec![0.0; buffer_size]; INPUT_CHANNELS];
+            let mut output_scratch = vec![vec![0.0; buffer_size]; output_channels];
+            let mut input_pos = 0;
+            let mut next_deadline = Instant::now();
+            while running.load(Ordering::Relaxed) {
+                if loopback {
+                    for i in 0..buffer_size {
+                        let value = delay_line.pop_front().unwrap_or(0.0);
+                        for channel in input_scratch.iter_mut() {
+                            channel[i] = value;
+                        }
+                    }
+                } else {
+                    for (channel, samples) in input_scratch.iter_mut().zip(input_samples.iter()) {
+                        for (i, value) in channel.iter_mut().enumerate() {
+                            *value = if samples.is_empty() {
+                                0.0
+                            } else {
+                                samples[(input_pos + i) % samples.len()]
+                            };
+                        }
+                    }
+                    input_pos += buffer_size;
+                }
+                for channel in output_scratch.iter_mut() {
+                    channel.fill(0.0);
+                }
+                {
+                    let input: ChannelSlices = input_scratch.iter().map(|c| c.as_slice()).collect();
+                    let mut output: ChannelSlicesMut = output_scratch
+                        .iter_mut()
+                        .take(MAX_CHANNELS)
+                        .map(|c| c.as_mut_slice())
+                        .collect();
+                    callback(&input, &mut output);
+                }
+                if loopback {
+                    delay_line.extend(output_scratch[0].iter());
+                }
+                if keep_output {
+                    for (ch, channel) in output_scratch.iter().enumerate() {
+                        recorded[ch].extend_from_slice(channel);
+                    }
+                }
+                next_deadline += buffer_duration;
+                let now = Instant::now();
+                if next_deadline > now {
+                    std::thread::sleep(next_deadline - now);
+                }
+            }
+            recorded
+        }));
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<(), String> {
+        self.running.store(false, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let recorded = thread.join().map_err(|_| "file backend thread panicked")?;
+            if let Some(filename) = &self.output_file {
+                write_wav(filename, &recorded, self.settings.sample_rate as u32)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn device_formats(&self) -> Vec<String> {
+        self.log.clone()
+    }
+}
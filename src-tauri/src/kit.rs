@@ -0,0 +1,175 @@
+//! The drum kit: which sample each sound a note can ask for plays. The
+//! notation's drum letters stand for the standard names, and a kit can add any
+//! other names for notes given in JSON. Kit files are named relative to the
+//! samples directory, or by an absolute path.
+
+use crate::read_audio_file::decode_audio_file;
+use crate::realtime::SwapSender;
+use crate::resample::convert;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Played by notes without sounds, and by those none of whose sounds the kit has.
+pub const DEFAULT_SOUND: &str = "ride";
+
+/// Interleaved stereo samples at the engine's rate, by sound name.
+pub type SampleKit = HashMap<String, Arc<Vec<f32>>>;
+
+/// The kit name a note's sound refers to: `k`, `s`, `h` and `r` are short for
+/// kick, snare, hihat and ride; anything else is a name of its own.
+pub fn sound_name(sound: &str) -> &str {
+    match sound {
+        "k" => "kick",
+        "s" => "snare",
+        "h" => "hihat",
+        "r" => "ride",
+        name => name,
+    }
+}
+
+/// Only the ride ships with the app, so until the kit maps kick, snare and
+/// hihat to files, notes asking for them play it as the default sound.
+pub fn default_kit() -> BTreeMap<String, String> {
+    BTreeMap::from([(DEFAULT_SOUND.to_string(), "ride_cropped.wav".to_string())])
+}
+
+/// Decodes every sample of `kit`, skipping the ones that cannot be read.
+pub fn load_kit(kit: &BTreeMap<String, String>, samples_dir: &Path, sample_rate: f64) -> SampleKit {
+    let mut samples = SampleKit::new();
+    for (name, file) in kit {
+        let path = samples_dir.join(file);
+        match decode_audio_file(&path.to_string_lossy()).and_then(|b| convert(&b, sample_rate, 2)) {
+            Ok(buffer) => {
+                samples.insert(name.clone(), Arc::new(buffer.samples));
+            }
+            Err(err) => println!("could not load {} for {}: {}", path.display(), name, err),
+        }
+    }
+    samples
+}
+
+/// Kits the audio thread has replaced. Their samples are kept until nothing
+/// else holds them: voices still playing a sample then drop a reference that is
+/// not the last, so the audio thread never frees one.
+#[derive(Default)]
+pub struct RetiredKits {
+    samples: Vec<Arc<Vec<f32>>>,
+}
+
+impl RetiredKits {
+    pub fn keep(&mut self, kit: SampleKit) {
+        self.samples.extend(kit.into_values());
+    }
+
+    /// Frees the samples no voice plays any more. With their kit gone, the
+    /// audio thread cannot start them again.
+    pub fn sweep(&mut self) {
+        self.samples.retain(|sample| Arc::strong_count(sample) > 1);
+    }
+}
+
+/// The sending side of the kit, shared with the threads decoding new ones.
+struct KitSender {
+    samples: SwapSender<SampleKit>,
+    retired: RetiredKits,
+    /// The latest `KitLoader::update` that asked for a new kit.
+    latest: usize,
+}
+
+impl KitSender {
+    fn send(&mut self, samples: SampleKit) -> Result<(), String> {
+        while let Some(kit) = self.samples.take_retired() {
+            self.retired.keep(kit);
+        }
+        self.retired.sweep();
+        self.samples
+            .push(samples)
+            .map_err(|_| "audio thread is not taking new samples".to_string())
+    }
+}
+
+/// Sends the engine a new set of samples whenever the config's kit changes,
+/// decoding them on a thread of their own.
+pub struct KitLoader {
+    samples_dir: PathBuf,
+    kit: BTreeMap<String, String>,
+    sender: Arc<Mutex<KitSender>>,
+}
+
+impl KitLoader {
+    /// `kit` is the one the engine was started with.
+    pub fn new(
+        samples_dir: PathBuf,
+        kit: BTreeMap<String, String>,
+        samples: SwapSender<SampleKit>,
+    ) -> KitLoader {
+        KitLoader {
+            samples_dir,
+            kit,
+            sender: Arc::new(Mutex::new(KitSender {
+                samples,
+                retired: RetiredKits::default(),
+                latest: 0,
+            })),
+        }
+    }
+
+    /// Starts loading `kit` if it changed. A load that finishes after a newer
+    /// one was started is not sent.
+    pub fn update(&mut self, kit: &BTreeMap<String, String>, sample_rate: f64) {
+        if *kit == self.kit {
+            return;
+        }
+        self.kit = kit.clone();
+        let request = match self.sender.lock() {
+            Ok(mut sender) => {
+                sender.latest += 1;
+                sender.latest
+            }
+            Err(_) => return,
+        };
+        let kit = kit.clone();
+        let samples_dir = self.samples_dir.clone();
+        let sender = self.sender.clone();
+        std::thread::spawn(move || {
+            let samples = load_kit(&kit, &samples_dir, sample_rate);
+            let mut sender = match sender.lock() {
+                Ok(sender) => sender,
+                Err(_) => return,
+            };
+            if sender.latest != request {
+                return;
+            }
+            if let Err(err) = sender.send(samples) {
+                println!("could not update the kit: {}", err);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_retired_samples_until_no_voice_plays_them() {
+        let sample = Arc::new(vec![0.5; 4]);
+        let freed = Arc::downgrade(&sample);
+        let voice = sample.clone();
+        let mut retired = RetiredKits::default();
+        retired.keep(SampleKit::from([("ride".to_string(), sample)]));
+        retired.sweep();
+        drop(voice);
+        assert!(freed.upgrade().is_some());
+        retired.sweep();
+        assert!(freed.upgrade().is_none());
+    }
+
+    #[test]
+    fn names_the_drum_letters() {
+        assert_eq!(sound_name("k"), "kick");
+        assert_eq!(sound_name("h"), "hihat");
+        assert_eq!(sound_name("cowbell"), "cowbell");
+    }
+}
@@ -0,0 +1,160 @@
+//! Round-trip latency measurement. A short chirp is played through every output
+//! channel while the input is recorded, and found again in the recording by
+//! cross-correlation. The delay in frames is what `Config::buffer_compensation`
+//! should be for the device pair.
+
+use crate::realtime::SwapSender;
+use crate::types::S;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+const CHIRP_SECONDS: f64 = 0.02;
+const CHIRP_START_HZ: f64 = 500.0;
+const CHIRP_END_HZ: f64 = 8000.0;
+const CHIRP_AMPLITUDE: f64 = 0.5;
+/// Longest round trip that can be measured.
+const MAX_LATENCY_SECONDS: f64 = 1.0;
+/// Normalized correlation below which the chirp counts as not found.
+const MIN_CONFIDENCE: f32 = 0.2;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LatencyMeasurement {
+    pub frames: usize,
+    pub milliseconds: f64,
+    pub sample_rate: f64,
+    /// Normalized cross-correlation at the detected delay, 0 to 1.
+    pub confidence: f32,
+}
+
+/// A measurement in flight. Built off the audio thread with its recording
+/// allocated, played and recorded by `Engine::process`, then sent back.
+pub struct LatencyProbe {
+    chirp: Vec<S>,
+    recording: Vec<S>,
+    pos: usize,
+}
+
+impl LatencyProbe {
+    pub fn new(sample_rate: f64) -> LatencyProbe {
+        let chirp = chirp(sample_rate);
+        let frames = chirp.len() + (MAX_LATENCY_SECONDS * sample_rate) as usize;
+        LatencyProbe {
+            chirp,
+            recording: vec![0.0; frames],
+            pos: 0,
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.pos >= self.recording.len()
+    }
+
+    /// Replaces the output with the chirp (then silence) and records the sum of
+    /// the input channels until the recording is full. Never allocates.
+    pub fn process(&mut self, input: &[&[S]], output: &mut [&mut [S]]) {
+        let num_frames = output.first().map(|c| c.len()).unwrap_or(0);
+        for i in 0..num_frames {
+            if self.is_done() {
+                break;
+            }
+            let value = self.chirp.get(self.pos).copied().unwrap_or(0.0);
+            for channel in output.iter_mut() {
+                channel[i] = value;
+            }
+            self.recording[self.pos] = input.iter().map(|channel| channel[i]).sum();
+            self.pos += 1;
+        }
+    }
+}
+
+/// Hann windowed linear sweep, kept below Nyquist at low rates.
+fn chirp(sample_rate: f64) -> Vec<S> {
+    let frames = (CHIRP_SECONDS * sample_rate) as usize;
+    let end_hz = CHIRP_END_HZ.min(sample_rate * 0.45);
+    let sweep_rate = (end_hz - CHIRP_START_HZ) / CHIRP_SECONDS;
+    (0..frames)
+        .map(|i| {
+            let t = i as f64 / sample_rate;
+            let phase =
+                2.0 * std::f64::consts::PI * (CHIRP_START_HZ * t + 0.5 * sweep_rate * t * t);
+            let window = 0.5 - 0.5 * (2.0 * std::f64::consts::PI * i as f64 / frames as f64).cos();
+            (CHIRP_AMPLITUDE * window * phase.sin()) as S
+        })
+        .collect()
+}
+
+/// Finds where `reference` starts in `recording`, returning the offset and the
+/// normalized correlation there. Polarity is ignored, as some paths invert.
+pub fn find_delay(reference: &[S], recording: &[S]) -> Option<(usize, f32)> {
+    if reference.is_empty() || recording.len() < reference.len() {
+        return None;
+    }
+    let reference_energy: f64 = reference.iter().map(|v| (*v as f64).powi(2)).sum();
+    let mut window_energy: f64 = recording[..reference.len()]
+        .iter()
+        .map(|v| (*v as f64).powi(2))
+        .sum();
+    let mut best: Option<(usize, f32)> = None;
+    for lag in 0..=recording.len() - reference.len() {
+        if lag > 0 {
+            let entering = recording[lag + reference.len() - 1] as f64;
+            let leaving = recording[lag - 1] as f64;
+            window_energy = (window_energy + entering * entering - leaving * leaving).max(0.0);
+        }
+        // silence correlates with nothing
+        if window_energy < 1e-12 {
+            continue;
+        }
+        let dot: f64 = reference
+            .iter()
+            .zip(&recording[lag..])
+            .map(|(a, b)| *a as f64 * *b as f64)
+            .sum();
+        let score = (dot.abs() / (reference_energy * window_energy).sqrt()) as f32;
+        if best.map(|(_, s)| score > s).unwrap_or(true) {
+            best = Some((lag, score));
+        }
+    }
+    best
+}
+
+/// Sends a probe to the audio thread, waits for it to come back and measures
+/// the delay of the chirp in what it recorded.
+pub fn measure(
+    probes: &mut SwapSender<LatencyProbe>,
+    sample_rate: f64,
+) -> Result<LatencyMeasurement, String> {
+    let probe = LatencyProbe::new(sample_rate);
+    let timeout = Duration::from_secs_f64(probe.recording.len() as f64 / sample_rate + 2.0);
+    if probes.send(probe).is_err() {
+        return Err("a latency measurement is already running".to_string());
+    }
+    let started = Instant::now();
+    let probe = loop {
+        if let Some(probe) = probes.take_retired() {
+            break probe;
+        }
+        if started.elapsed() > timeout {
+            return Err("the audio device did not run during the measurement".to_string());
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    };
+    match find_delay(&probe.chirp, &probe.recording) {
+        Some((frames, confidence)) if confidence >= MIN_CONFIDENCE => Ok(LatencyMeasurement {
+            frames,
+            milliseconds: frames as f64 * 1000.0 / sample_rate,
+            sample_rate,
+            confidence,
+        }),
+        _ => Err("the test signal was not heard on the input".to_string()),
+    }
+}
+
+/// Key under which measurements are stored, `None` meaning the default device.
+pub fn device_pair(input: Option<&str>, output: Option<&str>) -> String {
+    format!(
+        "{} -> {}",
+        input.unwrap_or("default"),
+        output.unwrap_or("default")
+    )
+}
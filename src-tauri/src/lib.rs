@@ -0,0 +1,31 @@
+pub mod alloc_counter;
+pub mod audio_backend;
+pub mod backing_track;
+pub mod click;
+pub mod constants;
+#[cfg(target_os = "macos")]
+pub mod coreaudio_backend;
+#[cfg(feature = "cpal")]
+pub mod cpal_backend;
+pub mod device_manager;
+pub mod engine;
+pub mod file_backend;
+pub mod get_loop_buffer_size;
+pub mod kit;
+pub mod latency;
+pub mod loop_file;
+pub mod looper;
+pub mod onset;
+pub mod parse_rhythm;
+pub mod read_audio_file;
+pub mod realtime;
+pub mod recording;
+pub mod resample;
+pub mod settings;
+pub mod structs;
+pub mod timing;
+pub mod track_stream;
+pub mod types;
+pub mod util;
+pub mod voice_pool;
+pub mod write_audio_file;
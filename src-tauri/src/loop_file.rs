@@ -0,0 +1,234 @@
+//! Saving the loop to a WAV file and loading one back as a reference loop.
+//!
+//! A loop frame is heard `buffer_compensation` frames after its slot in the
+//! loop buffer comes round, as the input it holds was played that long before
+//! it was recorded, and a reference loop is played that much early to match. So
+//! with the loop buffer at frame `pos` at beat `beat`, frame `q` of the buffer
+//! sounds at `beat + (q - pos - buffer_compensation)` frames, for either kind.
+
+use crate::get_loop_buffer_size::get_loop_buffer_size;
+use crate::read_audio_file::decode_audio_file;
+use crate::realtime::SwapSender;
+use crate::resample::convert;
+use crate::structs::{Config, LoopBuffer};
+use crate::types::S;
+use crate::write_audio_file::write_wav;
+use std::time::{Duration, Instant};
+
+/// A copy of the loop buffer made by the audio thread at the start of a callback.
+pub struct LoopSnapshot {
+    /// Sized by the sender; the audio thread only copies into a buffer of the loop's size.
+    pub buffer: Vec<S>,
+    pub pos: usize,
+    pub beat: f64,
+    pub taken: bool,
+}
+
+/// The loop frame that sounds at the bar line, for a loop buffer at frame
+/// `pos` at `beat`. `frames` is the loop length.
+pub fn first_frame(
+    config: &Config,
+    sample_rate: f64,
+    pos: usize,
+    beat: f64,
+    frames: usize,
+) -> usize {
+    let frames_per_beat = sample_rate * 60.0 / config.bpm;
+    let beat_frame = (beat * frames_per_beat).round() as i64;
+    (pos as i64 + config.buffer_compensation as i64 - beat_frame).rem_euclid(frames.max(1) as i64)
+        as usize
+}
+
+/// Writes the loop as stereo at the engine's rate, starting at beat 0 and one
+/// loop long. Returns the number of frames written.
+pub fn export_loop(
+    snapshots: &mut SwapSender<LoopSnapshot>,
+    config: &Config,
+    sample_rate: f64,
+    path: &str,
+) -> Result<usize, String> {
+    let snapshot = LoopSnapshot {
+        buffer: vec![0.0; get_loop_buffer_size(config, sample_rate)],
+        pos: 0,
+        beat: 0.0,
+        taken: false,
+    };
+    if snapshots.send(snapshot).is_err() {
+        return Err("a loop export is already running".to_string());
+    }
+    let started = Instant::now();
+    let snapshot = loop {
+        if let Some(snapshot) = snapshots.take_retired() {
+            break snapshot;
+        }
+        if started.elapsed() > Duration::from_secs(2) {
+            return Err("the audio device is not running".to_string());
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    };
+    if !snapshot.taken {
+        return Err("the loop changed length during the export".to_string());
+    }
+
+    let frames = snapshot.buffer.len() / 2;
+    let first = first_frame(config, sample_rate, snapshot.pos / 2, snapshot.beat, frames);
+    let channels: Vec<Vec<S>> = (0..2)
+        .map(|side| {
+            (first..frames)
+                .chain(0..first)
+                .map(|frame| snapshot.buffer[frame * 2 + side])
+                .collect()
+        })
+        .collect();
+    write_wav(path, &channels, sample_rate as u32)?;
+    Ok(frames)
+}
+
+/// Decodes `filename` into a reference loop buffer for the current loop length,
+/// cutting or padding it with silence to fit. Beat 0 is the file's first frame.
+pub fn load_loop(filename: &str, config: &Config, sample_rate: f64) -> Result<LoopBuffer, String> {
+    let decoded = decode_audio_file(filename).and_then(|b| convert(&b, sample_rate, 2))?;
+    let mut loop_buffer = LoopBuffer::new(get_loop_buffer_size(config, sample_rate));
+    let n = decoded.samples.len().min(loop_buffer.buffer.len());
+    loop_buffer.buffer[..n].copy_from_slice(&decoded.samples[..n]);
+    loop_buffer.reference = true;
+    Ok(loop_buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::default_config;
+    use crate::engine::Engine;
+    use crate::kit::SampleKit;
+    use crate::realtime::swap_channel;
+    use std::path::PathBuf;
+
+    /// One beat a second and a one beat loop, so beats and loops are `rate` frames.
+    fn loop_config(buffer_compensation: usize) -> Config {
+        Config {
+            bpm: 60.0,
+            beats_to_loop: 1.0,
+            looping_on: true,
+            click_on: false,
+            drum_on: false,
+            play_file: false,
+            audio_monitor_on: false,
+            buffer_compensation,
+            ..default_config()
+        }
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("{}_{}.wav", name, std::process::id()))
+    }
+
+    #[test]
+    fn finds_the_frame_on_the_bar_line() {
+        let rate = 100.0;
+        assert_eq!(first_frame(&loop_config(0), rate, 0, 0.0, 100), 0);
+        assert_eq!(first_frame(&loop_config(30), rate, 20, 0.0, 100), 50);
+        // half a beat on, the bar line was 50 frames back
+        assert_eq!(first_frame(&loop_config(30), rate, 20, 0.5, 100), 0);
+        // wrapping past either end of the loop
+        assert_eq!(first_frame(&loop_config(30), rate, 90, 0.0, 100), 20);
+        assert_eq!(first_frame(&loop_config(0), rate, 10, 0.5, 100), 60);
+        assert_eq!(first_frame(&loop_config(250), rate, 0, 0.0, 100), 50);
+    }
+
+    #[test]
+    fn exports_from_beat_0() {
+        let rate = 100.0;
+        let config = loop_config(30);
+        let (mut snapshots, mut audio_thread) = swap_channel::<LoopSnapshot>();
+        // the audio thread's side, with each frame holding its own index
+        let audio = std::thread::spawn(move || loop {
+            if let Some(mut snapshot) = audio_thread.receive() {
+                for (i, sample) in snapshot.buffer.iter_mut().enumerate() {
+                    let frame = (i / 2) as f32 / 100.0;
+                    *sample = if i % 2 == 0 { frame } else { -frame };
+                }
+                snapshot.pos = 2 * 80;
+                snapshot.beat = 0.5;
+                snapshot.taken = true;
+                audio_thread.retire(snapshot);
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        });
+        let path = temp_path("loop_file_export");
+        let path = path.to_str().unwrap();
+        let frames = export_loop(&mut snapshots, &config, rate, path).unwrap();
+        audio.join().unwrap();
+
+        let exported = decode_audio_file(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(frames, 100);
+        assert_eq!(exported.frames(), 100);
+        assert_eq!(exported.sample_rate, rate);
+        // 80 + 30 - 50 frames into the buffer, going round past its end
+        assert_eq!(&exported.samples[..4], &[0.6, -0.6, 0.61, -0.61]);
+        assert_eq!(&exported.samples[78..82], &[0.99, -0.99, 0.0, -0.0]);
+        assert_eq!(&exported.samples[198..], &[0.59, -0.59]);
+    }
+
+    #[test]
+    fn fits_a_loaded_loop_to_the_loop_length() {
+        let rate = 100.0;
+        let config = loop_config(0);
+        let path = temp_path("loop_file_load");
+        let path = path.to_str().unwrap();
+
+        write_wav(path, &[vec![0.5; 30], vec![0.25; 30]], 100).unwrap();
+        let short = load_loop(path, &config, rate).unwrap();
+        assert!(short.reference);
+        assert_eq!(short.buffer.len(), 200);
+        assert_eq!(&short.buffer[..2], &[0.5, 0.25]);
+        assert_eq!(&short.buffer[58..60], &[0.5, 0.25]);
+        assert!(short.buffer[60..].iter().all(|s| *s == 0.0));
+
+        let ramp: Vec<f32> = (0..150).map(|i| i as f32).collect();
+        write_wav(path, &[ramp.clone(), ramp], 100).unwrap();
+        let long = load_loop(path, &config, rate).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(long.buffer.len(), 200);
+        assert_eq!(&long.buffer[198..], &[99.0, 99.0]);
+    }
+
+    #[test]
+    fn plays_a_loaded_loop_over_the_input() {
+        let rate = 44100.0;
+        let config = loop_config(0);
+        let frames = rate as usize;
+        let mut channel = vec![0.0; frames];
+        channel[100] = 0.5;
+        let path = temp_path("loop_file_reference");
+        let path = path.to_str().unwrap();
+        write_wav(path, &[channel.clone(), channel], rate as u32).unwrap();
+        let reference = load_loop(path, &config, rate).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        let (mut engine, mut controls) = Engine::new(config, SampleKit::new(), rate);
+        assert!(controls.loop_buffer.send(reference).is_ok());
+        controls.looper.reference_loaded();
+        // two passes of steady input, which recording would put in the loop
+        let input = vec![0.01; 512];
+        let mut passes = vec![vec![]; 2];
+        for pass in passes.iter_mut() {
+            let mut done = 0;
+            while done < frames {
+                let n = (frames - done).min(512);
+                let (mut left, mut right) = (vec![0.0; n], vec![0.0; n]);
+                engine.process(&[&input[..n]], &mut [&mut left, &mut right]);
+                pass.extend(left);
+                done += n;
+            }
+        }
+        assert_eq!(passes[0], passes[1]);
+        assert!(passes[0][100] > 0.0);
+        assert!(passes[0]
+            .iter()
+            .enumerate()
+            .all(|(i, s)| i == 100 || *s == 0.0));
+    }
+}
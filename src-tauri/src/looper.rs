@@ -0,0 +1,293 @@
+//! What the loop does with the input while `Config::looping_on` is set. In
+//! record mode each pass replaces the one before, so the loop always holds the
+//! latest `beats_to_loop` beats; play freezes it; overdub mixes the input onto
+//! it, scaling what is already there by the feedback amount. Overdubbing keeps
+//! the contents it replaces so the layer can be undone.
+
+use crate::structs::LoopBuffer;
+use crate::types::S;
+use rtrb::{Consumer, Producer, RingBuffer};
+use serde::{Deserialize, Serialize};
+
+/// Commands that can be waiting for the next callback.
+const COMMAND_BUFFER_SIZE: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LooperMode {
+    Record,
+    Play,
+    Overdub,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum LooperCommand {
+    SetMode(LooperMode),
+    SetFeedback(f32),
+    Clear,
+    Undo,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct LooperStatus {
+    pub mode: LooperMode,
+    /// How much of the loop is kept on each overdub pass, from 0.0 to 1.0.
+    pub feedback: f32,
+    /// Whether there is an overdub layer to undo.
+    pub can_undo: bool,
+}
+
+/// The command side of the looper. It keeps its own copy of the status, so
+/// the audio thread never reports back.
+pub struct LooperControls {
+    commands: Producer<LooperCommand>,
+    status: LooperStatus,
+}
+
+/// The audio thread's end: applies the commands and writes the input to the loop.
+pub struct Looper {
+    commands: Consumer<LooperCommand>,
+    mode: LooperMode,
+    feedback: f32,
+    /// Where the current overdub layer started and how many samples of the loop
+    /// it has saved to `LoopBuffer::undo`, at most one pass.
+    layer_start: usize,
+    layer_len: usize,
+}
+
+pub fn looper() -> (Looper, LooperControls) {
+    let (producer, consumer) = RingBuffer::new(COMMAND_BUFFER_SIZE);
+    let status = LooperStatus {
+        mode: LooperMode::Record,
+        feedback: 1.0,
+        can_undo: false,
+    };
+    (
+        Looper {
+            commands: consumer,
+            mode: status.mode,
+            feedback: status.feedback,
+            layer_start: 0,
+            layer_len: 0,
+        },
+        LooperControls {
+            commands: producer,
+            status,
+        },
+    )
+}
+
+impl LooperControls {
+    pub fn status(&self) -> LooperStatus {
+        self.status.clone()
+    }
+
+    fn send(&mut self, command: LooperCommand) -> Result<LooperStatus, String> {
+        if self.commands.push(command).is_err() {
+            return Err("audio thread is not taking looper commands".to_string());
+        }
+        Ok(self.status())
+    }
+
+    /// Entering overdub starts a new layer; the previous one can no longer be undone.
+    pub fn set_mode(&mut self, mode: LooperMode) -> Result<LooperStatus, String> {
+        if mode == LooperMode::Overdub && self.status.mode != LooperMode::Overdub {
+            self.status.can_undo = true;
+        }
+        self.status.mode = mode;
+        self.send(LooperCommand::SetMode(mode))
+    }
+
+    pub fn set_feedback(&mut self, feedback: f32) -> Result<LooperStatus, String> {
+        self.status.feedback = feedback.clamp(0.0, 1.0);
+        self.send(LooperCommand::SetFeedback(self.status.feedback))
+    }
+
+    /// Silences the loop. The mode stays as it is.
+    pub fn clear(&mut self) -> Result<LooperStatus, String> {
+        self.status.can_undo = false;
+        self.send(LooperCommand::Clear)
+    }
+
+    /// Takes the last overdub layer back out and freezes the loop if it was
+    /// still being overdubbed.
+    pub fn undo(&mut self) -> Result<LooperStatus, String> {
+        if !self.status.can_undo {
+            return Err("there is no overdub to undo".to_string());
+        }
+        self.status.can_undo = false;
+        if self.status.mode == LooperMode::Overdub {
+            self.status.mode = LooperMode::Play;
+        }
+        self.send(LooperCommand::Undo)
+    }
+
+    /// Follows `Looper::reference_loaded` once a reference loop has been sent.
+    pub fn reference_loaded(&mut self) {
+        self.status.mode = LooperMode::Play;
+        self.status.can_undo = false;
+    }
+}
+
+impl Looper {
+    /// Applies the commands sent since the last callback.
+    pub fn receive(&mut self, loop_buffer: &mut LoopBuffer) {
+        while let Ok(command) = self.commands.pop() {
+            match command {
+                LooperCommand::SetMode(mode) => {
+                    if mode == LooperMode::Overdub && self.mode != LooperMode::Overdub {
+                        self.layer_start = loop_buffer.pos;
+                        self.layer_len = 0;
+                    }
+                    self.mode = mode;
+                }
+                LooperCommand::SetFeedback(feedback) => self.feedback = feedback,
+                LooperCommand::Clear => {
+                    loop_buffer.buffer.fill(0.0);
+                    self.layer_len = 0;
+                }
+                LooperCommand::Undo => {
+                    let len = loop_buffer.buffer.len();
+                    for k in 0..self.layer_len {
+                        let index = (self.layer_start + k) % len;
+                        loop_buffer.buffer[index] = loop_buffer.undo[index];
+                    }
+                    self.layer_len = 0;
+                    if self.mode == LooperMode::Overdub {
+                        self.mode = LooperMode::Play;
+                    }
+                }
+            }
+        }
+    }
+
+    /// A new loop buffer leaves nothing to undo.
+    pub fn buffer_replaced(&mut self, loop_buffer: &LoopBuffer) {
+        self.layer_start = loop_buffer.pos;
+        self.layer_len = 0;
+    }
+
+    /// A reference loop is played as it is.
+    pub fn reference_loaded(&mut self, loop_buffer: &LoopBuffer) {
+        self.mode = LooperMode::Play;
+        self.buffer_replaced(loop_buffer);
+    }
+
+    /// Writes one input sample to slot `p` of the loop.
+    pub fn write(&mut self, loop_buffer: &mut LoopBuffer, p: usize, sample: S) {
+        match self.mode {
+            LooperMode::Record => loop_buffer.buffer[p] = sample,
+            LooperMode::Play => {}
+            LooperMode::Overdub => {
+                if self.layer_len < loop_buffer.buffer.len() {
+                    loop_buffer.undo[p] = loop_buffer.buffer[p];
+                    self.layer_len += 1;
+                }
+                loop_buffer.buffer[p] = loop_buffer.buffer[p] * self.feedback + sample;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A loop of `len` samples counting up from 1.
+    fn counting_loop(len: usize) -> LoopBuffer {
+        let mut loop_buffer = LoopBuffer::new(len);
+        for (i, sample) in loop_buffer.buffer.iter_mut().enumerate() {
+            *sample = (i + 1) as S;
+        }
+        loop_buffer
+    }
+
+    /// Writes `input` from the loop's position on, going round as the engine does.
+    fn write_pass(looper: &mut Looper, loop_buffer: &mut LoopBuffer, input: &[S]) {
+        for sample in input {
+            let p = loop_buffer.pos;
+            looper.write(loop_buffer, p, *sample);
+            loop_buffer.pos = (p + 1) % loop_buffer.buffer.len();
+        }
+    }
+
+    #[test]
+    fn records_over_the_loop() {
+        let (mut looper, _controls) = looper();
+        let mut loop_buffer = counting_loop(4);
+        write_pass(&mut looper, &mut loop_buffer, &[0.5; 6]);
+        assert_eq!(loop_buffer.buffer, vec![0.5; 4]);
+    }
+
+    #[test]
+    fn play_leaves_the_loop_as_it_is() {
+        let (mut looper, mut controls) = looper();
+        let mut loop_buffer = counting_loop(4);
+        controls.set_mode(LooperMode::Play).unwrap();
+        looper.receive(&mut loop_buffer);
+        write_pass(&mut looper, &mut loop_buffer, &[0.5; 8]);
+        assert_eq!(loop_buffer.buffer, vec![1.0, 2.0, 3.0, 4.0]);
+    }
+
+    #[test]
+    fn overdub_scales_the_loop_by_the_feedback() {
+        let (mut looper, mut controls) = looper();
+        let mut loop_buffer = counting_loop(4);
+        controls.set_feedback(0.5).unwrap();
+        controls.set_mode(LooperMode::Overdub).unwrap();
+        looper.receive(&mut loop_buffer);
+        write_pass(&mut looper, &mut loop_buffer, &[0.25, 0.5, 0.75, 1.0]);
+        assert_eq!(loop_buffer.buffer, vec![0.75, 1.5, 2.25, 3.0]);
+        // a second pass takes the first one down again
+        write_pass(&mut looper, &mut loop_buffer, &[0.0; 4]);
+        assert_eq!(loop_buffer.buffer, vec![0.375, 0.75, 1.125, 1.5]);
+    }
+
+    #[test]
+    fn undo_restores_the_loop_from_before_the_overdub() {
+        let (mut looper, mut controls) = looper();
+        let mut loop_buffer = counting_loop(4);
+        loop_buffer.pos = 2;
+        controls.set_mode(LooperMode::Overdub).unwrap();
+        looper.receive(&mut loop_buffer);
+        // round the loop and half way again
+        write_pass(&mut looper, &mut loop_buffer, &[0.5; 6]);
+        assert_eq!(loop_buffer.buffer, vec![1.5, 2.5, 4.0, 5.0]);
+
+        let status = controls.undo().unwrap();
+        looper.receive(&mut loop_buffer);
+        assert_eq!(loop_buffer.buffer, vec![1.0, 2.0, 3.0, 4.0]);
+        assert_eq!(status.mode, LooperMode::Play);
+        assert!(!status.can_undo);
+        assert!(controls.undo().is_err());
+        // and the loop is frozen afterwards
+        write_pass(&mut looper, &mut loop_buffer, &[0.5; 4]);
+        assert_eq!(loop_buffer.buffer, vec![1.0, 2.0, 3.0, 4.0]);
+    }
+
+    #[test]
+    fn undo_keeps_what_was_there_before_a_partial_pass() {
+        let (mut looper, mut controls) = looper();
+        let mut loop_buffer = counting_loop(4);
+        controls.set_mode(LooperMode::Overdub).unwrap();
+        looper.receive(&mut loop_buffer);
+        write_pass(&mut looper, &mut loop_buffer, &[0.5; 2]);
+        controls.set_mode(LooperMode::Play).unwrap();
+        looper.receive(&mut loop_buffer);
+        controls.undo().unwrap();
+        looper.receive(&mut loop_buffer);
+        assert_eq!(loop_buffer.buffer, vec![1.0, 2.0, 3.0, 4.0]);
+    }
+
+    #[test]
+    fn clear_silences_the_whole_loop_at_once() {
+        let (mut looper, mut controls) = looper();
+        let mut loop_buffer = counting_loop(4);
+        controls.set_mode(LooperMode::Play).unwrap();
+        let status = controls.clear().unwrap();
+        looper.receive(&mut loop_buffer);
+        assert_eq!(loop_buffer.buffer, vec![0.0; 4]);
+        assert_eq!(status.mode, LooperMode::Play);
+        assert!(!status.can_undo);
+    }
+}
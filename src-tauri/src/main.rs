@@ -4,37 +4,38 @@
 )]
 
 mod commands;
-mod constants;
-mod get_loop_buffer_size;
-mod io_channels;
-mod read_audio_file;
-mod structs;
-mod types;
-mod util;
 
-extern crate coreaudio;
-
-use crate::commands::{get_samples, reset_beat, set_config, set_mp3_buffer};
-use crate::constants::{default_config, SAMPLE_RATE};
-use crate::get_loop_buffer_size::get_loop_buffer_size;
-use crate::io_channels::{get_input_output_channels, make_buffers, start_input_audio_unit};
-use crate::read_audio_file::get_samples_from_filename;
-use crate::structs::{
-    BeatResetState, ConfigState, LogState, LoopBuffer, LoopBufferState, Mp3Buffer, Mp3BufferState,
-    SampleOutputBuffer, SoundingSample,
+use crate::commands::{
+    cancel_file_load, clear_backing_track, clear_loop, delete_preset, export_loop, forward_hits,
+    get_backing_track, get_hits, get_looper_status, get_measured_latency, get_samples,
+    get_settings, get_timing_stats, list_audio_devices, list_presets, load_loop, load_preset,
+    measure_latency, parse_rhythm, reset_beat, save_preset, seek_backing_track,
+    select_audio_devices, set_config, set_looper_mode, set_mp3_buffer, set_overdub_feedback,
+    set_stream_settings, start_recording, stop_recording, supported_formats, undo_overdub,
+};
+use app_lib::audio_backend::{BackendKind, BackendOptions, StreamSettings};
+use app_lib::backing_track::load_backing_track;
+use app_lib::device_manager::DeviceManager;
+use app_lib::engine::Engine;
+use app_lib::kit::{load_kit, KitLoader};
+use app_lib::settings::SettingsStore;
+use app_lib::structs::{
+    BackingTrackState, BeatResetState, ConfigState, DeviceManagerState, FileLoadState, HitState,
+    KitState, LatencyProbeState, LogState, LoopBufferState, LoopSnapshotState, LooperState,
+    Mp3BufferState, RecordingSinkState, RecordingState, SampleOutputBuffer, SampleRateState,
+    SettingsState, TimingState, TrackSeekState,
 };
-use crate::types::{Args, S};
-use crate::util::{beat_bisect, mod_add};
-use rand::Rng;
+use app_lib::timing::TimingScorer;
 use std::{
-    collections::HashMap,
-    sync::atomic::AtomicBool,
-    sync::{Arc, Mutex},
+    collections::VecDeque,
+    path::Path,
+    sync::{atomic::AtomicBool, Arc, Mutex},
 };
+use tauri::Manager;
 
-fn main() -> Result<(), coreaudio::Error> {
+fn main() -> Result<(), String> {
     let context = tauri::generate_context!();
-    let app_config_dir = tauri::api::path::config_dir();
+    let app_config_dir = tauri::api::path::app_config_dir(context.config());
     let rd = tauri::api::path::resource_dir(&context.package_info(), &tauri::utils::Env::default());
     let binding = rd.unwrap();
     let resource_dir = binding.to_str().unwrap();
@@ -43,237 +44,135 @@ fn main() -> Result<(), coreaudio::Error> {
 
     // access an asset file within the tauri app
 
-    // load mp3
-    let mut mp3_loaded = false;
-    let path = "/Users/eric/Music/Logic/tauri-file.wav".into();
     println!("app_config_dir: {:?}", app_config_dir);
     println!("resource_dir: {:?}", &resource_dir);
-    let data = get_samples_from_filename(&path);
-    let mp3_arc: Arc<Mutex<Mp3Buffer>>;
-    if let Ok(data) = data {
-        mp3_loaded = true;
-        mp3_arc = Arc::new(Mutex::new(Mp3Buffer {
-            buffer: data,
-            pos: 0,
-        }));
-    } else {
-        mp3_arc = Arc::new(Mutex::new(Mp3Buffer {
-            buffer: vec![],
-            pos: 0,
-        }));
-    }
-
-    let mp3 = mp3_arc.clone();
-    let mp3_state = Mp3BufferState(mp3_arc.clone());
-
+    let settings = StreamSettings::default();
+    let settings_store = SettingsStore::open(app_config_dir);
+    let config = settings_store.settings().config.clone();
     // load samples
-    let mut sample_buffers = HashMap::new();
-    let ride_path = &format!("{}/{}", resource_dir, "samples/ride_cropped.wav");
-    sample_buffers.insert(
-        "ride".to_string(),
-        Arc::new(get_samples_from_filename(ride_path).unwrap()),
-    );
-    let mut sounding_samples = vec![];
-    sounding_samples.push(SoundingSample {
-        sample: sample_buffers.get("ride").unwrap().clone(),
-        pos: 0,
-    });
-
-    // setup audio
-    let (mut input_audio_unit, mut output_audio_unit, io_log) =
-        get_input_output_channels().unwrap();
-    let buffers = make_buffers();
-
-    let mut click_sound_counter: i32 = 0;
-    let mut rng = rand::thread_rng();
-
-    let log_state = LogState(Arc::new(Mutex::new(io_log)));
-
-    let config = default_config();
-    let config_state = ConfigState(Arc::new(Mutex::new(config)));
-    let config1 = config_state.0.clone();
+    let samples_dir = Path::new(resource_dir).join("samples");
+    let sample_buffers = load_kit(&config.kit, &samples_dir, settings.sample_rate);
+
+    let kit = config.kit.clone();
+    let (engine, mut controls) = Engine::new(config, sample_buffers, settings.sample_rate);
+    let mut backing_track = None;
+    let mut track_seek = None;
+    if let Some(path) = &settings_store.settings().backing_track {
+        match load_backing_track(
+            path,
+            settings.sample_rate,
+            &AtomicBool::new(false),
+            &mut |_| {},
+        ) {
+            Ok((buffer, info)) => {
+                track_seek = Some(buffer.seek_request());
+                let _ = controls.mp3.send(buffer);
+                backing_track = Some(info);
+            }
+            Err(err) => println!("could not load the last backing track: {}", err),
+        }
+    }
 
+    let timing_state = TimingState(Mutex::new(TimingScorer::new(
+        controls.config.current().config.scored_rhythm(),
+    )));
+    let config_state = ConfigState(Mutex::new(controls.config));
     let sample_output_buffer = SampleOutputBuffer {
-        buffer: Default::default(),
+        buffer: Mutex::new(controls.visual_samples),
     };
-    let sample_output_buffer_clone = sample_output_buffer.buffer.clone();
-
-    let loop_buffer_size: usize;
-    {
-        let c = config1.lock().unwrap();
-        loop_buffer_size = get_loop_buffer_size(&c);
-    }
-    let loop_buffer = LoopBuffer {
-        buffer: vec![0f32; loop_buffer_size],
-        pos: 0,
-    };
-    let loop_buffer_mutex_arc = Arc::new(Mutex::new(loop_buffer));
-    let loop_buffer_clone = loop_buffer_mutex_arc.clone();
-    let loop_buffer_state = LoopBufferState(loop_buffer_mutex_arc.clone());
-
-    let should_reset_beat_arc = Arc::new(AtomicBool::new(false));
-    let should_reset_beat = should_reset_beat_arc.clone();
-    let should_reset_beat_state = BeatResetState(should_reset_beat_arc.clone());
-
-    let mut beat: f64 = 0.0;
-    let mut last_beat: isize = 0;
+    let loop_buffer_state = LoopBufferState(Mutex::new(controls.loop_buffer));
+    let loop_snapshot_state = LoopSnapshotState(Mutex::new(controls.loop_snapshots));
+    let looper_state = LooperState(Mutex::new(controls.looper));
+    let mp3_state = Mp3BufferState(Mutex::new(controls.mp3));
+    let should_reset_beat_state = BeatResetState(controls.should_reset_beat);
+    let sample_rate_state = SampleRateState(controls.sample_rate);
+    let latency_probe_state = LatencyProbeState(Mutex::new(controls.latency_probes));
+    let hits = controls.hits;
+    let input_beat = controls.input_beat;
+    let recording_sink_state = RecordingSinkState(Mutex::new(controls.recordings));
+    let kit_state = KitState(Mutex::new(KitLoader::new(samples_dir, kit, controls.kit)));
 
-    start_input_audio_unit(
-        &mut input_audio_unit,
-        buffers.producer_left,
-        buffers.producer_right,
-    )
-    .unwrap();
-
-    output_audio_unit.set_render_callback(move |args: Args| {
-        let Args {
-            num_frames,
-            mut data,
-            ..
-        } = args;
-        let buffer_left = buffers.consumer_left.lock().unwrap();
-        let buffer_right = buffers.consumer_right.lock().unwrap();
-        let mut buffers = vec![buffer_left, buffer_right];
-        let config = config1.lock().unwrap();
-        let mut loop_buffer = loop_buffer_clone.lock().unwrap();
-        let beats_per_sample: f64 = config.bpm / SAMPLE_RATE / 60f64;
-        let mut mp3 = mp3.lock().unwrap();
-
-        if should_reset_beat.load(std::sync::atomic::Ordering::Relaxed) {
-            beat = 0.0;
-            mp3.pos = 0;
-            should_reset_beat_arc.store(false, std::sync::atomic::Ordering::Relaxed);
-        }
-
-        if let Ok(mut state_vec) = sample_output_buffer_clone.lock() {
-            for i in 0..num_frames {
-                // Default other channels to copy value from first channel as a fallback
-                let zero: S = 0 as S;
-                let f: S = *buffers[0].front().unwrap_or(&zero);
-                for (ch, channel) in data.channels_mut().enumerate() {
-                    let sample: S = buffers[ch].pop_front().unwrap_or(f) * config.audio_in_gain;
-                    let mut audio_out = 0.0;
-                    let mut visual_out = 0.0;
-                    if config.audio_monitor_on {
-                        audio_out += sample;
-                    }
-                    if config.visual_monitor_on {
-                        visual_out += sample;
-                    }
-
-                    let p = loop_buffer.pos;
-
-                    let compensated_loop_buffer_pos = mod_add(
-                        loop_buffer.pos,
-                        config.buffer_compensation * 2,
-                        loop_buffer.buffer.len(),
-                    );
-
-                    if config.looping_on {
-                        audio_out += loop_buffer.buffer[compensated_loop_buffer_pos];
-                        visual_out += loop_buffer.buffer[loop_buffer.pos];
-                        loop_buffer.buffer[p] = sample;
-                    } else {
-                        loop_buffer.buffer[p] = 0.0;
-                    }
-
-                    channel[i] = audio_out * 12.0;
-
-                    // mp3_sample = mp3_sample * 0.995 + mp3[mp3_pos] * 0.005;
-                    if mp3_loaded && config.play_file {
-                        channel[i] += mp3.buffer[mp3.pos];
-                        // channel[i] += mp3_sample;
-                    }
-                    if ch == 0 || ch == 1 {
-                        mp3.pos += 1;
-                    }
-                    if mp3.pos >= mp3.buffer.len() {
-                        mp3.pos = 0;
-                    }
-
-                    loop_buffer.pos += 1;
-                    if loop_buffer.pos >= loop_buffer.buffer.len() {
-                        loop_buffer.pos = 0;
-                    }
-
-                    for j in (0..sounding_samples.len()).rev() {
-                        if sounding_samples[j].pos < sounding_samples[j].sample.len() {
-                            if config.drum_on {
-                                channel[i] += sounding_samples[j].sample[sounding_samples[j].pos];
-                            }
-                            sounding_samples[j].pos += 1;
-                        } else {
-                            sounding_samples.remove(j);
-                        }
-                    }
-
-                    let visual_beat =
-                        (beat - (config.buffer_compensation as f64) * beats_per_sample) as f32;
-                    state_vec.push((visual_beat, visual_out.abs()));
-
-                    // let adjusted_beat = beat_bisect(&config.audio_subdivisions, beat);
-                    let mut audio_times = config
-                        .audio_subdivisions
-                        .notes
-                        .iter()
-                        .map(|n| n.time)
-                        .collect::<Vec<f64>>();
-                    audio_times.push(config.audio_subdivisions.end);
-
-                    let adjusted_beat = beat_bisect(&audio_times, beat);
-                    if adjusted_beat != last_beat {
-                        sounding_samples.push(SoundingSample {
-                            sample: sample_buffers.get("ride").unwrap().clone(),
-                            pos: 0,
-                        });
-                        if config.audio_subdivisions.notes.len() < 2
-                            || (adjusted_beat % ((config.audio_subdivisions.notes.len()) as isize)
-                                == 0)
-                        {
-                            click_sound_counter = 400;
-                        } else {
-                            click_sound_counter = 100;
-                        }
-                        last_beat = adjusted_beat;
-                    }
-                    if click_sound_counter > 0 {
-                        click_sound_counter -= 1;
-                        let in_loop = beat % (config.beats_to_loop * 2.0) < config.beats_to_loop;
-                        if config.click_on {
-                            if !config.click_toggle || in_loop {
-                                let mut r = rng.gen::<f32>() * (config.click_volume as f32);
-                                if r > 1.0 {
-                                    r = 1.0;
-                                }
-                                channel[i] += r;
-                            }
-                        }
-                    }
-                }
-                beat += beats_per_sample;
-            }
-        }
-        Ok(())
-    })?;
-    output_audio_unit.start()?;
+    // setup audio
+    let log_state = LogState(Arc::new(Mutex::new(vec![])));
+    let (device_manager, _) = DeviceManager::start(
+        BackendKind::from_env(),
+        BackendOptions::from_env(),
+        settings,
+        engine,
+        log_state.0.clone(),
+    )?;
+    let device_manager_state = DeviceManagerState(Mutex::new(device_manager.clone()));
 
     tauri::Builder::default()
         .manage(sample_output_buffer)
         .manage(config_state)
+        .manage(SettingsState(Mutex::new(settings_store)))
         .manage(loop_buffer_state)
+        .manage(loop_snapshot_state)
+        .manage(looper_state)
         .manage(mp3_state)
+        .manage(BackingTrackState(Mutex::new(backing_track)))
+        .manage(FileLoadState(Mutex::new(None)))
+        .manage(TrackSeekState(Mutex::new(track_seek)))
         .manage(should_reset_beat_state)
         .manage(log_state)
+        .manage(device_manager_state)
+        .manage(sample_rate_state)
+        .manage(latency_probe_state)
+        .manage(HitState(Mutex::new(VecDeque::new())))
+        .manage(timing_state)
+        .manage(recording_sink_state)
+        .manage(RecordingState(Mutex::new(None)))
+        .manage(kit_state)
+        .setup(move |app| {
+            let app_handle = app.handle();
+            std::thread::spawn(move || forward_hits(app_handle, hits, input_beat));
+            Ok(())
+        })
         .invoke_handler(tauri::generate_handler![
             get_samples,
             set_config,
+            get_settings,
+            parse_rhythm,
+            list_presets,
+            save_preset,
+            load_preset,
+            delete_preset,
             reset_beat,
             set_mp3_buffer,
+            get_backing_track,
+            clear_backing_track,
+            cancel_file_load,
+            seek_backing_track,
+            supported_formats,
+            list_audio_devices,
+            select_audio_devices,
+            set_stream_settings,
+            measure_latency,
+            get_measured_latency,
+            get_hits,
+            get_timing_stats,
+            start_recording,
+            stop_recording,
+            export_loop,
+            load_loop,
+            set_looper_mode,
+            set_overdub_feedback,
+            clear_loop,
+            undo_overdub,
+            get_looper_status,
         ])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .build(tauri::generate_context!())
+        .expect("error while building tauri application")
+        .run(|app_handle, event| {
+            // managed state is never dropped, so settings still waiting are written here
+            if let tauri::RunEvent::Exit = event {
+                let settings: tauri::State<SettingsState> = app_handle.state();
+                settings.0.lock().unwrap().flush();
+            }
+        });
 
     println!("next line after tauri builder");
 
-    Ok(())
+    device_manager.stop()
 }
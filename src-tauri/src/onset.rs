@@ -0,0 +1,186 @@
+//! Real-time onset detection on the input. The signal is cut into short hops;
+//! a hop is an onset when its level rises above the previous ones by more than
+//! an adaptive threshold that follows how much the level usually moves, so
+//! steady noise and sustained notes do not trigger it.
+
+use crate::types::S;
+use serde::Serialize;
+
+/// Frames per analysis hop, about 1.5 ms at 44.1 kHz.
+pub const ONSET_HOP: usize = 64;
+/// Rise in dB over the running average that always counts as an onset candidate.
+const MIN_RISE_DB: f32 = 6.0;
+/// How many deviations above the average rise an onset has to be.
+const DEVIATIONS: f32 = 3.0;
+/// Hops quieter than this never start an onset.
+const MIN_LEVEL_DB: f32 = -50.0;
+/// Time constant of the running average of level changes.
+const AVERAGE_SECONDS: f64 = 0.5;
+/// Shortest time between two onsets, so one hit's decay is not another hit.
+const REFRACTORY_SECONDS: f64 = 0.05;
+
+/// A detected hit on the input.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct Hit {
+    /// Beat at which the hit sounded, compensated by `Config::buffer_compensation`.
+    pub beat: f64,
+    /// Peak amplitude of the hop the hit was detected in.
+    pub strength: f32,
+    /// Beat resets before the hit, see `InputBeat::generation`.
+    pub generation: usize,
+}
+
+/// How far the onset detector has got, see `EngineControls::input_beat`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct InputBeat {
+    pub beat: f64,
+    /// Beat resets so far. Beats only compare within a generation, as a reset
+    /// takes effect on the audio thread some time after it was asked for.
+    pub generation: usize,
+}
+
+pub struct OnsetDetector {
+    energy: f32,
+    peak: f32,
+    frames: usize,
+    /// Levels of the two previous hops, as an attack can straddle a hop boundary.
+    levels: [f32; 2],
+    rise_mean: f32,
+    rise_deviation: f32,
+    smoothing: f32,
+    hops_since_onset: usize,
+    refractory_hops: usize,
+}
+
+impl OnsetDetector {
+    pub fn new(sample_rate: f64) -> OnsetDetector {
+        let hops_per_second = sample_rate / ONSET_HOP as f64;
+        OnsetDetector {
+            energy: 0.0,
+            peak: 0.0,
+            frames: 0,
+            // full scale, so whatever the input starts with is not a rise
+            levels: [0.0; 2],
+            rise_mean: 0.0,
+            rise_deviation: 0.0,
+            smoothing: (1.0 / (AVERAGE_SECONDS * hops_per_second)) as f32,
+            hops_since_onset: usize::MAX,
+            refractory_hops: (REFRACTORY_SECONDS * hops_per_second).ceil() as usize,
+        }
+    }
+
+    /// Feeds one frame. At the end of a hop that starts an onset, returns the
+    /// hop's peak amplitude; the onset happened `ONSET_HOP` frames ago.
+    pub fn process(&mut self, value: S) -> Option<f32> {
+        self.energy += value * value;
+        self.peak = self.peak.max(value.abs());
+        self.frames += 1;
+        if self.frames < ONSET_HOP {
+            return None;
+        }
+        let level = 10.0 * (self.energy / ONSET_HOP as f32 + 1e-10).log10();
+        let peak = self.peak;
+        self.energy = 0.0;
+        self.peak = 0.0;
+        self.frames = 0;
+
+        let rise = (level - self.levels[0].min(self.levels[1])).max(0.0);
+        self.levels = [self.levels[1], level];
+        let threshold = self.rise_mean + DEVIATIONS * self.rise_deviation + MIN_RISE_DB;
+        self.rise_mean += self.smoothing * (rise - self.rise_mean);
+        self.rise_deviation +=
+            self.smoothing * ((rise - self.rise_mean).abs() - self.rise_deviation);
+        self.hops_since_onset = self.hops_since_onset.saturating_add(1);
+
+        if rise > threshold && level > MIN_LEVEL_DB && self.hops_since_onset > self.refractory_hops
+        {
+            self.hops_since_onset = 0;
+            Some(peak)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    const RATE: f64 = 44100.0;
+
+    /// `seconds` of quiet noise, about -46 dB.
+    fn noise(seconds: f64) -> Vec<S> {
+        let mut rng = StdRng::seed_from_u64(1);
+        (0..(seconds * RATE) as usize)
+            .map(|_| (rng.gen::<f32>() * 2.0 - 1.0) * 0.01)
+            .collect()
+    }
+
+    /// Adds a hit decaying over about 10 ms at each of `frames`.
+    fn add_hits(input: &mut [S], frames: &[usize]) {
+        for start in frames {
+            for (k, sample) in input[*start..].iter_mut().take(2000).enumerate() {
+                let decay = (-(k as f32) / 441.0).exp();
+                *sample += 0.8 * decay * if k % 2 == 0 { 1.0 } else { -1.0 };
+            }
+        }
+    }
+
+    /// The frames at which hits were detected, each `ONSET_HOP` after its hop began.
+    fn detect(input: &[S]) -> Vec<usize> {
+        let mut detector = OnsetDetector::new(RATE);
+        input
+            .iter()
+            .enumerate()
+            .filter_map(|(i, value)| detector.process(*value).map(|_| i))
+            .collect()
+    }
+
+    #[test]
+    fn finds_one_hit_per_impulse() {
+        let mut input = noise(3.0);
+        let hits = [22050, 44100, 66150, 88200, 110250];
+        add_hits(&mut input, &hits);
+        let detected = detect(&input);
+        assert_eq!(detected.len(), hits.len(), "{:?}", detected);
+        for (frame, hit) in detected.iter().zip(hits.iter()) {
+            // reported at the end of the hop the hit started in, or the next
+            assert!(
+                *frame >= *hit && *frame < hit + 2 * ONSET_HOP,
+                "{} {}",
+                frame,
+                hit
+            );
+        }
+    }
+
+    #[test]
+    fn ignores_steady_noise() {
+        assert!(detect(&noise(3.0)).is_empty());
+        // nor does louder noise once it has settled
+        let loud: Vec<S> = noise(3.0).iter().map(|s| s * 30.0).collect();
+        assert!(detect(&loud).iter().all(|frame| *frame < ONSET_HOP * 2));
+    }
+
+    #[test]
+    fn waits_out_the_refractory_time() {
+        let mut input = noise(2.0);
+        // 20 ms apart, then 100 ms apart
+        add_hits(&mut input, &[22050, 22050 + 882, 66150, 66150 + 4410]);
+        let detected = detect(&input);
+        assert_eq!(detected.len(), 3, "{:?}", detected);
+        assert!(detected[0] < 22050 + 882);
+        assert!(detected[1] >= 66150 && detected[2] >= 66150 + 4410);
+    }
+
+    #[test]
+    fn reports_the_peak_of_the_hop() {
+        let mut input = vec![0.0; 4096];
+        input[1000] = 0.5;
+        input[1001] = -0.7;
+        let mut detector = OnsetDetector::new(RATE);
+        let strengths: Vec<f32> = input.iter().filter_map(|v| detector.process(*v)).collect();
+        assert_eq!(strengths, vec![0.7]);
+    }
+}
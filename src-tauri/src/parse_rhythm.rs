@@ -0,0 +1,521 @@
+//! Parses the rhythm notation of `src/parser2.peg` into a `ParserRhythm`, so
+//! rhythms can be given as text wherever the frontend is not there to parse
+//! them. Notes are separated by commas and their lengths are arithmetic on
+//! numbers (`1/2`, `(1+1)/3`):
+//!
+//! - `n:d` is `n` notes sharing a length of `d`
+//! - `[...]:x` squishes the notes inside into a length of `x`
+//! - a drum prefix (`k`, `s`, `h`, `r`, combined like `ks` or `k+h`) picks the sounds
+//! - `r` after a note makes it a rest
+//! - `>x` after a note moves it by `x` without moving the ones after it
+//!
+//! Notes moved past either end wrap around. Errors point at the text they are about.
+
+use crate::structs::{Note, ParserRhythm};
+use std::cmp::Ordering;
+use std::fmt;
+use std::ops::Range;
+
+/// A range of this many notes is assumed to be a typo.
+const MAX_RANGE_NOTES: usize = 4096;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseError {
+    pub message: String,
+    /// Byte offsets into the text.
+    pub span: Range<usize>,
+}
+
+impl ParseError {
+    /// The message followed by the line of `text` it is about, underlined.
+    pub fn describe(&self, text: &str) -> String {
+        let line_start = text[..self.span.start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = text[self.span.start..]
+            .find('\n')
+            .map_or(text.len(), |i| self.span.start + i);
+        let column = text[line_start..self.span.start].chars().count();
+        let width = text[self.span.start..self.span.end.min(line_end)]
+            .chars()
+            .count()
+            .max(1);
+        format!(
+            "{}\n  {}\n  {}{}",
+            self.message,
+            &text[line_start..line_end],
+            " ".repeat(column),
+            "^".repeat(width)
+        )
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} (at {}..{})",
+            self.message, self.span.start, self.span.end
+        )
+    }
+}
+
+pub fn parse_rhythm(text: &str) -> Result<ParserRhythm, ParseError> {
+    let mut parser = Parser { text, pos: 0 };
+    parser.skip_whitespace();
+    let notes = parser.list()?;
+    parser.skip_whitespace();
+    if parser.pos < text.len() {
+        return Err(parser.unexpected("`,` or the end of the rhythm"));
+    }
+
+    let mut time = 0.0;
+    let mut placed = Vec::with_capacity(notes.len());
+    for note in notes {
+        placed.push(Note {
+            sounds: note.sounds,
+            rest: note.rest,
+            ..Note::at(time + note.offset)
+        });
+        time += note.time;
+    }
+    let end = time;
+    if !end.is_finite() || end <= 0.0 {
+        return Err(ParseError {
+            message: "the rhythm has no length".to_string(),
+            span: 0..text.len(),
+        });
+    }
+    for note in placed.iter_mut() {
+        note.time = note.time.rem_euclid(end);
+    }
+    // NaN comes from a note moved too far to place, and would not sort
+    if placed.iter().any(|note| note.time.is_nan()) {
+        return Err(ParseError {
+            message: "a note is moved too far to place".to_string(),
+            span: 0..text.len(),
+        });
+    }
+    placed.sort_by(|a, b| a.time.partial_cmp(&b.time).unwrap_or(Ordering::Equal));
+    Ok(ParserRhythm {
+        notes: placed,
+        start: 0.0,
+        end,
+    })
+}
+
+/// A note before it is placed: its length and how far it is moved.
+#[derive(Clone, Debug)]
+struct ParsedNote {
+    time: f64,
+    offset: f64,
+    sounds: Vec<String>,
+    rest: bool,
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<char> {
+        self.text[self.pos..].chars().next()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(c) = self.peek().filter(|c| c.is_whitespace()) {
+            self.pos += c.len_utf8();
+        }
+    }
+
+    /// Takes `c` if it comes next, after any whitespace.
+    fn eat(&mut self, c: char) -> bool {
+        let start = self.pos;
+        self.skip_whitespace();
+        if self.peek() == Some(c) {
+            self.pos += c.len_utf8();
+            true
+        } else {
+            self.pos = start;
+            false
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), ParseError> {
+        if self.eat(c) {
+            Ok(())
+        } else {
+            self.skip_whitespace();
+            Err(self.unexpected(&format!("`{}`", c)))
+        }
+    }
+
+    fn unexpected(&self, expected: &str) -> ParseError {
+        match self.peek() {
+            Some(c) => ParseError {
+                message: format!("expected {}, found `{}`", expected, c),
+                span: self.pos..self.pos + c.len_utf8(),
+            },
+            None => ParseError {
+                message: format!("expected {}, found the end of the rhythm", expected),
+                span: self.pos..self.pos,
+            },
+        }
+    }
+
+    fn error(&self, message: &str, span: Range<usize>) -> ParseError {
+        ParseError {
+            message: message.to_string(),
+            span,
+        }
+    }
+
+    /// Items separated by commas, with an optional trailing comma.
+    fn list(&mut self) -> Result<Vec<ParsedNote>, ParseError> {
+        let mut notes = self.item()?;
+        while self.eat(',') {
+            self.skip_whitespace();
+            if matches!(self.peek(), None | Some(']')) {
+                break;
+            }
+            notes.extend(self.item()?);
+        }
+        Ok(notes)
+    }
+
+    fn item(&mut self) -> Result<Vec<ParsedNote>, ParseError> {
+        self.skip_whitespace();
+        let start = self.pos;
+        if self.eat('[') {
+            let notes = self.list()?;
+            self.expect(']')?;
+            let group = start..self.pos;
+            if !self.eat(':') {
+                return Ok(notes);
+            }
+            let length = self.expression()?;
+            let total: f64 = notes.iter().map(|note| note.time).sum();
+            if total == 0.0 {
+                return Err(self.error("a squished group has no length", group));
+            }
+            let scale = length / total;
+            return Ok(notes
+                .into_iter()
+                .map(|note| ParsedNote {
+                    time: note.time * scale,
+                    offset: note.offset * scale,
+                    ..note
+                })
+                .collect());
+        }
+
+        let sounds = self.drum_sounds()?;
+        let value_start = self.pos;
+        let value = self.expression()?;
+        let span = value_start..self.pos;
+        if sounds.is_empty() && self.eat(':') {
+            let length = self.expression()?;
+            if value < 0.0 || value.fract() != 0.0 {
+                return Err(self.error("the count of a range must be a whole number", span));
+            }
+            if value > MAX_RANGE_NOTES as f64 {
+                let message = format!("a range can have at most {} notes", MAX_RANGE_NOTES);
+                return Err(self.error(&message, span));
+            }
+            let count = value as usize;
+            let note = ParsedNote {
+                time: length / value,
+                offset: 0.0,
+                sounds: vec![],
+                rest: false,
+            };
+            return Ok(vec![note; count]);
+        }
+
+        let mut note = ParsedNote {
+            time: value,
+            offset: 0.0,
+            sounds,
+            rest: false,
+        };
+        loop {
+            if self.eat('r') {
+                note.rest = true;
+            } else if self.eat('>') {
+                note.offset = self.expression()?;
+            } else {
+                return Ok(vec![note]);
+            }
+        }
+    }
+
+    /// Reads a drum prefix if there is one, as its letters.
+    fn drum_sounds(&mut self) -> Result<Vec<String>, ParseError> {
+        self.skip_whitespace();
+        let mut sounds = vec![];
+        while let Some(c) = self.peek().filter(|c| "kshr".contains(*c)) {
+            self.pos += c.len_utf8();
+            sounds.push(c.to_string());
+        }
+        if sounds.is_empty() {
+            return Ok(sounds);
+        }
+        while self.eat('+') {
+            self.skip_whitespace();
+            match self.peek().filter(|c| "kshr".contains(*c)) {
+                Some(c) => {
+                    self.pos += c.len_utf8();
+                    sounds.push(c.to_string());
+                }
+                None => return Err(self.unexpected("a drum sound (`k`, `s`, `h` or `r`)")),
+            }
+        }
+        Ok(sounds)
+    }
+
+    fn expression(&mut self) -> Result<f64, ParseError> {
+        let mut value = self.term()?;
+        loop {
+            if self.eat('+') {
+                value += self.term()?;
+            } else if self.eat('-') {
+                value -= self.term()?;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn term(&mut self) -> Result<f64, ParseError> {
+        let mut value = self.factor()?;
+        loop {
+            if self.eat('*') {
+                value *= self.factor()?;
+            } else if self.eat('/') {
+                self.skip_whitespace();
+                let start = self.pos;
+                let divisor = self.factor()?;
+                if divisor == 0.0 {
+                    return Err(self.error("division by zero", start..self.pos));
+                }
+                value /= divisor;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn factor(&mut self) -> Result<f64, ParseError> {
+        if self.eat('(') {
+            let value = self.expression()?;
+            self.expect(')')?;
+            return Ok(value);
+        }
+        self.number()
+    }
+
+    /// An integer or decimal, optionally negative: `3`, `-1`, `0.5`, `.25`.
+    fn number(&mut self) -> Result<f64, ParseError> {
+        self.skip_whitespace();
+        let start = self.pos;
+        let rest = &self.text[start..];
+        let mut end = usize::from(rest.starts_with('-'));
+        let digits = |from: usize| {
+            rest[from..]
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(rest.len() - from)
+        };
+        let whole = digits(end);
+        end += whole;
+        if rest[end..].starts_with('.') && digits(end + 1) > 0 {
+            end += 1 + digits(end + 1);
+        } else if whole == 0 {
+            return Err(self.unexpected("a number"));
+        }
+        self.pos += end;
+        self.text[start..self.pos]
+            .parse::<f64>()
+            .map_err(|e| self.error(&e.to_string(), start..self.pos))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Compares with what `src/parser2.js` gives for `text`: each note's time,
+    /// sounds and whether it rests, and the end.
+    fn assert_parses(text: &str, expected: &[(f64, &[&str], bool)], end: f64) {
+        let rhythm = parse_rhythm(text).unwrap();
+        assert!(
+            (rhythm.end - end).abs() < 1e-9,
+            "{}: end {}",
+            text,
+            rhythm.end
+        );
+        assert_eq!(
+            rhythm.notes.len(),
+            expected.len(),
+            "{}: {:?}",
+            text,
+            rhythm.notes
+        );
+        for (note, (time, sounds, rest)) in rhythm.notes.iter().zip(expected) {
+            assert!(
+                (note.time - time).abs() < 1e-9,
+                "{}: {:?}",
+                text,
+                rhythm.notes
+            );
+            assert_eq!(&note.sounds, sounds, "{}", text);
+            assert_eq!(note.rest, *rest, "{}", text);
+        }
+    }
+
+    fn assert_error(text: &str, message: &str, span: Range<usize>) {
+        let err = parse_rhythm(text).unwrap_err();
+        assert_eq!(err.message, message, "{}", text);
+        assert_eq!(err.span, span, "{}", text);
+    }
+
+    #[test]
+    fn parses_ranges() {
+        assert_parses(
+            "4:1",
+            &[
+                (0.0, &[], false),
+                (0.25, &[], false),
+                (0.5, &[], false),
+                (0.75, &[], false),
+            ],
+            1.0,
+        );
+        assert_parses(
+            "2:1, 1",
+            &[(0.0, &[], false), (0.5, &[], false), (1.0, &[], false)],
+            2.0,
+        );
+        assert_parses(
+            "3:1",
+            &[
+                (0.0, &[], false),
+                (1.0 / 3.0, &[], false),
+                (2.0 / 3.0, &[], false),
+            ],
+            1.0,
+        );
+    }
+
+    #[test]
+    fn parses_squished_groups() {
+        assert_parses("[1, 1]:1", &[(0.0, &[], false), (0.5, &[], false)], 1.0);
+        assert_parses(
+            "[1, 2, 1]:2, 2",
+            &[
+                (0.0, &[], false),
+                (0.5, &[], false),
+                (1.5, &[], false),
+                (2.0, &[], false),
+            ],
+            4.0,
+        );
+        assert_parses(
+            "[k1, s1]:1, 2:1",
+            &[
+                (0.0, &["k"], false),
+                (0.5, &["s"], false),
+                (1.0, &[], false),
+                (1.5, &[], false),
+            ],
+            2.0,
+        );
+    }
+
+    #[test]
+    fn parses_offsets() {
+        assert_parses(
+            "1, 1>0.25, 1",
+            &[(0.0, &[], false), (1.25, &[], false), (2.0, &[], false)],
+            3.0,
+        );
+        // wraps around to the end
+        assert_parses("1>-0.5, 1", &[(1.0, &[], false), (1.5, &[], false)], 2.0);
+        // squished with the note
+        assert_parses(
+            "[1>0.5, 1]:1",
+            &[(0.25, &[], false), (0.5, &[], false)],
+            1.0,
+        );
+    }
+
+    #[test]
+    fn parses_rests() {
+        assert_parses(
+            "1, 1r, 1",
+            &[(0.0, &[], false), (1.0, &[], true), (2.0, &[], false)],
+            3.0,
+        );
+        assert_parses("k1r", &[(0.0, &["k"], true)], 1.0);
+        assert_parses(
+            "1 r > 0.25, 1",
+            &[(0.25, &[], true), (1.0, &[], false)],
+            2.0,
+        );
+    }
+
+    #[test]
+    fn parses_sound_letters() {
+        assert_parses(
+            "k1, s1/2, h1/2",
+            &[
+                (0.0, &["k"], false),
+                (1.0, &["s"], false),
+                (1.5, &["h"], false),
+            ],
+            2.0,
+        );
+        assert_parses(
+            "ks1, k+h1",
+            &[(0.0, &["k", "s"], false), (1.0, &["k", "h"], false)],
+            2.0,
+        );
+    }
+
+    #[test]
+    fn parses_arithmetic() {
+        assert_parses(
+            "1/2, (1+1)/4, .5",
+            &[(0.0, &[], false), (0.5, &[], false), (1.0, &[], false)],
+            1.5,
+        );
+    }
+
+    #[test]
+    fn points_errors_at_their_text() {
+        assert_error("1, x", "expected a number, found `x`", 3..4);
+        assert_error(
+            "1 1",
+            "expected `,` or the end of the rhythm, found `1`",
+            2..3,
+        );
+        assert_error("[1, 1", "expected `]`, found the end of the rhythm", 5..5);
+        assert_error("1/0", "division by zero", 2..3);
+        assert_error("1.5:1", "the count of a range must be a whole number", 0..3);
+        assert_error("5000:1", "a range can have at most 4096 notes", 0..4);
+        assert_error(
+            "k+x1",
+            "expected a drum sound (`k`, `s`, `h` or `r`), found `x`",
+            2..3,
+        );
+        assert_error("1, [0]:1", "a squished group has no length", 3..6);
+        assert_error("0", "the rhythm has no length", 0..1);
+        let far = format!("1, 1>1{}", "0".repeat(400));
+        assert_error(&far, "a note is moved too far to place", 0..far.len());
+    }
+
+    #[test]
+    fn underlines_the_error() {
+        let text = "1,\n  1/0";
+        let err = parse_rhythm(text).unwrap_err();
+        assert_eq!(err.describe(text), "division by zero\n    1/0\n      ^");
+    }
+}
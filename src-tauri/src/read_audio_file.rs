@@ -1,133 +1,285 @@
-use std::time::Instant;
+use crate::constants::DEFAULT_SAMPLE_RATE;
 
+use serde::Serialize;
+use std::path::Path;
 use symphonia::core::audio::SampleBuffer;
-use symphonia::core::codecs::{DecoderOptions, CODEC_TYPE_NULL};
+use symphonia::core::codecs::{Decoder, DecoderOptions, CODEC_TYPE_NULL};
 use symphonia::core::errors::Error;
-use symphonia::core::formats::FormatOptions;
+use symphonia::core::formats::{FormatOptions, FormatReader, SeekMode, SeekTo};
 use symphonia::core::io::MediaSourceStream;
 use symphonia::core::meta::MetadataOptions;
 use symphonia::core::probe::Hint;
 
-pub fn get_samples_from_filename(filename: &String) -> Result<Vec<f32>, String> {
-    let src_result = std::fs::File::open(&filename);
-    if let Err(e) = src_result {
-        return Err(format!("Failed to open file: {}", e));
+/// Decoded audio as it came out of the file.
+#[derive(Clone, Debug)]
+pub struct AudioBuffer {
+    /// Interleaved samples.
+    pub samples: Vec<f32>,
+    pub sample_rate: f64,
+    pub channels: usize,
+}
+
+impl AudioBuffer {
+    pub fn frames(&self) -> usize {
+        self.samples.len().checked_div(self.channels).unwrap_or(0)
     }
-    let src = src_result.unwrap();
+}
 
-    // Create the media source stream.
-    let mss = MediaSourceStream::new(Box::new(src), Default::default());
+/// A kind of file the enabled symphonia features decode, in the shape the
+/// frontend's file dialog takes as a filter.
+#[derive(Clone, Debug, Serialize)]
+pub struct AudioFormat {
+    pub name: &'static str,
+    pub extensions: &'static [&'static str],
+}
 
-    // Create a probe hint using the file's extension. [Optional]
-    let mut hint = Hint::new();
-    hint.with_extension("mp3");
+/// Kept in step with the symphonia features in `Cargo.toml`.
+pub const SUPPORTED_FORMATS: &[AudioFormat] = &[
+    AudioFormat {
+        name: "MP3",
+        extensions: &["mp3"],
+    },
+    AudioFormat {
+        name: "WAV",
+        extensions: &["wav", "wave"],
+    },
+    AudioFormat {
+        name: "FLAC",
+        extensions: &["flac"],
+    },
+    AudioFormat {
+        name: "Ogg Vorbis",
+        extensions: &["ogg", "oga"],
+    },
+    AudioFormat {
+        name: "AAC",
+        extensions: &["m4a", "mp4", "aac"],
+    },
+];
 
-    // Use the default options for metadata and format readers.
-    let meta_opts: MetadataOptions = Default::default();
-    let fmt_opts: FormatOptions = Default::default();
+pub fn decode_audio_file(filename: &str) -> Result<AudioBuffer, String> {
+    decode_audio_file_with_progress(filename, &mut |_| true)
+}
 
-    // Probe the media source.
-    let probed_result = symphonia::default::get_probe().format(&hint, mss, &fmt_opts, &meta_opts);
-    if let Err(_err) = probed_result {
-        return Err("unsupported format".to_string());
-    }
-    let probed = probed_result.unwrap();
-
-    // Get the instantiated format reader.
-    let mut format = probed.format;
-
-    // Find the first audio track with a known (decodeable) codec.
-    let track_result = format
-        .tracks()
-        .iter()
-        .find(|t| t.codec_params.codec != CODEC_TYPE_NULL);
-    if let None = track_result {
-        return Err("no audio track found".to_string());
-    }
-    let track = track_result.unwrap();
+/// Like `decode_audio_file`, calling `progress` after every packet with the
+/// fraction decoded so far, `None` if the file does not tell its length.
+/// Decoding stops with an error when `progress` returns false.
+pub fn decode_audio_file_with_progress(
+    filename: &str,
+    progress: &mut dyn FnMut(Option<f64>) -> bool,
+) -> Result<AudioBuffer, String> {
+    AudioFileReader::open(filename)?.read_to_end(progress)
+}
 
-    // Use the default options for the decoder.
-    let dec_opts: DecoderOptions = Default::default();
+/// An open audio file, decoded a packet at a time so long files can be
+/// played without holding all of them in memory.
+pub struct AudioFileReader {
+    format: Box<dyn FormatReader>,
+    decoder: Box<dyn Decoder>,
+    track_id: u32,
+    pub sample_rate: f64,
+    pub channels: usize,
+    /// Length in frames, if the file tells it.
+    pub frames: Option<u64>,
+    /// Where the packets read so far end, in frames.
+    position: u64,
+    /// Frames still to drop after seeking to the packet before the one asked for.
+    skip: u64,
+}
 
-    // Create a decoder for the track.
-    let decoder_result = symphonia::default::get_codecs().make(&track.codec_params, &dec_opts);
-    if let Err(_err) = decoder_result {
-        return Err("unsupported codec".to_string());
-    }
-    let mut decoder = decoder_result.unwrap();
-
-    // Store the track identifier, it will be used to filter packets.
-    let track_id = track.id;
-
-    let mut sample_data: Vec<f32> = Vec::new();
-
-    // The decode loop.
-    loop {
-        // Get the next packet from the media format.
-        let packet = match format.next_packet() {
-            Ok(packet) => packet,
-            Err(Error::ResetRequired) => {
-                // The track list has been changed. Re-examine it and create a new set of decoders,
-                // then restart the decode loop. This is an advanced feature and it is not
-                // unreasonable to consider this "the end." As of v0.5.0, the only usage of this is
-                // for chained OGG physical streams.
-                unimplemented!();
-            }
-            Err(err) => {
-                if err.to_string() == "end of stream" {
-                    return Ok(sample_data);
-                } else {
-                    println!(
-                        "there was an error while reading an audio file: {:?}",
-                        err.to_string()
-                    );
-                    return Err(err.to_string());
-                }
-            }
-        };
+impl AudioFileReader {
+    pub fn open(filename: &str) -> Result<AudioFileReader, String> {
+        let src_result = std::fs::File::open(filename);
+        if let Err(e) = src_result {
+            return Err(format!("Failed to open file: {}", e));
+        }
+        let src = src_result.unwrap();
 
-        // Consume any new metadata that has been read since the last packet.
-        while !format.metadata().is_latest() {
-            // Pop the old head of the metadata queue.
-            format.metadata().pop();
+        // Create the media source stream.
+        let mss = MediaSourceStream::new(Box::new(src), Default::default());
 
-            // Consume the new metadata at the head of the metadata queue.
+        // Create a probe hint using the file's extension. [Optional]
+        let mut hint = Hint::new();
+        if let Some(extension) = Path::new(filename).extension().and_then(|e| e.to_str()) {
+            hint.with_extension(extension);
         }
 
-        // If the packet does not belong to the selected track, skip over it.
-        if packet.track_id() != track_id {
-            continue;
+        // Use the default options for metadata and format readers.
+        let meta_opts: MetadataOptions = Default::default();
+        let fmt_opts: FormatOptions = Default::default();
+
+        // Probe the media source.
+        let probed_result =
+            symphonia::default::get_probe().format(&hint, mss, &fmt_opts, &meta_opts);
+        if let Err(_err) = probed_result {
+            return Err(format!("unsupported format: {}", filename));
         }
+        let probed = probed_result.unwrap();
+
+        // Get the instantiated format reader.
+        let format = probed.format;
+
+        // Find the first audio track with a known (decodeable) codec.
+        let track = format
+            .tracks()
+            .iter()
+            .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
+            .ok_or_else(|| "no audio track found".to_string())?;
+
+        // Use the default options for the decoder.
+        let dec_opts: DecoderOptions = Default::default();
+
+        // Create a decoder for the track.
+        let decoder_result = symphonia::default::get_codecs().make(&track.codec_params, &dec_opts);
+        if let Err(_err) = decoder_result {
+            return Err("unsupported codec".to_string());
+        }
+        let decoder = decoder_result.unwrap();
+
+        // Store the track identifier, it will be used to filter packets.
+        let track_id = track.id;
+        let frames = track.codec_params.n_frames.filter(|&n| n > 0);
+        let sample_rate = track
+            .codec_params
+            .sample_rate
+            .map(|rate| rate as f64)
+            .unwrap_or(DEFAULT_SAMPLE_RATE);
+        let channels = track
+            .codec_params
+            .channels
+            .map(|channels| channels.count())
+            .unwrap_or(2);
 
-        // Decode the packet into audio samples.
-        match decoder.decode(&packet) {
-            Ok(_decoded) => {
-                // Consume the decoded audio samples (see below).
-                if _decoded.frames() > 0 {
-                    let spec = *_decoded.spec();
-                    let mut samples: SampleBuffer<f32> =
-                        SampleBuffer::new(_decoded.frames() as u64, spec);
-                    samples.copy_interleaved_ref(_decoded);
-
-                    let new_sample_data: Vec<f32> = samples.samples().iter().map(|s| *s).collect();
-                    for i in 0..new_sample_data.len() {
-                        sample_data.push(new_sample_data[i]);
+        Ok(AudioFileReader {
+            format,
+            decoder,
+            track_id,
+            sample_rate,
+            channels,
+            frames,
+            position: 0,
+            skip: 0,
+        })
+    }
+
+    /// The fraction of the file read so far, `None` if its length is not known.
+    pub fn progress(&self) -> Option<f64> {
+        self.frames
+            .map(|n| (self.position as f64 / n as f64).min(1.0))
+    }
+
+    /// Decodes the next packet, `None` at the end of the file. Packets that
+    /// fail to decode come back empty.
+    pub fn next_chunk(&mut self) -> Result<Option<AudioBuffer>, String> {
+        loop {
+            // Get the next packet from the media format.
+            let packet = match self.format.next_packet() {
+                Ok(packet) => packet,
+                Err(Error::ResetRequired) => {
+                    // The track list has been changed. Re-examine it and create a new set of decoders,
+                    // then restart the decode loop. This is an advanced feature and it is not
+                    // unreasonable to consider this "the end." As of v0.5.0, the only usage of this is
+                    // for chained OGG physical streams.
+                    return Ok(None);
+                }
+                Err(err) => {
+                    if err.to_string() == "end of stream" {
+                        return Ok(None);
+                    } else {
+                        println!(
+                            "there was an error while reading an audio file: {:?}",
+                            err.to_string()
+                        );
+                        return Err(err.to_string());
                     }
-                    // println!("{:?}", sample_data);
                 }
+            };
+
+            // Consume any new metadata that has been read since the last packet.
+            while !self.format.metadata().is_latest() {
+                // Pop the old head of the metadata queue.
+                self.format.metadata().pop();
+
+                // Consume the new metadata at the head of the metadata queue.
             }
-            Err(Error::IoError(_)) => {
-                // The packet failed to decode due to an IO error, skip the packet.
+
+            // If the packet does not belong to the selected track, skip over it.
+            if packet.track_id() != self.track_id {
                 continue;
             }
-            Err(Error::DecodeError(_)) => {
-                // The packet failed to decode due to invalid data, skip the packet.
-                continue;
+            self.position = packet.ts() + packet.dur();
+
+            let mut chunk = AudioBuffer {
+                samples: vec![],
+                sample_rate: self.sample_rate,
+                channels: self.channels,
+            };
+            // Decode the packet into audio samples.
+            match self.decoder.decode(&packet) {
+                Ok(decoded) => {
+                    let frames = decoded.frames();
+                    if frames > 0 {
+                        let spec = *decoded.spec();
+                        self.channels = spec.channels.count();
+                        let mut samples: SampleBuffer<f32> = SampleBuffer::new(frames as u64, spec);
+                        samples.copy_interleaved_ref(decoded);
+
+                        let skip = (self.skip as usize).min(frames);
+                        self.skip -= skip as u64;
+                        chunk.channels = self.channels;
+                        chunk.samples = samples.samples()[skip * self.channels..].to_vec();
+                    }
+                }
+                Err(Error::IoError(_)) => {
+                    // The packet failed to decode due to an IO error, skip the packet.
+                }
+                Err(Error::DecodeError(_)) => {
+                    // The packet failed to decode due to invalid data, skip the packet.
+                }
+                Err(err) => {
+                    // An unrecoverable error occured, halt decoding.
+                    return Err(err.to_string());
+                }
             }
-            Err(err) => {
-                // An unrecoverable error occured, halt decoding.
-                panic!("{}", err);
+            return Ok(Some(chunk));
+        }
+    }
+
+    /// Moves to `frame`, so the next chunk starts exactly there.
+    pub fn seek(&mut self, frame: u64) -> Result<(), String> {
+        let seeked = self
+            .format
+            .seek(
+                SeekMode::Accurate,
+                SeekTo::TimeStamp {
+                    ts: frame,
+                    track_id: self.track_id,
+                },
+            )
+            .map_err(|e| format!("could not seek: {}", e))?;
+        self.decoder.reset();
+        self.position = seeked.actual_ts;
+        self.skip = seeked.required_ts.saturating_sub(seeked.actual_ts);
+        Ok(())
+    }
+
+    /// Decodes the rest of the file, calling `progress` as in
+    /// `decode_audio_file_with_progress`.
+    pub fn read_to_end(
+        mut self,
+        progress: &mut dyn FnMut(Option<f64>) -> bool,
+    ) -> Result<AudioBuffer, String> {
+        let mut sample_data: Vec<f32> = Vec::new();
+        while let Some(chunk) = self.next_chunk()? {
+            if !progress(self.progress()) {
+                return Err("cancelled".to_string());
             }
+            sample_data.extend_from_slice(&chunk.samples);
         }
+        Ok(AudioBuffer {
+            samples: sample_data,
+            sample_rate: self.sample_rate,
+            channels: self.channels,
+        })
     }
 }
@@ -0,0 +1,104 @@
+//! Wait-free hand-off between the Tauri command threads and the audio thread.
+//! The audio thread never takes a lock: values travel through SPSC ring buffers
+//! and triple buffers, and anything it replaces is sent back so it is dropped
+//! on the sending side.
+
+use rtrb::{Consumer, Producer, RingBuffer};
+use triple_buffer::{Input, Output, TripleBuffer};
+
+/// Number of replacements that can be in flight before `SwapSender::send` refuses more.
+const SWAP_CAPACITY: usize = 4;
+
+/// Sends values built off the audio thread (e.g. a resized loop buffer) to it.
+pub struct SwapSender<T> {
+    to_audio: Producer<T>,
+    from_audio: Consumer<T>,
+}
+
+/// The audio thread's end of a `SwapSender`.
+pub struct SwapReceiver<T> {
+    to_audio: Consumer<T>,
+    from_audio: Producer<T>,
+}
+
+pub fn swap_channel<T>() -> (SwapSender<T>, SwapReceiver<T>) {
+    let (to_audio_producer, to_audio_consumer) = RingBuffer::new(SWAP_CAPACITY);
+    let (from_audio_producer, from_audio_consumer) = RingBuffer::new(SWAP_CAPACITY);
+    (
+        SwapSender {
+            to_audio: to_audio_producer,
+            from_audio: from_audio_consumer,
+        },
+        SwapReceiver {
+            to_audio: to_audio_consumer,
+            from_audio: from_audio_producer,
+        },
+    )
+}
+
+impl<T> SwapSender<T> {
+    /// Queues `value` for the audio thread, dropping any values it has retired.
+    /// Gives the value back if the audio thread has not caught up.
+    pub fn send(&mut self, value: T) -> Result<(), T> {
+        self.collect_retired();
+        self.push(value)
+    }
+
+    /// Queues `value` like `send`, leaving retired values for `take_retired`.
+    pub fn push(&mut self, value: T) -> Result<(), T> {
+        self.to_audio.push(value).map_err(|err| match err {
+            rtrb::PushError::Full(value) => value,
+        })
+    }
+
+    pub fn collect_retired(&mut self) {
+        while self.from_audio.pop().is_ok() {}
+    }
+
+    /// Takes back a value the audio thread is done with, for senders that want
+    /// the result rather than just dropping it.
+    pub fn take_retired(&mut self) -> Option<T> {
+        self.from_audio.pop().ok()
+    }
+}
+
+impl<T> SwapReceiver<T> {
+    pub fn receive(&mut self) -> Option<T> {
+        self.to_audio.pop().ok()
+    }
+
+    /// Hands a replaced value back to the sender to be dropped there.
+    pub fn retire(&mut self, value: T) {
+        // the sender empties this queue before every send, so it cannot be full
+        let _ = self.from_audio.push(value);
+    }
+}
+
+/// The command side of a triple buffered value, keeping a copy of the latest
+/// value so it can be read without touching the audio thread's buffer.
+pub struct ValueSender<T: Clone + Send> {
+    current: T,
+    input: Input<T>,
+}
+
+pub fn value_channel<T: Clone + Send>(initial: T) -> (ValueSender<T>, Output<T>) {
+    let (input, output) = TripleBuffer::new(&initial).split();
+    (
+        ValueSender {
+            current: initial,
+            input,
+        },
+        output,
+    )
+}
+
+impl<T: Clone + Send> ValueSender<T> {
+    pub fn current(&self) -> &T {
+        &self.current
+    }
+
+    pub fn publish(&mut self, value: T) {
+        self.input.write(value.clone());
+        self.current = value;
+    }
+}
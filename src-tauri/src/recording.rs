@@ -0,0 +1,403 @@
+//! Session recording. The audio thread pushes every frame of the raw input (and
+//! optionally the output mix and the click) into a lock-free queue; a writer
+//! thread drains it into WAV files next to a CSV of beat markers.
+//!
+//! For `take.wav` the files are `take.wav` (input channels), `take.mix.wav`,
+//! `take.click.wav` and `take.beats.csv` (`frame,beat` at every whole beat the
+//! input was played on).
+
+use crate::realtime::SwapSender;
+use crate::types::S;
+use hound::{SampleFormat, WavSpec, WavWriter};
+use rtrb::{Consumer, Producer, RingBuffer};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// Seconds of audio the queue holds while the writer catches up.
+const QUEUE_SECONDS: f64 = 4.0;
+const MARKER_QUEUE_SIZE: usize = 1024;
+
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RecordingOptions {
+    /// Also record the stereo mix sent to the output channels.
+    pub mix: bool,
+    /// Also record the click on its own.
+    pub click: bool,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct RecordingSummary {
+    pub files: Vec<String>,
+    pub frames: u64,
+    pub sample_rate: f64,
+    /// Frames lost because the writer fell behind.
+    pub dropped_frames: usize,
+}
+
+#[derive(Clone, Copy)]
+struct BeatMarker {
+    frame: u64,
+    beat: f64,
+}
+
+/// The audio thread's end of a recording.
+pub struct RecordingSink {
+    samples: Producer<S>,
+    markers: Producer<BeatMarker>,
+    input_channels: usize,
+    options: RecordingOptions,
+    frame: u64,
+    /// The whole beat of the last frame queued, `None` at the start and after a gap.
+    last_beat: Option<i64>,
+    stop: Arc<AtomicBool>,
+    finished: Arc<AtomicBool>,
+    dropped_frames: Arc<AtomicUsize>,
+}
+
+/// Samples per queued frame: the input channels, then the mix pair, then the click.
+fn frame_len(input_channels: usize, options: RecordingOptions) -> usize {
+    input_channels + if options.mix { 2 } else { 0 } + options.click as usize
+}
+
+impl RecordingSink {
+    pub fn stop_requested(&self) -> bool {
+        self.stop.load(Ordering::Relaxed)
+    }
+
+    /// Marks the last frame as pushed; the writer finishes once the queue is empty.
+    pub fn finish(&self) {
+        self.finished.store(true, Ordering::Release);
+    }
+
+    /// Queues frame `i` of `input`. Input channels the device does not have are
+    /// silent. `beat` is when the input was played, compensated like `Hit::beat`.
+    /// Never allocates or blocks; a full queue drops the frame. Dropped frames
+    /// are not counted, so markers stay on the frames of the files. A beat that
+    /// starts before the recording or in a gap is not marked, unless the frame
+    /// after it is exactly on it, so every marker is on the frame of a beat.
+    pub fn push(&mut self, input: &[&[S]], i: usize, mix: [S; 2], click: S, beat: f64) {
+        if self.samples.slots() < frame_len(self.input_channels, self.options) {
+            self.dropped_frames.fetch_add(1, Ordering::Relaxed);
+            self.last_beat = None;
+            return;
+        }
+        let whole_beat = beat.floor() as i64;
+        let starts_beat = match self.last_beat {
+            Some(last_beat) => last_beat != whole_beat,
+            None => beat == beat.floor(),
+        };
+        self.last_beat = Some(whole_beat);
+        if starts_beat {
+            let _ = self.markers.push(BeatMarker {
+                frame: self.frame,
+                beat,
+            });
+        }
+        self.frame += 1;
+        for ch in 0..self.input_channels {
+            let _ = self
+                .samples
+                .push(input.get(ch).map(|c| c[i]).unwrap_or(0.0));
+        }
+        if self.options.mix {
+            let _ = self.samples.push(mix[0]);
+            let _ = self.samples.push(mix[1]);
+        }
+        if self.options.click {
+            let _ = self.samples.push(click);
+        }
+    }
+}
+
+/// The command side of a running recording.
+pub struct Recording {
+    stop: Arc<AtomicBool>,
+    finished: Arc<AtomicBool>,
+    writer: JoinHandle<Result<RecordingSummary, String>>,
+}
+
+impl Recording {
+    /// Creates the files and sends a sink to the audio thread, which records
+    /// from its next buffer on.
+    pub fn start(
+        sinks: &mut SwapSender<RecordingSink>,
+        path: &str,
+        input_channels: usize,
+        sample_rate: f64,
+        options: RecordingOptions,
+    ) -> Result<Recording, String> {
+        let stop = Arc::new(AtomicBool::new(false));
+        let finished = Arc::new(AtomicBool::new(false));
+        let dropped_frames = Arc::new(AtomicUsize::new(0));
+        let input_channels = input_channels.max(1);
+        let (samples, sample_consumer) = RingBuffer::new(
+            (QUEUE_SECONDS * sample_rate) as usize * frame_len(input_channels, options),
+        );
+        let (markers, marker_consumer) = RingBuffer::new(MARKER_QUEUE_SIZE);
+        let sink = RecordingSink {
+            samples,
+            markers,
+            input_channels,
+            options,
+            frame: 0,
+            last_beat: None,
+            stop: stop.clone(),
+            finished: finished.clone(),
+            dropped_frames: dropped_frames.clone(),
+        };
+
+        let writer = Writer::create(path, input_channels, sample_rate, options)?;
+        let writer_finished = finished.clone();
+        let writer = std::thread::spawn(move || {
+            writer.run(
+                sample_consumer,
+                marker_consumer,
+                writer_finished,
+                dropped_frames,
+            )
+        });
+        if sinks.send(sink).is_err() {
+            finished.store(true, Ordering::Release);
+            let _ = writer.join();
+            return Err("audio thread is not taking new recordings".to_string());
+        }
+        Ok(Recording {
+            stop,
+            finished,
+            writer,
+        })
+    }
+
+    /// Asks the audio thread to stop and waits for the files to be written.
+    pub fn stop(self, sinks: &mut SwapSender<RecordingSink>) -> Result<RecordingSummary, String> {
+        self.stop.store(true, Ordering::Relaxed);
+        let started = Instant::now();
+        while !self.finished.load(Ordering::Acquire) {
+            if started.elapsed() > Duration::from_secs(2) {
+                // no audio callback is running to hand the sink back
+                self.finished.store(true, Ordering::Release);
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        sinks.collect_retired();
+        self.writer
+            .join()
+            .map_err(|_| "recording writer panicked".to_string())?
+    }
+}
+
+/// `take.wav` with `suffix` "mix" is `take.mix.wav`.
+fn sibling(path: &Path, suffix: &str, extension: &str) -> PathBuf {
+    let stem = path
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+    path.with_file_name(format!("{}.{}.{}", stem, suffix, extension))
+}
+
+fn wav_writer(
+    path: &Path,
+    channels: usize,
+    sample_rate: f64,
+) -> Result<WavWriter<BufWriter<File>>, String> {
+    let spec = WavSpec {
+        channels: channels as u16,
+        sample_rate: sample_rate as u32,
+        bits_per_sample: 32,
+        sample_format: SampleFormat::Float,
+    };
+    WavWriter::create(path, spec).map_err(|e| format!("Failed to create file: {}", e))
+}
+
+struct Writer {
+    files: Vec<String>,
+    input: WavWriter<BufWriter<File>>,
+    mix: Option<WavWriter<BufWriter<File>>>,
+    click: Option<WavWriter<BufWriter<File>>>,
+    markers: BufWriter<File>,
+    input_channels: usize,
+    sample_rate: f64,
+}
+
+impl Writer {
+    fn create(
+        path: &str,
+        input_channels: usize,
+        sample_rate: f64,
+        options: RecordingOptions,
+    ) -> Result<Writer, String> {
+        let path = Path::new(path);
+        let mut files = vec![path.to_string_lossy().to_string()];
+        let input = wav_writer(path, input_channels, sample_rate)?;
+        let mut optional = |enabled: bool, suffix: &str, channels: usize| {
+            if !enabled {
+                return Ok(None);
+            }
+            let path = sibling(path, suffix, "wav");
+            files.push(path.to_string_lossy().to_string());
+            wav_writer(&path, channels, sample_rate).map(Some)
+        };
+        let mix = optional(options.mix, "mix", 2)?;
+        let click = optional(options.click, "click", 1)?;
+        let markers_path = sibling(path, "beats", "csv");
+        let mut markers = BufWriter::new(
+            File::create(&markers_path).map_err(|e| format!("Failed to create file: {}", e))?,
+        );
+        writeln!(markers, "frame,beat").map_err(|e| e.to_string())?;
+        files.push(markers_path.to_string_lossy().to_string());
+        Ok(Writer {
+            files,
+            input,
+            mix,
+            click,
+            markers,
+            input_channels,
+            sample_rate,
+        })
+    }
+
+    fn run(
+        mut self,
+        mut samples: Consumer<S>,
+        mut markers: Consumer<BeatMarker>,
+        finished: Arc<AtomicBool>,
+        dropped_frames: Arc<AtomicUsize>,
+    ) -> Result<RecordingSummary, String> {
+        let options = RecordingOptions {
+            mix: self.mix.is_some(),
+            click: self.click.is_some(),
+        };
+        let frame_len = frame_len(self.input_channels, options);
+        let mut frame = Vec::with_capacity(frame_len);
+        let mut frames: u64 = 0;
+        loop {
+            // checked before draining, so nothing pushed before it is left behind
+            let done = finished.load(Ordering::Acquire);
+            while let Ok(marker) = markers.pop() {
+                writeln!(self.markers, "{},{}", marker.frame, marker.beat)
+                    .map_err(|e| e.to_string())?;
+            }
+            if let Ok(chunk) = samples.read_chunk(samples.slots()) {
+                let (first, second) = chunk.as_slices();
+                for value in first.iter().chain(second.iter()) {
+                    frame.push(*value);
+                    if frame.len() == frame_len {
+                        self.write_frame(&frame)?;
+                        frame.clear();
+                        frames += 1;
+                    }
+                }
+                chunk.commit_all();
+            }
+            if done {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        self.input.finalize().map_err(|e| e.to_string())?;
+        if let Some(mix) = self.mix {
+            mix.finalize().map_err(|e| e.to_string())?;
+        }
+        if let Some(click) = self.click {
+            click.finalize().map_err(|e| e.to_string())?;
+        }
+        self.markers.flush().map_err(|e| e.to_string())?;
+        Ok(RecordingSummary {
+            files: self.files,
+            frames,
+            sample_rate: self.sample_rate,
+            dropped_frames: dropped_frames.load(Ordering::Relaxed),
+        })
+    }
+
+    fn write_frame(&mut self, frame: &[S]) -> Result<(), String> {
+        let (input, rest) = frame.split_at(self.input_channels);
+        for value in input {
+            self.input.write_sample(*value).map_err(|e| e.to_string())?;
+        }
+        let rest = match self.mix.as_mut() {
+            Some(mix) => {
+                mix.write_sample(rest[0]).map_err(|e| e.to_string())?;
+                mix.write_sample(rest[1]).map_err(|e| e.to_string())?;
+                &rest[2..]
+            }
+            None => rest,
+        };
+        if let Some(click) = self.click.as_mut() {
+            click.write_sample(rest[0]).map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A mono sink whose queue holds `frames` frames, with its queues' other ends.
+    fn sink(frames: usize) -> (RecordingSink, Consumer<S>, Consumer<BeatMarker>) {
+        let (producer, samples) = RingBuffer::new(frames);
+        let (marker_producer, markers) = RingBuffer::new(MARKER_QUEUE_SIZE);
+        let sink = RecordingSink {
+            samples: producer,
+            markers: marker_producer,
+            input_channels: 1,
+            options: RecordingOptions::default(),
+            frame: 0,
+            last_beat: None,
+            stop: Arc::new(AtomicBool::new(false)),
+            finished: Arc::new(AtomicBool::new(false)),
+            dropped_frames: Arc::new(AtomicUsize::new(0)),
+        };
+        (sink, samples, markers)
+    }
+
+    fn drain_markers(markers: &mut Consumer<BeatMarker>) -> Vec<(u64, f64)> {
+        std::iter::from_fn(|| markers.pop().ok())
+            .map(|marker| (marker.frame, marker.beat))
+            .collect()
+    }
+
+    #[test]
+    fn marks_beats_on_the_frames_written() {
+        let (mut sink, mut samples, mut markers) = sink(2);
+        let input: [&[S]; 1] = [&[0.1, 0.2, 0.3, 0.4, 0.5]];
+        for (i, beat) in [0.0, 0.5, 1.0, 1.5].iter().enumerate() {
+            sink.push(&input, i, [0.0; 2], 0.0, *beat);
+        }
+        // the writer catches up after two frames were dropped
+        while samples.pop().is_ok() {}
+        sink.push(&input, 4, [0.0; 2], 0.0, 2.0);
+
+        assert_eq!(sink.dropped_frames.load(Ordering::Relaxed), 2);
+        assert_eq!(samples.pop(), Ok(0.5));
+        assert_eq!(drain_markers(&mut markers), vec![(0, 0.0), (2, 2.0)]);
+    }
+
+    #[test]
+    fn does_not_mark_a_beat_started_before_the_recording_or_in_a_gap() {
+        let (mut sink, mut samples, mut markers) = sink(4);
+        let input: [&[S]; 1] = [&[0.0; 8]];
+        // starts half way through beat 0
+        for (i, beat) in [0.5, 0.75, 1.25, 1.5].iter().enumerate() {
+            sink.push(&input, i, [0.0; 2], 0.0, *beat);
+        }
+        // beat 2 starts while the queue is full
+        sink.push(&input, 4, [0.0; 2], 0.0, 2.25);
+        while samples.pop().is_ok() {}
+        for (i, beat) in [2.5, 2.75, 3.0].iter().enumerate() {
+            sink.push(&input, 5 + i, [0.0; 2], 0.0, *beat);
+        }
+
+        assert_eq!(sink.dropped_frames.load(Ordering::Relaxed), 1);
+        assert_eq!(drain_markers(&mut markers), vec![(2, 1.25), (6, 3.0)]);
+    }
+}
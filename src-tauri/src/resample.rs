@@ -0,0 +1,247 @@
+//! Brings decoded audio to the format the engine plays: its sample rate and
+//! channel layout. Runs when files are loaded or streamed, never on the audio
+//! thread.
+
+use crate::read_audio_file::AudioBuffer;
+use crate::util::deinterleave;
+use rubato::{
+    Resampler, SincFixedIn, SincInterpolationParameters, SincInterpolationType, WindowFunction,
+};
+
+const CHUNK_SIZE: usize = 1024;
+
+/// Remixes to `channels` and resamples to `sample_rate`.
+pub fn convert(
+    buffer: &AudioBuffer,
+    sample_rate: f64,
+    channels: usize,
+) -> Result<AudioBuffer, String> {
+    resample(&remix(buffer, channels), sample_rate)
+}
+
+/// Up- or down-mixes to `channels`. Extra output channels repeat the source
+/// channels in order (so mono is copied to every channel); when there are more
+/// source channels than outputs, source channel `n` is averaged into output
+/// channel `n % channels`.
+pub fn remix(buffer: &AudioBuffer, channels: usize) -> AudioBuffer {
+    if buffer.channels == channels || buffer.channels == 0 {
+        return buffer.clone();
+    }
+    let source = buffer.channels;
+    let mut samples = vec![0.0; buffer.frames() * channels];
+    if channels > source {
+        for (input, output) in buffer
+            .samples
+            .chunks_exact(source)
+            .zip(samples.chunks_exact_mut(channels))
+        {
+            for (ch, value) in output.iter_mut().enumerate() {
+                *value = input[ch % source];
+            }
+        }
+    } else {
+        let counts: Vec<f32> = (0..channels)
+            .map(|ch| (0..source).filter(|n| n % channels == ch).count() as f32)
+            .collect();
+        for (input, output) in buffer
+            .samples
+            .chunks_exact(source)
+            .zip(samples.chunks_exact_mut(channels))
+        {
+            for (n, value) in input.iter().enumerate() {
+                output[n % channels] += *value;
+            }
+            for (value, count) in output.iter_mut().zip(counts.iter()) {
+                *value /= *count;
+            }
+        }
+    }
+    AudioBuffer {
+        samples,
+        sample_rate: buffer.sample_rate,
+        channels,
+    }
+}
+
+/// Band-limited sinc resampling to `sample_rate`, keeping the length in time.
+pub fn resample(buffer: &AudioBuffer, sample_rate: f64) -> Result<AudioBuffer, String> {
+    if buffer.sample_rate == sample_rate || buffer.samples.is_empty() {
+        return Ok(AudioBuffer {
+            sample_rate,
+            ..buffer.clone()
+        });
+    }
+    let ratio = sample_rate / buffer.sample_rate;
+    let mut resampler = sinc_resampler(ratio, buffer.channels)?;
+
+    let input = deinterleave(&buffer.samples, buffer.channels);
+    let frames = buffer.frames();
+    let mut output: Vec<Vec<f32>> = vec![vec![]; buffer.channels];
+    let mut append = |chunk: Vec<Vec<f32>>| {
+        for (channel, resampled) in output.iter_mut().zip(chunk) {
+            channel.extend_from_slice(&resampled);
+        }
+    };
+    let mut pos = 0;
+    while pos + CHUNK_SIZE <= frames {
+        let chunk: Vec<&[f32]> = input.iter().map(|c| &c[pos..pos + CHUNK_SIZE]).collect();
+        append(resampler.process(&chunk, None).map_err(|e| e.to_string())?);
+        pos += CHUNK_SIZE;
+    }
+    let rest: Vec<&[f32]> = input.iter().map(|c| &c[pos..]).collect();
+    append(
+        resampler
+            .process_partial(Some(&rest), None)
+            .map_err(|e| e.to_string())?,
+    );
+    // flush the input the filter still holds back to look ahead
+    append(
+        resampler
+            .process_partial::<&[f32]>(None, None)
+            .map_err(|e| e.to_string())?,
+    );
+
+    // SincFixedIn starts half a filter early, so the output is already aligned
+    let expected = (frames as f64 * ratio).round() as usize;
+    let mut samples = Vec::with_capacity(expected * buffer.channels);
+    for frame in 0..expected {
+        for channel in output.iter() {
+            samples.push(*channel.get(frame).unwrap_or(&0.0));
+        }
+    }
+    Ok(AudioBuffer {
+        samples,
+        sample_rate,
+        channels: buffer.channels,
+    })
+}
+
+fn sinc_resampler(ratio: f64, channels: usize) -> Result<SincFixedIn<f32>, String> {
+    let parameters = SincInterpolationParameters {
+        sinc_len: 256,
+        f_cutoff: 0.95,
+        interpolation: SincInterpolationType::Linear,
+        oversampling_factor: 256,
+        window: WindowFunction::BlackmanHarris2,
+    };
+    SincFixedIn::<f32>::new(ratio, 1.0, parameters, CHUNK_SIZE, channels).map_err(|e| e.to_string())
+}
+
+/// Resamples audio that arrives a chunk at a time, as a streamed track is
+/// decoded, the same way `resample` does a whole buffer. Passes it through
+/// when the rates match.
+pub struct StreamResampler {
+    resampler: Option<SincFixedIn<f32>>,
+    channels: usize,
+    /// Input not resampled yet, one Vec per channel.
+    queued: Vec<Vec<f32>>,
+}
+
+impl StreamResampler {
+    pub fn new(from: f64, to: f64, channels: usize) -> Result<StreamResampler, String> {
+        let resampler = if from == to {
+            None
+        } else {
+            Some(sinc_resampler(to / from, channels)?)
+        };
+        Ok(StreamResampler {
+            resampler,
+            channels,
+            queued: vec![vec![]; channels],
+        })
+    }
+
+    /// Takes interleaved `samples` and returns, interleaved, as much as can be
+    /// resampled so far. Input is held back until a whole chunk has arrived.
+    pub fn process(&mut self, samples: &[f32]) -> Result<Vec<f32>, String> {
+        let resampler = match &mut self.resampler {
+            Some(resampler) => resampler,
+            None => return Ok(samples.to_vec()),
+        };
+        for (queued, channel) in self
+            .queued
+            .iter_mut()
+            .zip(deinterleave(samples, self.channels))
+        {
+            queued.extend_from_slice(&channel);
+        }
+        let mut output = vec![];
+        loop {
+            let needed = resampler.input_frames_next();
+            if self.queued[0].len() < needed {
+                return Ok(output);
+            }
+            let chunk: Vec<&[f32]> = self.queued.iter().map(|c| &c[..needed]).collect();
+            let resampled = resampler.process(&chunk, None).map_err(|e| e.to_string())?;
+            for channel in self.queued.iter_mut() {
+                channel.drain(..needed);
+            }
+            for frame in 0..resampled[0].len() {
+                output.extend(resampled.iter().map(|channel| channel[frame]));
+            }
+        }
+    }
+
+    /// Forgets the input so far, before going on from elsewhere in the file.
+    pub fn reset(&mut self) {
+        if let Some(resampler) = &mut self.resampler {
+            resampler.reset();
+        }
+        for channel in self.queued.iter_mut() {
+            channel.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(frames: usize, sample_rate: f64) -> AudioBuffer {
+        let samples = (0..frames)
+            .flat_map(|i| {
+                let value = (i as f64 * 440.0 * std::f64::consts::TAU / sample_rate).sin() as f32;
+                [value, -value]
+            })
+            .collect();
+        AudioBuffer {
+            samples,
+            sample_rate,
+            channels: 2,
+        }
+    }
+
+    #[test]
+    fn streams_the_same_as_a_whole_buffer() {
+        let input = sine(10000, 44100.0);
+        let whole = resample(&input, 48000.0).unwrap();
+        let mut stream = StreamResampler::new(44100.0, 48000.0, 2).unwrap();
+        let mut streamed = vec![];
+        for chunk in input.samples.chunks(700 * 2) {
+            streamed.extend(stream.process(chunk).unwrap());
+        }
+        // all but the last partial chunk, which waits for more input
+        assert!(streamed.len() >= 9 * CHUNK_SIZE * 2);
+        assert!(streamed.len() <= whole.samples.len());
+        for (a, b) in streamed.iter().zip(whole.samples.iter()) {
+            assert!((a - b).abs() < 1e-6, "{} != {}", a, b);
+        }
+    }
+
+    #[test]
+    fn starts_over_after_a_reset() {
+        let input = sine(4000, 22050.0);
+        let mut stream = StreamResampler::new(22050.0, 44100.0, 2).unwrap();
+        let first = stream.process(&input.samples).unwrap();
+        stream.process(&sine(500, 22050.0).samples[200..]).unwrap();
+        stream.reset();
+        assert_eq!(stream.process(&input.samples).unwrap(), first);
+    }
+
+    #[test]
+    fn passes_through_at_the_same_rate() {
+        let input = sine(100, 44100.0);
+        let mut stream = StreamResampler::new(44100.0, 44100.0, 2).unwrap();
+        assert_eq!(stream.process(&input.samples).unwrap(), input.samples);
+    }
+}
@@ -0,0 +1,521 @@
+//! The settings and named presets, kept as JSON in the app config directory.
+//! Every file carries a schema version and older ones are migrated when read.
+//! Fields a file does not have are taken from `default_config`, so adding a
+//! field to `Config` does not break files written before it. The settings are
+//! written on a thread of their own once they stop changing for a moment.
+
+use crate::constants::default_config;
+use crate::latency::LatencyMeasurement;
+use crate::structs::Config;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Bumped whenever the file layout changes in a way filling in defaults cannot handle.
+pub const SETTINGS_VERSION: u64 = 2;
+const SETTINGS_FILE: &str = "settings.json";
+const PRESETS_DIR: &str = "presets";
+/// How long the settings have to stay the same before they are written, so
+/// dragging a slider writes the file once rather than on every step.
+const SAVE_DELAY: Duration = Duration::from_millis(500);
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Settings {
+    pub version: u64,
+    pub config: Config,
+    /// Frontend state stored with the config as it is, such as the notation
+    /// the rhythms were parsed from.
+    #[serde(default)]
+    pub frontend: Option<Value>,
+    /// The file last loaded with `set_mp3_buffer`, loaded again on startup.
+    #[serde(default)]
+    pub backing_track: Option<String>,
+    /// Round trips measured with `measure_latency`, by `latency::device_pair`.
+    #[serde(default)]
+    pub latencies: BTreeMap<String, LatencyMeasurement>,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            version: SETTINGS_VERSION,
+            config: default_config(),
+            frontend: None,
+            backing_track: None,
+            latencies: BTreeMap::new(),
+        }
+    }
+}
+
+/// The current settings and where they are saved. Without a directory nothing
+/// is saved and there are no presets.
+pub struct SettingsStore {
+    dir: Option<PathBuf>,
+    settings: Settings,
+    writer: Option<SettingsWriter>,
+}
+
+impl SettingsStore {
+    /// Reads the saved settings from `dir`, starting from the defaults if there
+    /// are none or they cannot be read.
+    pub fn open(dir: Option<PathBuf>) -> SettingsStore {
+        let settings = match &dir {
+            Some(dir) if dir.join(SETTINGS_FILE).exists() => {
+                read_settings(&dir.join(SETTINGS_FILE)).unwrap_or_else(|e| {
+                    println!("could not read settings, using the defaults: {}", e);
+                    Settings::default()
+                })
+            }
+            _ => Settings::default(),
+        };
+        let writer = dir
+            .as_ref()
+            .map(|dir| SettingsWriter::start(dir.join(SETTINGS_FILE)));
+        SettingsStore {
+            dir,
+            settings,
+            writer,
+        }
+    }
+
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    /// Makes `config` current and saves it. `None` keeps the frontend state.
+    pub fn set_config(&mut self, config: Config, frontend: Option<Value>) -> Result<(), String> {
+        self.settings.config = config;
+        if frontend.is_some() {
+            self.settings.frontend = frontend;
+        }
+        self.save()
+    }
+
+    pub fn set_backing_track(&mut self, path: Option<String>) -> Result<(), String> {
+        self.settings.backing_track = path;
+        self.save()
+    }
+
+    /// Writes the settings still waiting for `SAVE_DELAY` now, before the app exits.
+    pub fn flush(&mut self) {
+        // dropping the writer waits for it to write what it has
+        self.writer = self
+            .dir
+            .as_ref()
+            .map(|dir| SettingsWriter::start(dir.join(SETTINGS_FILE)));
+    }
+
+    /// Keeps the round trip measured for `pair` and makes it the config's
+    /// `buffer_compensation`.
+    pub fn set_latency(
+        &mut self,
+        pair: String,
+        measurement: LatencyMeasurement,
+    ) -> Result<(), String> {
+        self.settings.config.buffer_compensation = measurement.frames;
+        self.settings.latencies.insert(pair, measurement);
+        self.save()
+    }
+
+    fn save(&self) -> Result<(), String> {
+        match &self.writer {
+            Some(writer) => writer.save(&self.settings),
+            None => Ok(()),
+        }
+    }
+
+    fn presets_dir(&self) -> Result<PathBuf, String> {
+        self.dir
+            .as_ref()
+            .map(|dir| dir.join(PRESETS_DIR))
+            .ok_or_else(|| "there is no config directory for presets".to_string())
+    }
+
+    fn preset_path(&self, name: &str) -> Result<PathBuf, String> {
+        if name.trim().is_empty() || name.starts_with('.') || name.contains(['/', '\\', ':']) {
+            return Err(format!("invalid preset name: {:?}", name));
+        }
+        Ok(self.presets_dir()?.join(format!("{}.json", name)))
+    }
+
+    /// Preset names in alphabetical order.
+    pub fn list_presets(&self) -> Result<Vec<String>, String> {
+        let dir = self.presets_dir()?;
+        if !dir.exists() {
+            return Ok(vec![]);
+        }
+        let mut names: Vec<String> = fs::read_dir(&dir)
+            .map_err(|e| e.to_string())?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension() == Some("json".as_ref()))
+            .filter_map(|path| Some(path.file_stem()?.to_string_lossy().to_string()))
+            .collect();
+        names.sort();
+        Ok(names)
+    }
+
+    /// Saves the current settings under `name`, replacing any preset of that name.
+    pub fn save_preset(&self, name: &str) -> Result<(), String> {
+        write_settings(&self.preset_path(name)?, &self.settings)
+    }
+
+    /// Makes the preset current and saves it as the settings.
+    pub fn load_preset(&mut self, name: &str) -> Result<Settings, String> {
+        let path = self.preset_path(name)?;
+        if !path.exists() {
+            return Err(format!("no preset named {:?}", name));
+        }
+        let preset = read_settings(&path)?;
+        self.set_config(preset.config.clone(), preset.frontend.clone())?;
+        Ok(self.settings.clone())
+    }
+
+    pub fn delete_preset(&self, name: &str) -> Result<(), String> {
+        let path = self.preset_path(name)?;
+        if !path.exists() {
+            return Err(format!("no preset named {:?}", name));
+        }
+        fs::remove_file(path).map_err(|e| e.to_string())
+    }
+}
+
+/// Writes the settings on a thread of its own once they have not changed for
+/// `SAVE_DELAY`. Settings still waiting are written when it is dropped.
+struct SettingsWriter {
+    sender: Option<Sender<Settings>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl SettingsWriter {
+    fn start(path: PathBuf) -> SettingsWriter {
+        let (sender, receiver) = mpsc::channel();
+        let thread = thread::spawn(move || write_latest(&path, receiver));
+        SettingsWriter {
+            sender: Some(sender),
+            thread: Some(thread),
+        }
+    }
+
+    fn save(&self, settings: &Settings) -> Result<(), String> {
+        self.sender
+            .as_ref()
+            .and_then(|sender| sender.send(settings.clone()).ok())
+            .ok_or_else(|| "the settings writer has stopped".to_string())
+    }
+}
+
+impl Drop for SettingsWriter {
+    fn drop(&mut self) {
+        self.sender.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Writes the newest of the settings `receiver` gets each time they settle,
+/// until the sender is dropped.
+fn write_latest(path: &Path, receiver: Receiver<Settings>) {
+    while let Ok(mut settings) = receiver.recv() {
+        // stops at a quiet moment, or once the sender is dropped
+        while let Ok(newer) = receiver.recv_timeout(SAVE_DELAY) {
+            settings = newer;
+        }
+        if let Err(err) = write_settings(path, &settings) {
+            println!("could not save settings: {}", err);
+        }
+    }
+}
+
+/// A config as `set_config` receives it, or the one in a settings file, of
+/// any version.
+pub fn read_config(text: &str) -> Result<Config, String> {
+    let value = serde_json::from_str(text).map_err(|e| e.to_string())?;
+    migrate(value).map(|settings| settings.config)
+}
+
+fn read_settings(path: &Path) -> Result<Settings, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let value = serde_json::from_str(&text).map_err(|e| format!("{}: {}", path.display(), e))?;
+    migrate(value).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/// Writes next to `path` first, so a crash never leaves half a file behind.
+fn write_settings(path: &Path, settings: &Settings) -> Result<(), String> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+    }
+    let text = serde_json::to_string_pretty(settings).map_err(|e| e.to_string())?;
+    let temporary = path.with_extension("json.tmp");
+    fs::write(&temporary, text).map_err(|e| e.to_string())?;
+    fs::rename(&temporary, path).map_err(|e| e.to_string())
+}
+
+/// Brings a file of any version to `SETTINGS_VERSION`.
+fn migrate(mut value: Value) -> Result<Settings, String> {
+    let mut version = match value.get("version") {
+        Some(version) => version
+            .as_u64()
+            .ok_or_else(|| "the version is not a number".to_string())?,
+        None => 0,
+    };
+    if version > SETTINGS_VERSION {
+        return Err(format!(
+            "written by a newer version (schema {}, this one reads up to {})",
+            version, SETTINGS_VERSION
+        ));
+    }
+    if version == 0 {
+        // unversioned files are a bare config, as `set_config` receives it
+        value = serde_json::json!({ "config": value });
+        version = 1;
+    }
+    if version == 1 {
+        // the one rhythm became the first of a list of layers
+        if let Some(config) = value.get_mut("config").and_then(Value::as_object_mut) {
+            if let Some(rhythm) = config.remove("audio_subdivisions") {
+                config.insert(
+                    "layers".to_string(),
+                    serde_json::json!([{ "rhythm": rhythm }]),
+                );
+            }
+            config.remove("test_object");
+        }
+        // and so did the frontend's copy of it, which keeps its notation
+        if let Some(frontend) = value.get_mut("frontend").and_then(Value::as_object_mut) {
+            if let Some(rhythm) = frontend.remove("audioSubdivisions") {
+                frontend.insert(
+                    "layers".to_string(),
+                    serde_json::json!([{ "rhythm": rhythm, "gain": 1.0, "pan": 0.0, "mute": false }]),
+                );
+            }
+            frontend.remove("testObject");
+        }
+        version = 2;
+    }
+    value["version"] = Value::from(version);
+
+    let mut config = serde_json::to_value(default_config()).map_err(|e| e.to_string())?;
+    if let Some(stored) = value.get_mut("config") {
+        merge(&mut config, stored.take());
+    }
+    value["config"] = config;
+    serde_json::from_value(value).map_err(|e| e.to_string())
+}
+
+/// Overwrites `base` with `overlay`, going into objects so keys missing from
+/// `overlay` keep their value from `base`.
+fn merge(base: &mut Value, overlay: Value) {
+    match (base, overlay) {
+        (Value::Object(base), Value::Object(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(base_value) => merge(base_value, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn note_times(rhythm: &crate::structs::ParserRhythm) -> Vec<f64> {
+        rhythm.notes.iter().map(|note| note.time).collect()
+    }
+
+    #[test]
+    fn reads_a_bare_unversioned_config() {
+        let text = json!({
+            "bpm": 120.0,
+            "click_on": false,
+            "audio_subdivisions": { "notes": [{ "time": 0 }, { "time": 0.25 }], "start": 0, "end": 1 },
+        })
+        .to_string();
+        let config = read_config(&text).unwrap();
+        assert_eq!(config.bpm, 120.0);
+        assert!(!config.click_on);
+        assert_eq!(config.layers.len(), 1);
+        assert_eq!(note_times(&config.layers[0].rhythm), vec![0.0, 0.25]);
+    }
+
+    #[test]
+    fn moves_the_version_1_rhythm_into_layers() {
+        let value = json!({
+            "version": 1,
+            "config": {
+                "audio_subdivisions": "1/3, 1/3, 1/3",
+                "test_object": { "anything": true },
+            },
+            "frontend": {
+                "audioSubdivisions": { "inputText": "3:1" },
+                "testObject": 1,
+                "bpmText": "91",
+            },
+        });
+        let settings = migrate(value).unwrap();
+        assert_eq!(settings.version, SETTINGS_VERSION);
+        assert_eq!(settings.config.layers.len(), 1);
+        assert_eq!(settings.config.layers[0].rhythm.notes.len(), 3);
+        let frontend = settings.frontend.unwrap();
+        assert_eq!(
+            frontend["layers"],
+            json!([{ "rhythm": { "inputText": "3:1" }, "gain": 1.0, "pan": 0.0, "mute": false }])
+        );
+        assert!(frontend.get("audioSubdivisions").is_none());
+        assert!(frontend.get("testObject").is_none());
+        assert_eq!(frontend["bpmText"], "91");
+    }
+
+    #[test]
+    fn fills_missing_fields_from_the_defaults() {
+        let value = json!({
+            "version": SETTINGS_VERSION,
+            "config": { "bpm": 140.0, "click": { "decay": 0.1 } },
+        });
+        let settings = migrate(value).unwrap();
+        let defaults = default_config();
+        assert_eq!(settings.config.bpm, 140.0);
+        assert_eq!(settings.config.click.decay, 0.1);
+        assert_eq!(settings.config.click.attack, defaults.click.attack);
+        assert_eq!(settings.config.beats_to_loop, defaults.beats_to_loop);
+        assert_eq!(
+            settings.config.buffer_compensation,
+            defaults.buffer_compensation
+        );
+        assert_eq!(settings.config.kit, defaults.kit);
+        assert_eq!(
+            note_times(&settings.config.layers[0].rhythm),
+            note_times(&defaults.layers[0].rhythm)
+        );
+        assert_eq!(settings.backing_track, None);
+    }
+
+    #[test]
+    fn refuses_files_from_a_newer_version() {
+        let value = json!({ "version": SETTINGS_VERSION + 1, "config": {} });
+        assert!(migrate(value).is_err());
+        assert!(read_config(r#"{ "version": "2" }"#).is_err());
+    }
+
+    #[test]
+    fn rejects_preset_names_outside_the_presets_dir() {
+        let store = SettingsStore {
+            dir: Some(PathBuf::from("config")),
+            settings: Settings::default(),
+            writer: None,
+        };
+        for name in ["../x", "a/b", "a\\b", "c:x", ".hidden", "", "  "] {
+            assert!(store.preset_path(name).is_err(), "{:?}", name);
+        }
+        assert_eq!(
+            store.preset_path("groove").unwrap(),
+            Path::new("config").join(PRESETS_DIR).join("groove.json")
+        );
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn writes_the_last_settings_once_they_settle() {
+        let dir = temp_dir("settings_test_settle");
+        let mut store = SettingsStore::open(Some(dir.clone()));
+        for bpm in 100..120 {
+            let config = Config {
+                bpm: bpm as f64,
+                ..default_config()
+            };
+            store.set_config(config, None).unwrap();
+        }
+        store
+            .set_backing_track(Some("track.mp3".to_string()))
+            .unwrap();
+        assert!(!dir.join(SETTINGS_FILE).exists());
+
+        thread::sleep(SAVE_DELAY * 3);
+        let saved = read_settings(&dir.join(SETTINGS_FILE)).unwrap();
+        assert_eq!(saved.config.bpm, 119.0);
+        assert_eq!(saved.backing_track.as_deref(), Some("track.mp3"));
+        assert!(!dir.join("settings.json.tmp").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn writes_what_is_waiting_when_flushed() {
+        let dir = temp_dir("settings_test_flush");
+        let mut store = SettingsStore::open(Some(dir.clone()));
+        let config = Config {
+            bpm: 130.0,
+            ..default_config()
+        };
+        store.set_config(config, None).unwrap();
+        store.flush();
+        let saved = read_settings(&dir.join(SETTINGS_FILE)).unwrap();
+        assert_eq!(saved.config.bpm, 130.0);
+
+        // and goes on saving afterwards
+        store
+            .set_backing_track(Some("track.mp3".to_string()))
+            .unwrap();
+        store.flush();
+        let saved = read_settings(&dir.join(SETTINGS_FILE)).unwrap();
+        assert_eq!(saved.backing_track.as_deref(), Some("track.mp3"));
+        drop(store);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn keeps_measured_latency_across_restarts() {
+        let dir = temp_dir("settings_test_latency");
+        let mut store = SettingsStore::open(Some(dir.clone()));
+        let measurement = LatencyMeasurement {
+            frames: 1234,
+            milliseconds: 28.0,
+            sample_rate: 44100.0,
+            confidence: 0.9,
+        };
+        store
+            .set_latency("in -> out".to_string(), measurement)
+            .unwrap();
+        assert_eq!(store.settings().config.buffer_compensation, 1234);
+        drop(store);
+
+        let store = SettingsStore::open(Some(dir.clone()));
+        assert_eq!(store.settings().config.buffer_compensation, 1234);
+        assert_eq!(store.settings().latencies["in -> out"].frames, 1234);
+        drop(store);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn writes_what_is_waiting_when_dropped() {
+        let dir = temp_dir("settings_test_drop");
+        let mut store = SettingsStore::open(Some(dir.clone()));
+        let config = Config {
+            bpm: 150.0,
+            ..default_config()
+        };
+        store.set_config(config, None).unwrap();
+        drop(store);
+
+        let store = SettingsStore::open(Some(dir.clone()));
+        assert_eq!(store.settings().config.bpm, 150.0);
+        drop(store);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,62 +1,375 @@
+use crate::backing_track::BackingTrackInfo;
+use crate::click::ClickSettings;
+use crate::device_manager::DeviceManager;
+use crate::engine::EngineConfig;
+use crate::kit::{default_kit, KitLoader};
+use crate::latency::LatencyProbe;
+use crate::loop_file::LoopSnapshot;
+use crate::looper::LooperControls;
+use crate::onset::Hit;
+use crate::parse_rhythm::parse_rhythm;
+use crate::realtime::{SwapSender, ValueSender};
+use crate::recording::{Recording, RecordingSink};
+use crate::settings::SettingsStore;
+use crate::timing::TimingScorer;
+use crate::track_stream::{SeekRequest, TrackStream};
+use atomic_float::AtomicF64;
+use rtrb::{Consumer, Producer};
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::VecDeque,
+    collections::{BTreeMap, VecDeque},
     sync::atomic::AtomicBool,
     sync::{Arc, Mutex},
 };
 pub struct BeatResetState(pub Arc<AtomicBool>);
 
+/// A backing track, interleaved stereo at the sample rate it was loaded for:
+/// decoded whole into `buffer`, or for long files streamed from disk.
 pub struct Mp3Buffer {
     pub buffer: Vec<f32>,
-    pub pos: usize,
+    /// Playback position in frames; fractional when the file and device rates differ.
+    pub pos: f64,
+    pub sample_rate: f64,
+    /// Set for streamed files, which leave `buffer` empty.
+    pub stream: Option<TrackStream>,
+    seek: Arc<SeekRequest>,
+    seeks_applied: usize,
+}
+
+impl Mp3Buffer {
+    pub fn new(buffer: Vec<f32>, sample_rate: f64) -> Mp3Buffer {
+        let frames = (buffer.len() / 2) as u64;
+        Mp3Buffer {
+            buffer,
+            pos: 0.0,
+            sample_rate,
+            stream: None,
+            seek: Arc::new(SeekRequest::new(sample_rate, Some(frames.max(1)))),
+            seeks_applied: 0,
+        }
+    }
+
+    pub fn streaming(stream: TrackStream, sample_rate: f64) -> Mp3Buffer {
+        Mp3Buffer {
+            seek: stream.seek_request(),
+            stream: Some(stream),
+            ..Mp3Buffer::new(vec![], sample_rate)
+        }
+    }
+
+    /// Moves playback from outside the audio thread.
+    pub fn seek_request(&self) -> Arc<SeekRequest> {
+        self.seek.clone()
+    }
+
+    fn frames(&self) -> usize {
+        self.buffer.len() / 2
+    }
+
+    /// The left (0) or right (1) channel at the current position, linearly interpolated.
+    pub fn sample(&self, side: usize) -> f32 {
+        let fraction = (self.pos - self.pos.floor()) as f32;
+        if let Some(stream) = &self.stream {
+            return stream.sample(side, fraction);
+        }
+        let frames = self.frames();
+        if frames == 0 {
+            return 0.0;
+        }
+        let index = self.pos as usize % frames;
+        let next = (index + 1) % frames;
+        let a = self.buffer[index * 2 + side];
+        let b = self.buffer[next * 2 + side];
+        a + (b - a) * fraction
+    }
+
+    /// Moves on by `frames` of the file, wrapping at the end.
+    pub fn advance(&mut self, frames: f64) {
+        if let Some(stream) = &mut self.stream {
+            let whole = self.pos.floor();
+            self.pos += frames;
+            for _ in 0..(self.pos.floor() - whole) as usize {
+                stream.next_frame();
+            }
+            return;
+        }
+        let len = self.frames() as f64;
+        self.pos += frames;
+        if self.pos >= len {
+            self.pos = if len > 0.0 { self.pos % len } else { 0.0 };
+        }
+    }
+
+    /// Goes back to the start of the file.
+    pub fn rewind(&mut self) {
+        self.pos = 0.0;
+        if let Some(stream) = &mut self.stream {
+            stream.seek(0);
+        }
+    }
+
+    /// Takes up the latest seek request, once per callback.
+    pub fn receive_seek(&mut self) {
+        if let Some(stream) = &mut self.stream {
+            stream.sync();
+            return;
+        }
+        let (count, frame) = self.seek.latest();
+        if count != self.seeks_applied {
+            self.pos = frame as f64;
+        }
+        self.seeks_applied = count;
+    }
 }
 
-pub struct Mp3BufferState(pub Arc<Mutex<Mp3Buffer>>);
+pub struct Mp3BufferState(pub Mutex<SwapSender<Mp3Buffer>>);
+
+/// The backing track playing, `None` when no file is loaded.
+pub struct BackingTrackState(pub Mutex<Option<BackingTrackInfo>>);
+
+/// Cancels the backing track being loaded, if any.
+pub struct FileLoadState(pub Mutex<Option<Arc<AtomicBool>>>);
+
+/// Seeks the backing track playing, if any.
+pub struct TrackSeekState(pub Mutex<Option<Arc<SeekRequest>>>);
 
 pub struct SampleOutputBuffer {
-    pub buffer: Arc<Mutex<Vec<(f32, f32)>>>,
+    pub buffer: Mutex<Consumer<(f32, f32)>>,
 }
 
 pub struct LoopBuffer {
     pub buffer: Vec<f32>,
+    /// What the current overdub layer replaced, slot for slot.
+    pub undo: Vec<f32>,
     pub pos: usize,
+    /// A reference loop, which the looper plays from the next bar line on. The
+    /// audio thread keeps the contents of its current loop when it takes a
+    /// buffer that is not a reference.
+    pub reference: bool,
+}
+
+impl LoopBuffer {
+    /// A silent loop of `size` interleaved stereo samples.
+    pub fn new(size: usize) -> LoopBuffer {
+        LoopBuffer {
+            buffer: vec![0f32; size],
+            undo: vec![0f32; size],
+            pos: 0,
+            reference: false,
+        }
+    }
 }
 
-pub struct LoopBufferState(pub Arc<Mutex<LoopBuffer>>);
+pub struct LoopBufferState(pub Mutex<SwapSender<LoopBuffer>>);
+
+pub struct LoopSnapshotState(pub Mutex<SwapSender<LoopSnapshot>>);
+
+pub struct LooperState(pub Mutex<LooperControls>);
 
 pub struct LogState(pub Arc<Mutex<Vec<String>>>);
 
+/// The rate the engine currently runs at, as negotiated with the output device.
+pub struct SampleRateState(pub Arc<AtomicF64>);
+
+pub struct DeviceManagerState(pub Mutex<DeviceManager>);
+
+pub struct LatencyProbeState(pub Mutex<SwapSender<LatencyProbe>>);
+
+/// Hits detected since the frontend last asked for them.
+pub struct HitState(pub Mutex<VecDeque<Hit>>);
+
+pub struct TimingState(pub Mutex<TimingScorer>);
+
+pub struct RecordingSinkState(pub Mutex<SwapSender<RecordingSink>>);
+
+/// The recording in progress, if any.
+pub struct RecordingState(pub Mutex<Option<Recording>>);
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Note {
     pub time: f64,
-    // sounds: Vec<String>,
+    /// Kit sounds to play, by name or drum letter; none plays the default sound.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sounds: Vec<String>,
+    /// Rests keep their place in the rhythm but play nothing.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub rest: bool,
+    /// Scales the note's sounds and click, 1 being full volume.
+    #[serde(default = "full_velocity", skip_serializing_if = "is_full_velocity")]
+    pub velocity: f32,
+    /// Extra gain for an accented note, added to 1 before scaling by
+    /// `velocity`. Accented notes also click like downbeats.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub accent: Option<f32>,
+}
+
+impl Note {
+    /// A full velocity note playing the default sound.
+    pub fn at(time: f64) -> Note {
+        Note {
+            time,
+            sounds: vec![],
+            rest: false,
+            velocity: full_velocity(),
+            accent: None,
+        }
+    }
+
+    /// How loud the note plays, 0 for rests.
+    pub fn gain(&self) -> f32 {
+        if self.rest {
+            return 0.0;
+        }
+        self.velocity * (1.0 + self.accent.unwrap_or(0.0))
+    }
+}
+
+fn full_velocity() -> f32 {
+    1.0
+}
+
+fn unity_gain() -> f32 {
+    1.0
+}
+
+fn is_full_velocity(velocity: &f32) -> bool {
+    *velocity == 1.0
+}
+
+fn is_false(value: &bool) -> bool {
+    !*value
 }
 
+/// Deserializes from the notes as the frontend's parser gives them, or from
+/// the notation itself, parsed with `parse_rhythm`.
 #[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(try_from = "RhythmSource")]
 pub struct ParserRhythm {
     pub notes: Vec<Note>,
     pub start: f64,
     pub end: f64,
 }
 
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RhythmSource {
+    Text(String),
+    Parsed {
+        notes: Vec<Note>,
+        start: f64,
+        end: f64,
+    },
+}
+
+impl TryFrom<RhythmSource> for ParserRhythm {
+    type Error = String;
+
+    fn try_from(source: RhythmSource) -> Result<ParserRhythm, String> {
+        match source {
+            RhythmSource::Text(text) => parse_rhythm(&text).map_err(|e| e.describe(&text)),
+            RhythmSource::Parsed { notes, start, end } => Ok(ParserRhythm { notes, start, end }),
+        }
+    }
+}
+
+/// Routing for one physical input channel.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct InputChannel {
+    /// Index of the device input channel, starting at 0.
+    pub channel: usize,
+    pub gain: f32,
+    /// -1.0 is hard left, 1.0 hard right. At 0.0 both sides get the full signal.
+    pub pan: f32,
+    pub to_loop: bool,
+    pub to_monitor: bool,
+    pub to_visualizer: bool,
+}
+
+impl Default for InputChannel {
+    fn default() -> InputChannel {
+        InputChannel {
+            channel: 0,
+            gain: 1.0,
+            pan: 0.0,
+            to_loop: true,
+            to_monitor: true,
+            to_visualizer: true,
+        }
+    }
+}
+
+fn default_input_channels() -> Vec<InputChannel> {
+    vec![InputChannel::default()]
+}
+
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct Config {
     pub bpm: f64,
     pub beats_to_loop: f64,
     pub audio_in_gain: f32,
+    #[serde(default = "default_input_channels")]
+    pub input_channels: Vec<InputChannel>,
     pub looping_on: bool,
     pub click_on: bool,
     pub click_toggle: bool,
     pub click_volume: f64,
+    #[serde(default)]
+    pub click: ClickSettings,
     pub drum_on: bool,
     pub play_file: bool,
     pub visual_monitor_on: bool,
     pub audio_monitor_on: bool,
     pub buffer_compensation: usize,
-    pub audio_subdivisions: ParserRhythm,
-    pub test_object: ParserRhythm,
+    /// Rhythms played together on the same beat, each repeating on its own.
+    pub layers: Vec<RhythmLayer>,
+    /// Sample files by sound name.
+    #[serde(default = "default_kit")]
+    pub kit: BTreeMap<String, String>,
+}
+
+impl Config {
+    /// The rhythm hits are scored against: the first layer's, muted or not,
+    /// so a part can be muted to be played by hand.
+    pub fn scored_rhythm(&self) -> Option<&ParserRhythm> {
+        self.layers.first().map(|layer| &layer.rhythm)
+    }
 }
-pub struct ConfigState(pub Arc<Mutex<Config>>);
+
+/// One rhythm of the click and drums. Its length is that of its rhythm, so a
+/// layer of 3 notes in 4 beats against one of 4 plays 3 over 4.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct RhythmLayer {
+    pub rhythm: ParserRhythm,
+    /// Kit sound for the notes that do not name one, instead of the default sound.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sound: Option<String>,
+    #[serde(default = "unity_gain")]
+    pub gain: f32,
+    /// -1.0 is hard left, 1.0 hard right.
+    #[serde(default)]
+    pub pan: f32,
+    /// Muted layers keep time without playing.
+    #[serde(default)]
+    pub mute: bool,
+}
+
+impl RhythmLayer {
+    pub fn new(rhythm: ParserRhythm) -> RhythmLayer {
+        RhythmLayer {
+            rhythm,
+            sound: None,
+            gain: 1.0,
+            pan: 0.0,
+            mute: false,
+        }
+    }
+}
+pub struct ConfigState(pub Mutex<ValueSender<EngineConfig>>);
+
+pub struct SettingsState(pub Mutex<SettingsStore>);
+
+pub struct KitState(pub Mutex<KitLoader>);
 
 #[derive(Clone, serde::Serialize)]
 pub struct Payload {
@@ -66,11 +379,11 @@ pub struct Payload {
 pub struct SoundingSample {
     pub sample: Arc<Vec<f32>>,
     pub pos: usize,
+    /// Left and right.
+    pub gains: [f32; 2],
 }
 
 pub struct Buffers {
-    pub producer_left: Arc<Mutex<VecDeque<f32>>>,
-    pub consumer_left: Arc<Mutex<VecDeque<f32>>>,
-    pub producer_right: Arc<Mutex<VecDeque<f32>>>,
-    pub consumer_right: Arc<Mutex<VecDeque<f32>>>,
+    pub producers: Vec<Producer<f32>>,
+    pub consumers: Vec<Consumer<f32>>,
 }
@@ -0,0 +1,381 @@
+//! Scores detected hits against the rhythm being practiced. Each hit is matched
+//! to the nearest note of `Config::scored_rhythm`; a note counts as missed
+//! once the input has passed the midpoint to the next note without a hit, as
+//! any later hit is nearer to the next one. A bar is one pass through the rhythm.
+//! Scoring starts over whenever the engine resets the beat.
+
+use crate::onset::{Hit, InputBeat};
+use crate::structs::ParserRhythm;
+use crate::util::beat_bisect;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashSet};
+
+/// Bars kept in `TimingStats::bars`; older ones are dropped, the session keeps counting them.
+const MAX_BARS: usize = 1000;
+
+/// A hit matched to a note. Positive offsets are late.
+#[derive(Clone, Debug, Serialize)]
+pub struct ScoredHit {
+    pub beat: f64,
+    pub note_beat: f64,
+    pub bar: isize,
+    pub offset_beats: f64,
+    pub offset_ms: f64,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Stats {
+    pub hits: usize,
+    pub misses: usize,
+    /// Hits on a note that already had one, or that was already judged.
+    pub extra_hits: usize,
+    pub mean_offset_ms: f64,
+    pub std_dev_ms: f64,
+    pub mean_offset_beats: f64,
+    pub std_dev_beats: f64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct BarStats {
+    pub bar: isize,
+    pub stats: Stats,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TimingStats {
+    pub session: Stats,
+    pub bars: Vec<BarStats>,
+    pub last_hit: Option<ScoredHit>,
+}
+
+#[derive(Clone, Default)]
+struct Accumulator {
+    hits: usize,
+    misses: usize,
+    extra_hits: usize,
+    sum_beats: f64,
+    sum_squares_beats: f64,
+    sum_ms: f64,
+    sum_squares_ms: f64,
+}
+
+impl Accumulator {
+    fn add(&mut self, hit: &ScoredHit) {
+        self.hits += 1;
+        self.sum_beats += hit.offset_beats;
+        self.sum_squares_beats += hit.offset_beats * hit.offset_beats;
+        self.sum_ms += hit.offset_ms;
+        self.sum_squares_ms += hit.offset_ms * hit.offset_ms;
+    }
+
+    fn stats(&self) -> Stats {
+        let mean_and_deviation = |sum: f64, sum_squares: f64| {
+            if self.hits == 0 {
+                return (0.0, 0.0);
+            }
+            let n = self.hits as f64;
+            let mean = sum / n;
+            (mean, (sum_squares / n - mean * mean).max(0.0).sqrt())
+        };
+        let (mean_offset_beats, std_dev_beats) =
+            mean_and_deviation(self.sum_beats, self.sum_squares_beats);
+        let (mean_offset_ms, std_dev_ms) = mean_and_deviation(self.sum_ms, self.sum_squares_ms);
+        Stats {
+            hits: self.hits,
+            misses: self.misses,
+            extra_hits: self.extra_hits,
+            mean_offset_ms,
+            std_dev_ms,
+            mean_offset_beats,
+            std_dev_beats,
+        }
+    }
+}
+
+pub struct TimingScorer {
+    /// Note times followed by the rhythm's end, as `beat_bisect` expects.
+    times: Vec<f64>,
+    session: Accumulator,
+    bars: BTreeMap<isize, Accumulator>,
+    /// Notes with a hit that have not been judged yet.
+    matched: HashSet<isize>,
+    /// First note not yet judged; `None` until the first input arrives.
+    next_unjudged: Option<isize>,
+    last_hit: Option<ScoredHit>,
+    /// The engine's beat resets the scores are for, see `InputBeat::generation`.
+    generation: usize,
+}
+
+impl TimingScorer {
+    pub fn new(rhythm: Option<&ParserRhythm>) -> TimingScorer {
+        TimingScorer {
+            times: TimingScorer::times(rhythm),
+            session: Accumulator::default(),
+            bars: BTreeMap::new(),
+            matched: HashSet::new(),
+            next_unjudged: None,
+            last_hit: None,
+            generation: 0,
+        }
+    }
+
+    /// Rests are not played, so they are not scored. Same fallback as
+    /// `beat_bisect`: without notes to play, every beat is one.
+    fn times(rhythm: Option<&ParserRhythm>) -> Vec<f64> {
+        let rhythm = match rhythm {
+            Some(rhythm) if rhythm.notes.iter().any(|n| !n.rest) && rhythm.end > 0.0 => rhythm,
+            _ => return vec![0.0, 1.0],
+        };
+        let mut times: Vec<f64> = rhythm
+            .notes
+            .iter()
+            .filter(|n| !n.rest)
+            .map(|n| n.time)
+            .collect();
+        times.push(rhythm.end);
+        times
+    }
+
+    /// Starts over if the notes changed.
+    pub fn set_rhythm(&mut self, rhythm: Option<&ParserRhythm>) {
+        let times = TimingScorer::times(rhythm);
+        if times != self.times {
+            *self = TimingScorer {
+                generation: self.generation,
+                ..TimingScorer::new(rhythm)
+            };
+        }
+    }
+
+    fn reset(&mut self) {
+        self.session = Accumulator::default();
+        self.bars.clear();
+        self.matched.clear();
+        self.next_unjudged = None;
+        self.last_hit = None;
+    }
+
+    /// Starts over on input from a newer beat reset. Returns whether input of
+    /// `generation` counts; input from before the current reset does not.
+    fn follow(&mut self, generation: usize) -> bool {
+        if generation > self.generation {
+            self.reset();
+            self.generation = generation;
+        }
+        generation == self.generation
+    }
+
+    fn bar_accumulator(&mut self, bar: isize) -> &mut Accumulator {
+        if !self.bars.contains_key(&bar) && self.bars.len() >= MAX_BARS {
+            if let Some(&oldest) = self.bars.keys().next() {
+                self.bars.remove(&oldest);
+            }
+        }
+        self.bars.entry(bar).or_default()
+    }
+
+    fn notes_per_bar(&self) -> isize {
+        self.times.len() as isize - 1
+    }
+
+    fn note_beat(&self, note: isize) -> f64 {
+        let bar = note.div_euclid(self.notes_per_bar());
+        let index = note.rem_euclid(self.notes_per_bar()) as usize;
+        bar as f64 * self.times[self.times.len() - 1] + self.times[index]
+    }
+
+    fn bar(&self, note: isize) -> isize {
+        note.div_euclid(self.notes_per_bar())
+    }
+
+    fn nearest_note(&self, beat: f64) -> isize {
+        let before = beat_bisect(&self.times, beat);
+        if beat - self.note_beat(before) <= self.note_beat(before + 1) - beat {
+            before
+        } else {
+            before + 1
+        }
+    }
+
+    /// Notes before the first input are not judged.
+    fn start_at(&mut self, beat: f64) -> isize {
+        match self.next_unjudged {
+            Some(note) => note,
+            None => {
+                let note = self.nearest_note(beat);
+                self.next_unjudged = Some(note);
+                note
+            }
+        }
+    }
+
+    /// Matches `hit` to the nearest note at `bpm`. Returns `None` for extra
+    /// hits and hits from before the last beat reset.
+    pub fn score(&mut self, hit: &Hit, bpm: f64) -> Option<ScoredHit> {
+        if !self.follow(hit.generation) {
+            return None;
+        }
+        let next_unjudged = self.start_at(hit.beat);
+        let note = self.nearest_note(hit.beat);
+        let bar = self.bar(note);
+        if note < next_unjudged || !self.matched.insert(note) {
+            self.session.extra_hits += 1;
+            self.bar_accumulator(bar).extra_hits += 1;
+            return None;
+        }
+        let note_beat = self.note_beat(note);
+        let offset_beats = hit.beat - note_beat;
+        let scored = ScoredHit {
+            beat: hit.beat,
+            note_beat,
+            bar,
+            offset_beats,
+            offset_ms: offset_beats * 60_000.0 / bpm,
+        };
+        self.session.add(&scored);
+        self.bar_accumulator(bar).add(&scored);
+        self.last_hit = Some(scored.clone());
+        Some(scored)
+    }
+
+    /// Judges every note the input has moved past, counting those without a hit as missed.
+    pub fn advance(&mut self, input: InputBeat) {
+        if !self.follow(input.generation) {
+            return;
+        }
+        let beat = input.beat;
+        let mut note = self.start_at(beat);
+        while (self.note_beat(note) + self.note_beat(note + 1)) / 2.0 < beat {
+            if !self.matched.remove(&note) {
+                self.session.misses += 1;
+                let bar = self.bar(note);
+                self.bar_accumulator(bar).misses += 1;
+            }
+            note += 1;
+        }
+        self.next_unjudged = Some(note);
+    }
+
+    pub fn stats(&self) -> TimingStats {
+        TimingStats {
+            session: self.session.stats(),
+            bars: self
+                .bars
+                .iter()
+                .map(|(bar, accumulator)| BarStats {
+                    bar: *bar,
+                    stats: accumulator.stats(),
+                })
+                .collect(),
+            last_hit: self.last_hit.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structs::Note;
+
+    fn eighths() -> ParserRhythm {
+        ParserRhythm {
+            notes: vec![Note::at(0.0), Note::at(0.5)],
+            start: 0.0,
+            end: 1.0,
+        }
+    }
+
+    fn hit(beat: f64, generation: usize) -> Hit {
+        Hit {
+            beat,
+            strength: 1.0,
+            generation,
+        }
+    }
+
+    fn input(beat: f64, generation: usize) -> InputBeat {
+        InputBeat { beat, generation }
+    }
+
+    #[test]
+    fn scores_hits_after_a_reset_the_engine_has_not_reached_yet() {
+        let rhythm = eighths();
+        let mut scorer = TimingScorer::new(Some(&rhythm));
+        scorer.advance(input(40.0, 0));
+        // asked for a reset, but the audio thread is still on the old beat
+        scorer.advance(input(40.1, 0));
+        for i in 0..20 {
+            let beat = i as f64 * 0.5;
+            assert!(scorer.score(&hit(beat, 1), 120.0).is_some());
+            scorer.advance(input(beat + 0.1, 1));
+            // late input from before the reset is ignored
+            scorer.advance(input(41.0, 0));
+        }
+        let stats = scorer.stats().session;
+        assert_eq!(stats.hits, 20);
+        assert_eq!(stats.extra_hits, 0);
+        assert_eq!(stats.misses, 0);
+    }
+
+    #[test]
+    fn drops_hits_from_before_the_reset() {
+        let rhythm = eighths();
+        let mut scorer = TimingScorer::new(Some(&rhythm));
+        scorer.advance(input(0.0, 1));
+        assert!(scorer.score(&hit(40.0, 0), 120.0).is_none());
+        assert_eq!(scorer.stats().session.extra_hits, 0);
+    }
+
+    #[test]
+    fn keeps_the_generation_when_the_rhythm_changes() {
+        let mut scorer = TimingScorer::new(Some(&eighths()));
+        scorer.advance(input(0.0, 2));
+        scorer.set_rhythm(None);
+        assert!(scorer.score(&hit(1.0, 2), 120.0).is_some());
+        assert!(scorer.score(&hit(2.0, 1), 120.0).is_none());
+    }
+
+    #[test]
+    fn keeps_the_latest_bars() {
+        let rhythm = eighths();
+        let mut scorer = TimingScorer::new(Some(&rhythm));
+        let bars = MAX_BARS as isize + 10;
+        scorer.advance(input(0.0, 0));
+        scorer.advance(input(bars as f64, 0));
+        let stats = scorer.stats();
+        assert_eq!(stats.bars.len(), MAX_BARS);
+        assert_eq!(stats.bars[0].bar, 10);
+        assert_eq!(stats.session.misses, bars as usize * 2);
+    }
+
+    #[test]
+    fn does_not_score_rests() {
+        let mut rhythm = eighths();
+        rhythm.notes[1].rest = true;
+        let mut scorer = TimingScorer::new(Some(&rhythm));
+        scorer.advance(input(0.0, 0));
+        // nearer the rest than the note after it
+        let scored = scorer.score(&hit(0.6, 0), 60.0).unwrap();
+        assert_eq!(scored.note_beat, 1.0);
+        scorer.advance(input(4.0, 0));
+        assert_eq!(scorer.stats().session.misses, 3);
+    }
+
+    #[test]
+    fn scores_against_the_offbeat_when_the_downbeat_rests() {
+        let mut rhythm = eighths();
+        rhythm.notes[0].rest = true;
+        let mut scorer = TimingScorer::new(Some(&rhythm));
+        scorer.advance(input(0.0, 0));
+        let scored = scorer.score(&hit(0.55, 0), 60.0).unwrap();
+        assert_eq!(scored.note_beat, 0.5);
+        assert!((scored.offset_ms - 50.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn scores_every_beat_when_all_notes_rest() {
+        let mut rhythm = eighths();
+        rhythm.notes.iter_mut().for_each(|n| n.rest = true);
+        assert_eq!(TimingScorer::times(Some(&rhythm)), vec![0.0, 1.0]);
+    }
+}
@@ -0,0 +1,336 @@
+//! Plays backing tracks too long to decode whole. A thread decodes ahead,
+//! resampling to the engine's rate, into a bounded ring buffer that the audio
+//! thread reads from, starting over at the end of the file. Seeking moves the
+//! decoding thread, which waits for the audio thread to drop what it had
+//! buffered before going on from the new position; the frames that pass
+//! meanwhile are skipped, so a track rewound on beat 0 stays on the beat.
+
+use crate::read_audio_file::AudioFileReader;
+use crate::resample::{remix, StreamResampler};
+use rtrb::{Consumer, Producer, RingBuffer};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// How far ahead of playback the decoding thread gets.
+const BUFFER_SECONDS: f64 = 10.0;
+/// How long the decoding thread sleeps while the buffer is full.
+const FILL_WAIT: Duration = Duration::from_millis(5);
+
+/// A playback position asked for from outside the audio thread. Decoded tracks
+/// pick it up on the audio thread, streamed ones on their decoding thread.
+pub struct SeekRequest {
+    sample_rate: f64,
+    frames: Option<u64>,
+    frame: AtomicU64,
+    /// Bumped with every request.
+    count: AtomicUsize,
+}
+
+impl SeekRequest {
+    pub fn new(sample_rate: f64, frames: Option<u64>) -> SeekRequest {
+        SeekRequest {
+            sample_rate,
+            frames,
+            frame: AtomicU64::new(0),
+            count: AtomicUsize::new(0),
+        }
+    }
+
+    /// Asks for playback to go on from `seconds` into the track, wrapping past the end.
+    pub fn seek(&self, seconds: f64) {
+        self.seek_frame((seconds.max(0.0) * self.sample_rate) as u64);
+    }
+
+    pub fn seek_frame(&self, frame: u64) {
+        let frame = match self.frames {
+            Some(frames) => frame % frames,
+            None => frame,
+        };
+        self.frame.store(frame, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Release);
+    }
+
+    /// The number of requests so far and the frame last asked for.
+    pub fn latest(&self) -> (usize, u64) {
+        let count = self.count.load(Ordering::Acquire);
+        (count, self.frame.load(Ordering::Relaxed))
+    }
+}
+
+struct Shared {
+    seek: Arc<SeekRequest>,
+    /// The last request the decoding thread has moved to.
+    sought: AtomicUsize,
+    /// The last request the audio thread has dropped the old frames for.
+    drained: AtomicUsize,
+    /// Set once the decoding thread has stopped.
+    finished: AtomicBool,
+}
+
+/// The audio thread's end of a streamed track: interleaved stereo at the
+/// sample rate it was started for.
+pub struct TrackStream {
+    samples: Consumer<f32>,
+    shared: Arc<Shared>,
+    /// The frames either side of the playback position, once in sync with the
+    /// decoding thread.
+    current: [f32; 2],
+    next: [f32; 2],
+    synced: bool,
+    requested: usize,
+    /// Frames played since the last seek was asked for, to drop once the new
+    /// position arrives.
+    skip: usize,
+}
+
+/// Starts decoding `reader` at `sample_rate` on a thread of its own, which
+/// ends when the stream is dropped.
+pub fn stream(reader: AudioFileReader, sample_rate: f64) -> Result<TrackStream, String> {
+    let resampler = StreamResampler::new(reader.sample_rate, sample_rate, 2)?;
+    let capacity = (sample_rate * BUFFER_SECONDS) as usize * 2;
+    let (producer, consumer) = RingBuffer::new(capacity);
+    let frames = reader
+        .frames
+        .map(|n| (n as f64 * sample_rate / reader.sample_rate).round() as u64);
+    let shared = Arc::new(Shared {
+        seek: Arc::new(SeekRequest::new(sample_rate, frames)),
+        sought: AtomicUsize::new(0),
+        drained: AtomicUsize::new(0),
+        finished: AtomicBool::new(false),
+    });
+    let decoder_shared = shared.clone();
+    thread::Builder::new()
+        .name("track stream".to_string())
+        .spawn(move || {
+            decode_ahead(reader, resampler, producer, &decoder_shared);
+            decoder_shared.finished.store(true, Ordering::Release);
+        })
+        .map_err(|e| e.to_string())?;
+    let stream = TrackStream {
+        samples: consumer,
+        shared,
+        current: [0.0; 2],
+        next: [0.0; 2],
+        synced: false,
+        requested: 0,
+        skip: 0,
+    };
+    Ok(stream)
+}
+
+impl TrackStream {
+    pub fn seek_request(&self) -> Arc<SeekRequest> {
+        self.shared.seek.clone()
+    }
+
+    /// Whether the decoding thread has stopped, at the end of a file that had
+    /// no audio or on an error. What it decoded before is still played.
+    pub fn is_finished(&self) -> bool {
+        self.shared.finished.load(Ordering::Acquire)
+    }
+
+    /// Seconds of audio decoded ahead.
+    pub fn buffered(&self, sample_rate: f64) -> f64 {
+        (self.samples.slots() / 2) as f64 / sample_rate
+    }
+
+    /// The left (0) or right (1) channel `fraction` of the way to the next frame.
+    pub fn sample(&self, side: usize, fraction: f32) -> f32 {
+        let a = self.current[side];
+        a + (self.next[side] - a) * fraction
+    }
+
+    /// Seeks from the audio thread, counting from the frame being played now.
+    pub fn seek(&mut self, frame: u64) {
+        self.shared.seek.seek_frame(frame);
+        self.take_seek();
+    }
+
+    /// Moves on by one frame. Plays silence while seeking or when the
+    /// decoding thread has fallen behind.
+    pub fn next_frame(&mut self) {
+        self.take_seek();
+        if self.synced {
+            self.current = self.next;
+            self.next = self.pop().unwrap_or([0.0; 2]);
+        } else {
+            self.skip += 1;
+        }
+        self.sync();
+    }
+
+    /// Catches up with the decoding thread: drops what was buffered before it
+    /// moved, then the frames played since the seek was asked for.
+    pub fn sync(&mut self) {
+        self.take_seek();
+        let shared = &*self.shared;
+        let sought = shared.sought.load(Ordering::Acquire);
+        if sought != shared.drained.load(Ordering::Relaxed) {
+            if let Ok(old) = self.samples.read_chunk(self.samples.slots()) {
+                old.commit_all();
+            }
+            shared.drained.store(sought, Ordering::Release);
+        }
+        if self.synced || sought != self.requested {
+            return;
+        }
+        while self.skip > 0 {
+            if self.pop().is_none() {
+                return;
+            }
+            self.skip -= 1;
+        }
+        if self.samples.slots() >= 4 {
+            self.current = self.pop().unwrap_or([0.0; 2]);
+            self.next = self.pop().unwrap_or([0.0; 2]);
+            self.synced = true;
+        }
+    }
+
+    fn take_seek(&mut self) {
+        let (requested, _) = self.shared.seek.latest();
+        if requested != self.requested {
+            self.requested = requested;
+            self.synced = false;
+            self.skip = 0;
+            self.current = [0.0; 2];
+            self.next = [0.0; 2];
+        }
+    }
+
+    fn pop(&mut self) -> Option<[f32; 2]> {
+        let frame = self.samples.read_chunk(2).ok()?;
+        let (first, second) = frame.as_slices();
+        let mut values = [0.0; 2];
+        for (value, sample) in values.iter_mut().zip(first.iter().chain(second)) {
+            *value = *sample;
+        }
+        frame.commit_all();
+        Some(values)
+    }
+}
+
+fn decode_ahead(
+    mut reader: AudioFileReader,
+    mut resampler: StreamResampler,
+    mut samples: Producer<f32>,
+    shared: &Shared,
+) {
+    let file_frames_per_frame = reader.sample_rate / shared.seek.sample_rate;
+    let mut served = 0;
+    let mut pending: Vec<f32> = vec![];
+    let mut written = 0;
+    let mut decoded_this_pass = false;
+    loop {
+        if samples.is_abandoned() {
+            return;
+        }
+        let (requested, frame) = shared.seek.latest();
+        if requested != served {
+            served = requested;
+            let file_frame = (frame as f64 * file_frames_per_frame).round() as u64;
+            if let Err(err) = reader.seek(file_frame) {
+                println!("could not seek the backing track: {}", err);
+            }
+            resampler.reset();
+            pending.clear();
+            written = 0;
+            shared.sought.store(served, Ordering::Release);
+            while shared.drained.load(Ordering::Acquire) != served {
+                if samples.is_abandoned() || shared.seek.latest().0 != served {
+                    break;
+                }
+                thread::sleep(FILL_WAIT);
+            }
+            continue;
+        }
+
+        if written < pending.len() {
+            // whole frames only, so the audio thread never reads half of one
+            let n = samples.slots().min(pending.len() - written) / 2 * 2;
+            if n == 0 {
+                thread::sleep(FILL_WAIT);
+                continue;
+            }
+            if let Ok(chunk) = samples.write_chunk_uninit(n) {
+                chunk.fill_from_iter(pending[written..written + n].iter().copied());
+                written += n;
+            }
+            continue;
+        }
+
+        match reader.next_chunk() {
+            Ok(Some(chunk)) => {
+                decoded_this_pass |= chunk.frames() > 0;
+                pending = match resampler.process(&remix(&chunk, 2).samples) {
+                    Ok(resampled) => resampled,
+                    Err(err) => {
+                        println!("could not resample the backing track: {}", err);
+                        return;
+                    }
+                };
+                written = 0;
+            }
+            // the resampler goes on from the end into the start, as playback does
+            Ok(None) if decoded_this_pass => {
+                decoded_this_pass = false;
+                if let Err(err) = reader.seek(0) {
+                    println!("could not restart the backing track: {}", err);
+                    return;
+                }
+            }
+            Ok(None) => return,
+            Err(err) => {
+                println!("stopped streaming the backing track: {}", err);
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hound::{SampleFormat, WavSpec, WavWriter};
+
+    #[test]
+    fn streams_at_the_engine_rate() {
+        let path = std::env::temp_dir().join("track_stream_test.wav");
+        let spec = WavSpec {
+            channels: 1,
+            sample_rate: 22050,
+            bits_per_sample: 32,
+            sample_format: SampleFormat::Float,
+        };
+        let mut writer = WavWriter::create(&path, spec).unwrap();
+        for i in 0..22050 {
+            writer
+                .write_sample(if i == 11025 { 1.0f32 } else { 0.0 })
+                .unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let reader = AudioFileReader::open(path.to_str().unwrap()).unwrap();
+        let mut stream = stream(reader, 44100.0).unwrap();
+        while stream.buffered(44100.0) < 1.5 {
+            thread::sleep(FILL_WAIT);
+        }
+        stream.sync();
+        let played: Vec<f32> = (0..44100)
+            .map(|_| {
+                let sample = stream.sample(0, 0.0);
+                stream.next_frame();
+                sample
+            })
+            .collect();
+        std::fs::remove_file(&path).unwrap();
+
+        let peak = (0..played.len())
+            .max_by(|a, b| played[*a].partial_cmp(&played[*b]).unwrap())
+            .unwrap();
+        assert!((22049..=22051).contains(&peak), "peak at {}", peak);
+        assert_eq!(stream.seek_request().frames, Some(44100));
+    }
+}
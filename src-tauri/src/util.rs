@@ -1,4 +1,4 @@
-fn bisect<T: std::cmp::PartialOrd>(arr: &Vec<T>, val: T) -> isize {
+fn bisect<T: std::cmp::PartialOrd>(arr: &[T], val: T) -> isize {
     let mut lo = 0;
     let mut hi = arr.len();
     while lo < hi {
@@ -12,10 +12,10 @@ fn bisect<T: std::cmp::PartialOrd>(arr: &Vec<T>, val: T) -> isize {
     lo as isize - 1
 }
 
-pub fn beat_bisect(subdivisions: &Vec<f64>, beat: f64) -> isize {
-    let default_subdivisions = &vec![0.0, 1.0];
+pub fn beat_bisect(subdivisions: &[f64], beat: f64) -> isize {
+    let default_subdivisions: &[f64] = &[0.0, 1.0];
     let subdivisions = if subdivisions.len() < 2 {
-        &default_subdivisions
+        default_subdivisions
     } else {
         subdivisions
     };
@@ -35,3 +35,10 @@ pub fn mod_add(a: usize, b: usize, max: usize) -> usize {
     }
     res
 }
+
+/// Splits interleaved samples into one Vec per channel.
+pub fn deinterleave(samples: &[f32], channels: usize) -> Vec<Vec<f32>> {
+    (0..channels)
+        .map(|ch| samples.iter().skip(ch).step_by(channels).copied().collect())
+        .collect()
+}
@@ -0,0 +1,60 @@
+use crate::structs::SoundingSample;
+use std::sync::Arc;
+
+/// Most drum samples that can ring at once; triggering another steals the oldest.
+pub const MAX_VOICES: usize = 32;
+
+/// Fixed capacity set of sounding samples. Never grows past `MAX_VOICES`, so
+/// triggering and finishing voices does not allocate on the audio thread.
+pub struct VoicePool {
+    voices: Vec<SoundingSample>,
+}
+
+impl VoicePool {
+    pub fn new() -> VoicePool {
+        VoicePool {
+            voices: Vec::with_capacity(MAX_VOICES),
+        }
+    }
+
+    /// Starts the interleaved stereo `sample`, scaled by `gains` on the left and right.
+    pub fn trigger(&mut self, sample: &Arc<Vec<f32>>, gains: [f32; 2]) {
+        if self.voices.len() == MAX_VOICES {
+            self.voices.remove(0);
+        }
+        self.voices.push(SoundingSample {
+            sample: sample.clone(),
+            pos: 0,
+            gains,
+        });
+    }
+
+    /// Sums the current sample of every voice and advances them, dropping voices
+    /// that have finished. The sum is computed even when muted so voices keep time.
+    /// A dropped voice's sample is still held by its kit, or by `RetiredKits`
+    /// once the kit is replaced, so it is not freed here.
+    pub fn next_sample(&mut self) -> f32 {
+        let mut out = 0.0;
+        for j in (0..self.voices.len()).rev() {
+            let sounding = &mut self.voices[j];
+            if sounding.pos < sounding.sample.len() {
+                out += sounding.sample[sounding.pos] * sounding.gains[sounding.pos % 2];
+                sounding.pos += 1;
+            } else {
+                self.voices.remove(j);
+            }
+        }
+        out
+    }
+
+    /// Stops every voice. Off the audio thread only, as it may free samples.
+    pub fn clear(&mut self) {
+        self.voices.clear();
+    }
+}
+
+impl Default for VoicePool {
+    fn default() -> VoicePool {
+        VoicePool::new()
+    }
+}
@@ -0,0 +1,21 @@
+use hound::{SampleFormat, WavSpec, WavWriter};
+
+/// Writes non-interleaved channels to a 32-bit float WAV file.
+pub fn write_wav(filename: &str, channels: &[Vec<f32>], sample_rate: u32) -> Result<(), String> {
+    let spec = WavSpec {
+        channels: channels.len() as u16,
+        sample_rate,
+        bits_per_sample: 32,
+        sample_format: SampleFormat::Float,
+    };
+    let mut writer =
+        WavWriter::create(filename, spec).map_err(|e| format!("Failed to create file: {}", e))?;
+    let num_frames = channels.iter().map(|c| c.len()).max().unwrap_or(0);
+    for i in 0..num_frames {
+        for channel in channels {
+            let value = *channel.get(i).unwrap_or(&0.0);
+            writer.write_sample(value).map_err(|e| e.to_string())?;
+        }
+    }
+    writer.finalize().map_err(|e| e.to_string())
+}
@@ -9,8 +9,17 @@ import {
   JsConfig,
   isJsConfigKey,
   ConfigKey,
+  Settings,
+  rustConfigFromSettings,
 } from "./config";
 import { Input } from "./Input";
+import { Devices } from "./Devices";
+import { TimingStats } from "./TimingStats";
+import { Recording } from "./Recording";
+import { LoopFile } from "./LoopFile";
+import { Looper } from "./Looper";
+import { Presets } from "./Presets";
+import { BackingTrack, BackingTrackInfo } from "./BackingTrack";
 import { appWindow } from "@tauri-apps/api/window";
 import { listen } from "@tauri-apps/api/event";
 import { SlidingDivision } from "./SlidingDivision";
@@ -67,9 +76,21 @@ const Section = ({
   </div>
 );
 
+interface Hit {
+  beat: number;
+  strength: number;
+}
+
 const App = () => {
   const [log, setLog] = useState("log");
+  const [lastHit, setLastHit] = useState<Hit | null>(null);
   const [hideConfig, setHideConfig] = useState(false);
+  useEffect(() => {
+    const unlisten = listen<Hit>("hit", (event) => setLastHit(event.payload));
+    return () => {
+      unlisten.then((f) => f());
+    };
+  }, []);
   useEffect(() => {
     const setListener = async () => {
       const unlisten = await listen("log", (msg) => {
@@ -130,8 +151,17 @@ const App = () => {
     return () => { clearInterval(interval); getArrayAdded.current = false;};
   }, []);
 
+  const [backingTrack, setBackingTrack] = useState<BackingTrackInfo | null>(
+    null
+  );
+  useEffect(() => {
+    invoke<BackingTrackInfo | null>("get_backing_track").then(setBackingTrack);
+  }, []);
+
   const pickNewMp3 = (filename: string) => () => {
-    invoke("set_mp3_buffer", { filename });
+    invoke<BackingTrackInfo>("set_mp3_buffer", { filename })
+      .then(setBackingTrack)
+      .catch((err) => setLog(`could not load ${filename}: ${err}`));
   };
 
   useEffect(() => {
@@ -152,6 +182,12 @@ const App = () => {
 
   const [rustConfig, setRustConfig] = useState(defaultRustConfig);
   const [jsConfig, setJsConfig] = useState(defaultJsConfig);
+  useEffect(() => {
+    // start from what was saved last time
+    invoke<Settings>("get_settings").then((settings) =>
+      setRustConfig(rustConfigFromSettings(settings))
+    );
+  }, []);
   const get = <T extends ConfigKey>(k: T) => {
     if (isRustConfigKey(k)) return rustConfig[k] as RustConfig[typeof k];
     else if (isJsConfigKey(k)) return jsConfig[k] as JsConfig[typeof k];
@@ -202,10 +238,18 @@ const App = () => {
 
     const newConfig = { ...rustConfig, ...args };
     setRustConfig(newConfig);
-    const newConfigForRust = snakeCaseKeys(unwrapValues(newConfig));
+    const newConfigForRust = snakeCaseKeys(
+      unwrapValues({
+        ...newConfig,
+        layers: newConfig.layers.map((layer) => ({
+          ...layer,
+          rhythm: layer.rhythm.val,
+        })),
+      })
+    );
     // console.log("calling set_config with " + newConfigForRust);
     // alert(JSON.stringify(newConfigForRust));
-    invoke("set_config", { newConfig: newConfigForRust });
+    invoke("set_config", { newConfig: newConfigForRust, frontend: newConfig });
     // console.log("called set_config");
   };
 
@@ -335,12 +379,30 @@ const App = () => {
     >
       <>
         <button onClick={resetBeat}>RESET TIME</button>
-        {/* <button onClick={pickNewMp3("/Users/eric/Music/Logic/Logic_3.wav")}>
-				NEW MP3 1
-			</button>
-			<button onClick={pickNewMp3("/Users/eric/Music/Logic/Logic_4.wav")}>
-				NEW MP3 2
-			</button> */}
+
+        <Section label="presets">
+          <Presets
+            onLoad={(settings) =>
+              setRustConfig(rustConfigFromSettings(settings))
+            }
+          />
+        </Section>
+
+        <Section label="devices">
+          <Devices
+            onLatencyMeasured={(frames) =>
+              updateRustConfig({ bufferCompensation: frames })
+            }
+          />
+        </Section>
+
+        <Section label="timing">
+          <TimingStats />
+        </Section>
+
+        <Section label="recording">
+          <Recording />
+        </Section>
 
         <Section label="bpm">
           <Input
@@ -354,22 +416,25 @@ const App = () => {
 
         <Section label="click">
           <Input label="click" _key="clickOn" set={set} get={get} />
-          <Input
-            label="click rhythm"
-            _key="audioSubdivisions"
-            set={set}
-            get={get}
-          />
+          <Input label="rhythm layers" _key="layers" set={set} get={get} />
           <Input label="click toggle" _key="clickToggle" set={set} get={get} />
           <Input label="click volume" _key="clickVolume" set={set} get={get} />
+          <Input label="click sound" _key="click" set={set} get={get} />
         </Section>
 
         <Section label="drum">
           <Input label="drum on" _key="drumOn" set={set} get={get} />
+          <Input label="kit" _key="kit" set={set} get={get} />
         </Section>
 
         <Section label="gain">
           <Input label="input gain" _key="audioInGain" set={set} get={get} />
+          <Input
+            label="input channels"
+            _key="inputChannels"
+            set={set}
+            get={get}
+          />
           <Input label="visual gain" _key="visualGain" set={set} get={get} />
         </Section>
 
@@ -382,9 +447,16 @@ const App = () => {
             set={set}
             get={get}
           />
+          <Looper />
+          <LoopFile />
         </Section>
 
         <Section label="file">
+          <BackingTrack
+            track={backingTrack}
+            onLoad={(filename) => pickNewMp3(filename)()}
+            onClear={() => setBackingTrack(null)}
+          />
           <Input label="play file" _key="playFile" set={set} get={get} />
         </Section>
 
@@ -424,6 +496,12 @@ const App = () => {
             set={set}
             get={get}
           />
+          {lastHit && (
+            <div>
+              last hit: beat {lastHit.beat.toFixed(3)}, strength{" "}
+              {lastHit.strength.toFixed(2)}
+            </div>
+          )}
         </Section>
         {/* <Input label="canvas height" _key= "canvasHeight" /> */}
         {/* <Input label="canvas width" _key= "canvasWidth" /> */}
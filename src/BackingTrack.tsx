@@ -0,0 +1,97 @@
+import { invoke } from "@tauri-apps/api";
+import { open } from "@tauri-apps/api/dialog";
+import { listen } from "@tauri-apps/api/event";
+import { useEffect, useState } from "react";
+import { audioFilters } from "./formats";
+
+export interface BackingTrackInfo {
+  path: string;
+  duration: number;
+  channels: number;
+  sample_rate: number;
+  streamed: boolean;
+}
+
+interface FileLoadProgress {
+  path: string;
+  stage: "decoding" | "converting" | "loaded" | "failed" | "cancelled";
+  progress: number | null;
+  error: string | null;
+}
+
+export const BackingTrack = ({
+  track,
+  onLoad,
+  onClear,
+}: {
+  track: BackingTrackInfo | null;
+  onLoad: (filename: string) => void;
+  onClear: () => void;
+}) => {
+  const [loading, setLoading] = useState<FileLoadProgress | null>(null);
+
+  useEffect(() => {
+    const unlisten = listen<FileLoadProgress>("file_load_progress", (event) =>
+      setLoading(event.payload)
+    );
+    return () => {
+      unlisten.then((f) => f());
+    };
+  }, []);
+
+  const busy =
+    loading?.stage === "decoding" || loading?.stage === "converting";
+
+  const pick = async () => {
+    const filename = await open({
+      filters: await audioFilters(),
+    });
+    if (typeof filename === "string") onLoad(filename);
+  };
+
+  const clear = () => {
+    invoke("clear_backing_track").then(onClear);
+  };
+
+  return (
+    <>
+      {track ? (
+        <div>
+          {track.path}: {track.duration.toFixed(1)} s, {track.channels}{" "}
+          channels, {track.sample_rate} Hz
+          {track.streamed && ", streamed from disk"}
+        </div>
+      ) : (
+        <div>no file loaded</div>
+      )}
+      {loading && loading.stage !== "loaded" && (
+        <div>
+          {loading.path}: {loading.stage}
+          {loading.progress !== null &&
+            busy &&
+            ` ${Math.round(loading.progress * 100)}%`}
+          {loading.error && `: ${loading.error}`}
+        </div>
+      )}
+      {track && track.duration > 0 && (
+        <input
+          type="range"
+          min={0}
+          max={track.duration}
+          step={0.1}
+          defaultValue={0}
+          onChange={(e) =>
+            invoke("seek_backing_track", {
+              seconds: parseFloat(e.target.value),
+            })
+          }
+        />
+      )}
+      <button onClick={pick}>load file</button>
+      {busy && (
+        <button onClick={() => invoke("cancel_file_load")}>cancel</button>
+      )}
+      {track && <button onClick={clear}>unload</button>}
+    </>
+  );
+};
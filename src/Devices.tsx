@@ -0,0 +1,151 @@
+import { useEffect, useState } from "react";
+import { invoke } from "@tauri-apps/api";
+
+interface DeviceInfo {
+  id: string;
+  name: string;
+  input_channels: number;
+  output_channels: number;
+  sample_rates: number[];
+}
+
+interface LatencyMeasurement {
+  frames: number;
+  milliseconds: number;
+  sample_rate: number;
+  confidence: number;
+}
+
+const DEFAULT_DEVICE = "";
+const SAMPLE_RATES = [44100, 48000, 88200, 96000];
+const BUFFER_SIZES = [64, 128, 256, 512, 1024, 2048];
+
+const DeviceSelect = ({
+  label,
+  devices,
+  value,
+  onChange,
+}: {
+  label: string;
+  devices: DeviceInfo[];
+  value: string;
+  onChange: (id: string) => void;
+}) => (
+  <div style={{ display: "flex", flexDirection: "row", gap: "4px" }}>
+    <label>{label}</label>
+    <select value={value} onChange={(e) => onChange(e.target.value)}>
+      <option value={DEFAULT_DEVICE}>default</option>
+      {devices.map((d) => (
+        <option key={d.id} value={d.id}>
+          {d.name}
+        </option>
+      ))}
+    </select>
+  </div>
+);
+
+export const Devices = ({
+  onLatencyMeasured,
+}: {
+  onLatencyMeasured: (frames: number) => void;
+}) => {
+  const [devices, setDevices] = useState<DeviceInfo[]>([]);
+  const [input, setInput] = useState(DEFAULT_DEVICE);
+  const [output, setOutput] = useState(DEFAULT_DEVICE);
+  const [status, setStatus] = useState("");
+  const [sampleRate, setSampleRate] = useState(44100);
+  const [bufferSize, setBufferSize] = useState(2048);
+
+  const refresh = () => {
+    invoke<DeviceInfo[]>("list_audio_devices")
+      .then(setDevices)
+      .catch((e) => setStatus(String(e)));
+  };
+  useEffect(refresh, []);
+
+  const select = () => {
+    const devices = {
+      input: input === DEFAULT_DEVICE ? null : input,
+      output: output === DEFAULT_DEVICE ? null : output,
+    };
+    invoke("select_audio_devices", devices)
+      .then((opened) => {
+        setStatus(JSON.stringify(opened));
+        // reuse the round trip measured earlier for this pair, if any
+        return invoke<LatencyMeasurement | null>(
+          "get_measured_latency",
+          devices
+        );
+      })
+      .then((latency) => latency && onLatencyMeasured(latency.frames))
+      .catch((e) => setStatus(String(e)));
+  };
+
+  const measureLatency = () => {
+    setStatus("measuring latency...");
+    invoke<LatencyMeasurement>("measure_latency")
+      .then((latency) => {
+        setStatus(
+          `latency: ${latency.frames} frames (${latency.milliseconds.toFixed(
+            1
+          )} ms, confidence ${latency.confidence.toFixed(2)})`
+        );
+        onLatencyMeasured(latency.frames);
+      })
+      .catch((e) => setStatus(String(e)));
+  };
+
+  const applySettings = () => {
+    invoke("set_stream_settings", { sampleRate, bufferSize })
+      .then((opened) => setStatus(JSON.stringify(opened)))
+      .catch((e) => setStatus(String(e)));
+  };
+
+  return (
+    <>
+      <DeviceSelect
+        label="input"
+        devices={devices.filter((d) => d.input_channels > 0)}
+        value={input}
+        onChange={setInput}
+      />
+      <DeviceSelect
+        label="output"
+        devices={devices.filter((d) => d.output_channels > 0)}
+        value={output}
+        onChange={setOutput}
+      />
+      <div style={{ display: "flex", flexDirection: "row", gap: "4px" }}>
+        <button onClick={refresh}>refresh</button>
+        <button onClick={select}>use devices</button>
+        <button onClick={measureLatency}>measure latency</button>
+      </div>
+      <div style={{ display: "flex", flexDirection: "row", gap: "4px" }}>
+        <label>sample rate</label>
+        <select
+          value={sampleRate}
+          onChange={(e) => setSampleRate(parseInt(e.target.value))}
+        >
+          {SAMPLE_RATES.map((rate) => (
+            <option key={rate} value={rate}>
+              {rate}
+            </option>
+          ))}
+        </select>
+        <label>buffer size</label>
+        <select
+          value={bufferSize}
+          onChange={(e) => setBufferSize(parseInt(e.target.value))}
+        >
+          {BUFFER_SIZES.map((size) => (
+            <option key={size} value={size}>
+              {size}
+            </option>
+          ))}
+        </select>
+        <button onClick={applySettings}>apply</button>
+      </div>
+      {status && <div>{status}</div>}
+    </>
+  );
+};
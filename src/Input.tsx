@@ -1,5 +1,5 @@
 import { useRef, useState } from "react";
-import { Config, ConfigKey } from "./config";
+import { Config, ConfigKey, defaultLayer, Layer } from "./config";
 import parser1 from "./parser1";
 import parser2 from "./parser2";
 
@@ -127,6 +127,281 @@ const NumberInput = ({ label, _key, get, set, validate }: II<number>) => {
   );
 };
 
+type InputChannel = Config["inputChannels"][number];
+
+const InputChannelsInput = ({ label, _key, get, set }: II<InputChannel[]>) => {
+  const channels = get(_key);
+  const update = (index: number, changes: Partial<InputChannel>) =>
+    set(
+      _key,
+      channels.map((c, i) => (i === index ? { ...c, ...changes } : c))
+    );
+  const number = (index: number, key: "channel" | "gain" | "pan") => (
+    <input
+      type="number"
+      step={key === "channel" ? 1 : 0.1}
+      value={channels[index][key]}
+      onChange={(e) => {
+        const val = parseFloat(e.target.value);
+        if (!isNaN(val)) update(index, { [key]: val });
+      }}
+      style={{ width: "4em" }}
+    />
+  );
+  const toggle = (
+    index: number,
+    key: "to_loop" | "to_monitor" | "to_visualizer"
+  ) => (
+    <input
+      type="checkbox"
+      checked={channels[index][key]}
+      onChange={() => update(index, { [key]: !channels[index][key] })}
+    />
+  );
+  return (
+    <div style={{ display: "flex", flexDirection: "column", gap: "4px" }}>
+      <label>{label}</label>
+      {channels.map((_, i) => (
+        <div
+          key={i}
+          style={{ display: "flex", flexDirection: "row", gap: "4px" }}
+        >
+          <label>in</label>
+          {number(i, "channel")}
+          <label>gain</label>
+          {number(i, "gain")}
+          <label>pan</label>
+          {number(i, "pan")}
+          <label>loop</label>
+          {toggle(i, "to_loop")}
+          <label>monitor</label>
+          {toggle(i, "to_monitor")}
+          <label>visual</label>
+          {toggle(i, "to_visualizer")}
+          <button onClick={() => set(_key, channels.filter((_, j) => j !== i))}>
+            remove
+          </button>
+        </div>
+      ))}
+      <button
+        onClick={() =>
+          set(_key, [
+            ...channels,
+            {
+              channel: channels.length,
+              gain: 1.0,
+              pan: 0.0,
+              to_loop: true,
+              to_monitor: true,
+              to_visualizer: true,
+            },
+          ])
+        }
+      >
+        add input channel
+      </button>
+    </div>
+  );
+};
+
+const KitInput = ({ label, _key, get, set }: II<Record<string, string>>) => {
+  const kit = get(_key);
+  const [sound, setSound] = useState("");
+  const entries = Object.entries(kit);
+  return (
+    <div style={{ display: "flex", flexDirection: "column", gap: "4px" }}>
+      <label>{label}</label>
+      {entries.map(([name, file]) => (
+        <div
+          key={name}
+          style={{ display: "flex", flexDirection: "row", gap: "4px" }}
+        >
+          <label style={{ width: "4em" }}>{name}</label>
+          <input
+            value={file}
+            onChange={(e) => set(_key, { ...kit, [name]: e.target.value })}
+            style={{ width: "12em" }}
+          />
+          <button
+            onClick={() =>
+              set(
+                _key,
+                Object.fromEntries(entries.filter(([n]) => n !== name))
+              )
+            }
+          >
+            remove
+          </button>
+        </div>
+      ))}
+      <div style={{ display: "flex", flexDirection: "row", gap: "4px" }}>
+        <input
+          placeholder="sound"
+          value={sound}
+          onChange={(e) => setSound(e.target.value)}
+          style={{ width: "4em" }}
+        />
+        <button
+          disabled={!sound || sound in kit}
+          onClick={() => {
+            set(_key, { ...kit, [sound]: `${sound}.wav` });
+            setSound("");
+          }}
+        >
+          add sound
+        </button>
+      </div>
+    </div>
+  );
+};
+
+type ClickSettings = Config["click"];
+const clickLevels = ["downbeat", "beat", "subdivision"] as const;
+
+const ClickInput = ({ label, _key, get, set }: II<ClickSettings>) => {
+  const click = get(_key);
+  const update = (changes: Partial<ClickSettings>) =>
+    set(_key, { ...click, ...changes });
+  const number = (
+    value: number,
+    onChange: (val: number) => void,
+    step = 1
+  ) => (
+    <input
+      type="number"
+      min={0}
+      step={step}
+      value={value}
+      onChange={(e) => {
+        const val = parseFloat(e.target.value);
+        if (!isNaN(val)) onChange(val);
+      }}
+      style={{ width: "4em" }}
+    />
+  );
+  return (
+    <div style={{ display: "flex", flexDirection: "column", gap: "4px" }}>
+      <div style={{ display: "flex", flexDirection: "row", gap: "4px" }}>
+        <label>{label}</label>
+        <select
+          value={click.timbre}
+          onChange={(e) =>
+            update({ timbre: e.target.value as ClickSettings["timbre"] })
+          }
+        >
+          <option value="woodblock">woodblock</option>
+          <option value="beep">beep</option>
+          <option value="noise">noise</option>
+          <option value="sample">sample</option>
+        </select>
+        {click.timbre === "sample" && (
+          <input
+            value={click.sample}
+            onChange={(e) => update({ sample: e.target.value })}
+            style={{ width: "4em" }}
+          />
+        )}
+        <label>attack ms</label>
+        {number(click.attack * 1000, (ms) => update({ attack: ms / 1000 }))}
+        <label>decay ms</label>
+        {number(click.decay * 1000, (ms) => update({ decay: ms / 1000 }))}
+        <label>beats per bar</label>
+        {number(click.beats_per_bar, (n) =>
+          update({ beats_per_bar: Math.max(1, Math.round(n)) })
+        )}
+      </div>
+      {clickLevels.map((level) => (
+        <div
+          key={level}
+          style={{ display: "flex", flexDirection: "row", gap: "4px" }}
+        >
+          <label style={{ width: "6em" }}>{level}</label>
+          <label>gain</label>
+          {number(
+            click[level].gain,
+            (gain) => update({ [level]: { ...click[level], gain } }),
+            0.1
+          )}
+          <label>pitch</label>
+          {number(
+            click[level].pitch,
+            (pitch) => update({ [level]: { ...click[level], pitch } }),
+            10
+          )}
+        </div>
+      ))}
+    </div>
+  );
+};
+
+const LayersInput = ({ label, _key, get, set }: II<Layer[]>) => {
+  const layers = get(_key);
+  const update = (index: number, changes: Partial<Layer>) =>
+    set(
+      _key,
+      layers.map((l, i) => (i === index ? { ...l, ...changes } : l))
+    );
+  const number = (index: number, key: "gain" | "pan") => (
+    <input
+      type="number"
+      step={0.1}
+      min={key === "pan" ? -1 : 0}
+      max={key === "pan" ? 1 : undefined}
+      value={layers[index][key]}
+      onChange={(e) => {
+        const val = parseFloat(e.target.value);
+        if (!isNaN(val)) update(index, { [key]: val });
+      }}
+      style={{ width: "4em" }}
+    />
+  );
+  return (
+    <div style={{ display: "flex", flexDirection: "column", gap: "4px" }}>
+      <label>{label}</label>
+      {layers.map((layer, i) => (
+        <div
+          key={i}
+          style={{ display: "flex", flexDirection: "row", gap: "4px" }}
+        >
+          <ParserArrayInput
+            label="rhythm"
+            _key={_key}
+            get={get}
+            set={(_: ConfigKey, rhythm: Layer["rhythm"]) =>
+              update(i, { rhythm })
+            }
+            parser={parser2}
+            val={layer.rhythm}
+          />
+          <label>sound</label>
+          <input
+            value={layer.sound ?? ""}
+            placeholder="ride"
+            onChange={(e) => update(i, { sound: e.target.value || null })}
+            style={{ width: "4em" }}
+          />
+          <label>gain</label>
+          {number(i, "gain")}
+          <label>pan</label>
+          {number(i, "pan")}
+          <label>mute</label>
+          <input
+            type="checkbox"
+            checked={layer.mute}
+            onChange={() => update(i, { mute: !layer.mute })}
+          />
+          <button onClick={() => set(_key, layers.filter((_, j) => j !== i))}>
+            remove
+          </button>
+        </div>
+      ))}
+      <button onClick={() => set(_key, [...layers, defaultLayer])}>
+        add layer
+      </button>
+    </div>
+  );
+};
+
 // @ts-ignore
 export const Input = (props: InputProps) => {
   const { _key, get } = props;
@@ -139,6 +414,10 @@ export const Input = (props: InputProps) => {
           return <ParserArrayInput {...{ ...props, parser: parser1, val }} />;
         } else if (val.type === "parser2") {
           return <ParserArrayInput {...{ ...props, parser: parser2, val }} />;
+        } else if (_key === "kit") {
+          return <KitInput {...props} />;
+        } else if (_key === "click") {
+          return <ClickInput {...props} />;
         } else {
           throw new Error("Unknown object type");
         }
@@ -150,6 +429,12 @@ export const Input = (props: InputProps) => {
         return <NumberInput {...props} />;
       }
       case "array": {
+        if (_key === "layers") {
+          return <LayersInput {...props} />;
+        }
+        if (typeof val[0] === "object") {
+          return <InputChannelsInput {...props} />;
+        }
         return <NumberArrayInput {...props} />;
       }
       default: {
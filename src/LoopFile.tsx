@@ -0,0 +1,39 @@
+import { useState } from "react";
+import { invoke } from "@tauri-apps/api";
+import { open, save } from "@tauri-apps/api/dialog";
+import { audioFilters } from "./formats";
+
+const wavFilter = [{ name: "WAV", extensions: ["wav"] }];
+
+export const LoopFile = () => {
+  const [reference, setReference] = useState<string | null>(null);
+  const [status, setStatus] = useState("");
+
+  const exportLoop = async () => {
+    const path = await save({ defaultPath: "loop.wav", filters: wavFilter });
+    if (!path) return;
+    invoke("export_loop", { path })
+      .then(() => setStatus(`saved loop to ${path}`))
+      .catch((err) => setStatus(`${err}`));
+  };
+
+  const loadLoop = async () => {
+    const filename = await open({ filters: await audioFilters() });
+    if (typeof filename !== "string") return;
+    invoke("load_loop", { filename })
+      .then(() => {
+        setReference(filename);
+        setStatus("");
+      })
+      .catch((err) => setStatus(`${err}`));
+  };
+
+  return (
+    <>
+      <button onClick={exportLoop}>save loop</button>
+      <button onClick={loadLoop}>load reference loop</button>
+      {reference && <div>reference: {reference}</div>}
+      {status && <div>{status}</div>}
+    </>
+  );
+};
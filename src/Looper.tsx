@@ -0,0 +1,65 @@
+import { useEffect, useState } from "react";
+import { invoke } from "@tauri-apps/api";
+
+type LooperMode = "record" | "play" | "overdub";
+
+interface LooperStatus {
+  mode: LooperMode;
+  feedback: number;
+  can_undo: boolean;
+}
+
+const modes: LooperMode[] = ["record", "play", "overdub"];
+
+export const Looper = () => {
+  const [status, setStatus] = useState<LooperStatus | null>(null);
+  const [error, setError] = useState("");
+
+  const run = (command: string, args?: Record<string, unknown>) =>
+    invoke<LooperStatus>(command, args)
+      .then((status) => {
+        setStatus(status);
+        setError("");
+      })
+      .catch((err) => setError(`${err}`));
+
+  useEffect(() => {
+    run("get_looper_status");
+    // a reference loop loaded elsewhere switches the looper to play
+    const interval = setInterval(() => run("get_looper_status"), 1000);
+    return () => clearInterval(interval);
+  }, []);
+
+  if (!status) return null;
+  return (
+    <>
+      {modes.map((mode) => (
+        <button
+          key={mode}
+          disabled={status.mode === mode}
+          onClick={() => run("set_looper_mode", { mode })}
+        >
+          {mode}
+        </button>
+      ))}
+      <label>
+        feedback
+        <input
+          type="range"
+          min={0}
+          max={1}
+          step={0.05}
+          value={status.feedback}
+          onChange={(e) =>
+            run("set_overdub_feedback", { feedback: Number(e.target.value) })
+          }
+        />
+      </label>
+      <button disabled={!status.can_undo} onClick={() => run("undo_overdub")}>
+        undo overdub
+      </button>
+      <button onClick={() => run("clear_loop")}>clear</button>
+      {error && <div>{error}</div>}
+    </>
+  );
+};
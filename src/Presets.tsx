@@ -0,0 +1,73 @@
+import { useEffect, useState } from "react";
+import { invoke } from "@tauri-apps/api";
+import { Settings } from "./config";
+
+export const Presets = ({
+  onLoad,
+}: {
+  onLoad: (settings: Settings) => void;
+}) => {
+  const [presets, setPresets] = useState<string[]>([]);
+  const [name, setName] = useState("");
+  const [error, setError] = useState("");
+
+  const refresh = () =>
+    invoke<string[]>("list_presets")
+      .then(setPresets)
+      .catch((err) => setError(`${err}`));
+
+  useEffect(() => {
+    refresh();
+  }, []);
+
+  const save = () => {
+    invoke("save_preset", { name })
+      .then(() => {
+        setName("");
+        setError("");
+        refresh();
+      })
+      .catch((err) => setError(`${err}`));
+  };
+
+  const load = (preset: string) => {
+    invoke<Settings>("load_preset", { name: preset })
+      .then((settings) => {
+        setError("");
+        onLoad(settings);
+      })
+      .catch((err) => setError(`${err}`));
+  };
+
+  const remove = (preset: string) => {
+    invoke("delete_preset", { name: preset })
+      .then(refresh)
+      .catch((err) => setError(`${err}`));
+  };
+
+  return (
+    <>
+      {presets.map((preset) => (
+        <div
+          key={preset}
+          style={{ display: "flex", flexDirection: "row", gap: "4px" }}
+        >
+          <label>{preset}</label>
+          <button onClick={() => load(preset)}>load</button>
+          <button onClick={() => remove(preset)}>delete</button>
+        </div>
+      ))}
+      <div style={{ display: "flex", flexDirection: "row", gap: "4px" }}>
+        <input
+          value={name}
+          placeholder="preset name"
+          onChange={(e) => setName(e.target.value)}
+        />
+        <button disabled={!name.trim()} onClick={save}>
+          save preset
+        </button>
+      </div>
+      {error && <div>{error}</div>}
+    </>
+  );
+};
@@ -0,0 +1,73 @@
+import { useState } from "react";
+import { invoke } from "@tauri-apps/api";
+import { save } from "@tauri-apps/api/dialog";
+
+interface RecordingSummary {
+  files: string[];
+  frames: number;
+  sample_rate: number;
+  dropped_frames: number;
+}
+
+export const Recording = () => {
+  const [recording, setRecording] = useState(false);
+  const [mix, setMix] = useState(true);
+  const [click, setClick] = useState(false);
+  const [status, setStatus] = useState("");
+
+  const start = async () => {
+    const path = await save({
+      defaultPath: "take.wav",
+      filters: [{ name: "WAV", extensions: ["wav"] }],
+    });
+    if (!path) return;
+    invoke("start_recording", { path, options: { mix, click } })
+      .then(() => {
+        setRecording(true);
+        setStatus(`recording to ${path}`);
+      })
+      .catch((err) => setStatus(`${err}`));
+  };
+
+  const stop = () => {
+    invoke<RecordingSummary>("stop_recording")
+      .then((summary) => {
+        const seconds = summary.frames / summary.sample_rate;
+        setStatus(
+          `wrote ${seconds.toFixed(1)} s to ${summary.files.join(", ")}` +
+            (summary.dropped_frames
+              ? ` (${summary.dropped_frames} frames dropped)`
+              : "")
+        );
+      })
+      .catch((err) => setStatus(`${err}`))
+      .finally(() => setRecording(false));
+  };
+
+  return (
+    <>
+      <label>
+        <input
+          type="checkbox"
+          checked={mix}
+          disabled={recording}
+          onChange={(e) => setMix(e.target.checked)}
+        />
+        mix
+      </label>
+      <label>
+        <input
+          type="checkbox"
+          checked={click}
+          disabled={recording}
+          onChange={(e) => setClick(e.target.checked)}
+        />
+        click
+      </label>
+      <button onClick={recording ? stop : start}>
+        {recording ? "stop recording" : "start recording"}
+      </button>
+      {status && <div>{status}</div>}
+    </>
+  );
+};
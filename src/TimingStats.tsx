@@ -0,0 +1,49 @@
+import { useEffect, useState } from "react";
+import { invoke } from "@tauri-apps/api";
+
+interface Stats {
+  hits: number;
+  misses: number;
+  extra_hits: number;
+  mean_offset_ms: number;
+  std_dev_ms: number;
+  mean_offset_beats: number;
+  std_dev_beats: number;
+}
+
+interface TimingStatsResult {
+  session: Stats;
+  bars: { bar: number; stats: Stats }[];
+}
+
+const describe = (stats: Stats) =>
+  `${stats.hits} hits, ${stats.misses} missed, ${stats.extra_hits} extra, ` +
+  `${stats.mean_offset_ms >= 0 ? "late" : "early"} ${Math.abs(
+    stats.mean_offset_ms
+  ).toFixed(1)} ms ± ${stats.std_dev_ms.toFixed(1)} ms`;
+
+export const TimingStats = () => {
+  const [stats, setStats] = useState<TimingStatsResult | null>(null);
+
+  useEffect(() => {
+    const interval = setInterval(() => {
+      invoke<TimingStatsResult>("get_timing_stats")
+        .then(setStats)
+        .catch(() => setStats(null));
+    }, 1000);
+    return () => clearInterval(interval);
+  }, []);
+
+  if (!stats) return <div>no timing data</div>;
+  const lastBar = stats.bars[stats.bars.length - 1];
+  return (
+    <>
+      <div>session: {describe(stats.session)}</div>
+      {lastBar && (
+        <div>
+          bar {lastBar.bar}: {describe(lastBar.stats)}
+        </div>
+      )}
+    </>
+  );
+};
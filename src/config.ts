@@ -1,5 +1,36 @@
+/** One rhythm of the click and drums, with the notation it was parsed from. */
+export const defaultLayer = {
+  rhythm: {
+    inputText: "1",
+    val: { notes: [{ time: 0 }], start: 0, end: 1 } as {
+      notes: { time: number; sounds?: string[] }[];
+      start: number;
+      end: number;
+    },
+    type: "parser2",
+  },
+  // kit sound for notes that do not name one
+  sound: null as string | null,
+  gain: 1.0,
+  pan: 0.0,
+  mute: false,
+};
+
+export type Layer = typeof defaultLayer;
+
 export const defaultRustConfig = {
 	audioInGain: 1.0,
+  // sent to rust as is, so the per channel keys are already snake_case
+  inputChannels: [
+    {
+      channel: 0,
+      gain: 1.0,
+      pan: 0.0,
+      to_loop: true,
+      to_monitor: true,
+      to_visualizer: true,
+    },
+  ],
   audioMonitorOn: false,
   beatsToLoop: 4,
   bpm: 91,
@@ -7,24 +38,37 @@ export const defaultRustConfig = {
   clickOn: true,
   clickToggle: false,
   clickVolume: 0.3,
+  // sent to rust as is, like inputChannels
+  click: {
+    timbre: "woodblock" as "woodblock" | "beep" | "noise" | "sample",
+    attack: 0.001,
+    decay: 0.04,
+    sample: "ride",
+    beats_per_bar: 4,
+    downbeat: { gain: 1.0, pitch: 1600 },
+    beat: { gain: 0.7, pitch: 1200 },
+    subdivision: { gain: 0.4, pitch: 900 },
+  },
   drumOn: true,
+  // sound name to sample file, relative to the samples directory
+  kit: { ride: "ride_cropped.wav" } as Record<string, string>,
   loopingOn: false,
   playFile: true,
-  audioSubdivisions: {
-    inputText: "2:1",
-    val: {
-      notes: [{ time: 0, sounds: ["h"] }, { time: 0.5 }],
-      start: 0,
-      end: 1,
+  layers: [
+    {
+      ...defaultLayer,
+      rhythm: {
+        inputText: "2:1",
+        val: {
+          notes: [{ time: 0 }, { time: 0.5 }],
+          start: 0,
+          end: 1,
+        },
+        type: "parser2",
+      },
     },
-    type: "parser2",
-  },
+  ] as Layer[],
   visualMonitorOn: true,
-  testObject: {
-    notes: [{ time: 0, sounds: ["h"] }, { time: 0.5 }],
-    start: 0,
-    end: 1,
-  },
 };
 
 export const defaultJsConfig = {
@@ -59,3 +103,40 @@ export const isJsConfigKey = (k: string): k is JsConfigKey =>
 export type Config = RustConfig & JsConfig;
 
 export type ConfigKey = keyof Config;
+
+/** What `get_settings` and `load_preset` return. */
+export interface Settings {
+  version: number;
+  config: Record<string, unknown>;
+  frontend?: Partial<RustConfig> | null;
+}
+
+const snakeCaseToCamelCase = (str: string) =>
+  str.replace(/_([a-z])/g, (g) => g[1].toUpperCase());
+
+/** The frontend's config for saved settings, from the stored frontend state
+ * if there is one and otherwise from the config rust keeps. */
+export const rustConfigFromSettings = (settings: Settings): RustConfig => {
+  if (settings.frontend) return { ...defaultRustConfig, ...settings.frontend };
+  const config: Record<string, unknown> = { ...defaultRustConfig };
+  for (const [key, value] of Object.entries(settings.config)) {
+    const k = snakeCaseToCamelCase(key);
+    if (!isRustConfigKey(k)) continue;
+    if (k === "layers") {
+      // rust has the parsed rhythms but not their notation
+      config[k] = (value as Record<string, unknown>[]).map((layer) => ({
+        ...defaultLayer,
+        ...layer,
+        rhythm: { ...defaultLayer.rhythm, inputText: "", val: layer.rhythm },
+      }));
+      continue;
+    }
+    const current = config[k];
+    // parsed values keep their input text, which rust does not have
+    config[k] =
+      typeof current === "object" && current !== null && "val" in current
+        ? { ...current, val: value }
+        : value;
+  }
+  return config as RustConfig;
+};
@@ -0,0 +1,14 @@
+import { invoke } from "@tauri-apps/api";
+import { DialogFilter } from "@tauri-apps/api/dialog";
+
+/** File dialog filters for every format the backend decodes, all of them first. */
+export const audioFilters = async (): Promise<DialogFilter[]> => {
+  const formats = await invoke<DialogFilter[]>("supported_formats");
+  return [
+    {
+      name: "Audio",
+      extensions: formats.flatMap((format) => format.extensions),
+    },
+    ...formats,
+  ];
+};